@@ -10,38 +10,83 @@ use smol::LocalExecutor;
 use futures_lite::future;
 
 use std::rc::Rc;
+use std::path::PathBuf;
 
 
 use atlas_core::{Result, Error};
 use atlas_core::parse::Lexer;
 use atlas_core::grammar::ReplInputParser;
 
-use atlas_core::store::{HeapStorage, Storage, Storable, value::Value};
+use atlas_core::store::{HeapStorage, FileStorage, HashStorage, Storage, Storable, value::Value};
 
 use atlas_core::parse::ast::{ReplInput, Module, Span, DeclareModifier};
 
-use atlas_core::compile::{Env, Compile};
+use atlas_core::compile::{Env, Compile, cache::ModuleCache};
 
 use atlas_core::vm::{
     Machine, Resources,
-    resource::{Snapshot, HttpProvider, BuiltinsProvider, FileProvider}
+    resource::{Snapshot, BuiltinsProvider, FileProvider, DataProvider, EnvProvider},
+    http::{HttpProvider, HttpConfig},
+    git::GitProvider,
+    scope::InFlight,
+    replay::{ReplayLog, Mode},
+    content::ContentCache,
+    trace::Cache
 };
 use crate::store::print::Depth;
 
-use atlas_core::store::Handle;
+use atlas_core::store::{Handle, ObjectReader};
 
 use atlas_sandbox::{SandboxManager, ExecHandler};
 
 use pretty::{BoxDoc, BoxAllocator};
 
+use clap::{Command, Arg};
+
 fn interactive() -> Result<()> {
     env_logger::Builder::from_env(
         env_logger::Env::default().default_filter_or("info,rustyline=info,surf=error,fuser=error")
     ).init();
 
+    let args = Command::new("atlas")
+        .arg(Arg::new("storage").long("storage").takes_value(true)
+                .possible_values(["heap", "hash", "file"]).default_value("heap")
+                .help("Where to keep objects: in memory, in memory by content hash, or in the data directory"))
+        .arg(Arg::new("record").long("record").takes_value(true).value_name("LOG")
+                .conflicts_with("replay")
+                .help("Record every fetch and syscall result into a replay log"))
+        .arg(Arg::new("replay").long("replay").takes_value(true).value_name("LOG")
+                .help("Re-run using only the results in a replay log, without the network or sandbox"))
+        .arg(Arg::new("offline").long("offline")
+                .help("Only use previously downloaded resources"))
+        .arg(Arg::new("env").long("env").value_name("VAR")
+                .takes_value(true).multiple_occurrences(true)
+                .help("Allow reading the environment variable as env://VAR"))
+        .get_matches();
+    let offline = args.is_present("offline");
+    let env_vars : Vec<String> = args.values_of("env")
+        .map(|v| v.map(String::from).collect()).unwrap_or_default();
+    let replay = match (args.value_of("record"), args.value_of("replay")) {
+        (Some(p), _) => Some((Mode::Record, PathBuf::from(p))),
+        (_, Some(p)) => Some((Mode::Replay, PathBuf::from(p))),
+        _ => None
+    };
 
     let dirs = ProjectDirs::from("org", "atlas", "atlas").unwrap();
 
+    match args.value_of("storage") {
+        Some("file") => {
+            let path = dirs.data_dir().join("store");
+            log::info!("using storage at {}", path.display());
+            repl(&FileStorage::open(path)?, &dirs, replay, offline, env_vars)
+        },
+        Some("hash") => repl(&HashStorage::new(), &dirs, replay, offline, env_vars),
+        _ => repl(&HeapStorage::new(), &dirs, replay, offline, env_vars)
+    }
+}
+
+fn repl<S: Storage>(storage: &S, dirs: &ProjectDirs, replay: Option<(Mode, PathBuf)>,
+                    offline: bool, env_vars: Vec<String>) -> Result<()> {
     let mut rl = {
         let mut editor = Editor::<()>::new();
         std::fs::create_dir_all(dirs.config_dir()).unwrap();
@@ -50,24 +95,51 @@ fn interactive() -> Result<()> {
         editor
     };
 
-    let sandbox = dirs.runtime_dir().unwrap();
-    let sm = SandboxManager::new(sandbox).unwrap();
-    let exec_handler = Rc::new(ExecHandler::new(&sm));
+    let replay_log = match &replay {
+        Some((Mode::Record, _)) => Some(Rc::new(ReplayLog::record(storage))),
+        Some((Mode::Replay, path)) => Some(Rc::new(ReplayLog::load(storage, path)?)),
+        None => None
+    };
+    let replaying = replay_log.as_ref().map(|r| r.mode()) == Some(Mode::Replay);
+
+    // Replays never run anything, so they don't need a sandbox
+    let sm = if replaying { None } else {
+        let sandbox = dirs.runtime_dir().unwrap();
+        Some(SandboxManager::new(sandbox).unwrap())
+    };
+    let exec_handler = sm.as_ref().map(|sm| Rc::new(ExecHandler::new(sm)));
 
     let mut env = Env::new();
-    let storage = HeapStorage::new();
 
     let resources = {
         let mut resources = Resources::new();
         // Add the resource handlers
-        resources.add_provider(Rc::new(FileProvider::new(&storage)));
-        resources.add_provider(Rc::new(BuiltinsProvider::new(&storage)));
-        resources.add_provider(Rc::new(HttpProvider::new(&storage)));
+        // Large files are copied next to the other caches (rather than
+        // into a temp dir which may be in memory) to be mapped
+        resources.add_provider(Rc::new(FileProvider::with_snapshot_dir(storage, dirs.cache_dir().join("snapshots"))));
+        resources.add_provider(Rc::new(BuiltinsProvider::new(storage)));
+        resources.add_provider(Rc::new(DataProvider::new(storage)));
+        // The environment is only readable where asked for
+        if !env_vars.is_empty() {
+            resources.add_provider(Rc::new(EnvProvider::only(storage, env_vars)));
+        }
+        let mut http = HttpProvider::with_cache_dir(storage, dirs.data_dir().join("downloads"));
+        http.set_config(HttpConfig::load(dirs.config_dir().join("http.conf"))?);
+        http.set_offline(offline);
+        resources.add_provider(Rc::new(http));
+        let git = GitProvider::with_cache_dir(storage, dirs.data_dir().join("git"));
+        git.set_offline(offline);
+        resources.add_provider(Rc::new(git));
         Rc::new(resources)
     };
 
-    let mut cache = Rc::new(storage.create_thunk_map());
-    let mut snapshot = Rc::new(Snapshot::new(resources.clone()));
+    let cache = Rc::new(storage.create_thunk_map());
+    let in_flight = Rc::new(InFlight::new());
+    let traces = Rc::new(Cache::new());
+    let modules = Rc::new(ModuleCache::with_dir(storage, dirs.cache_dir().join("modules")));
+    let content = Rc::new(ContentCache::with_dir(storage, dirs.cache_dir().join("content")));
+    // Resources are pinned by the lockfile in the working directory
+    let mut snapshot = Rc::new(Snapshot::with_lockfile(resources.clone(), "atlas.lock")?);
 
 
     // Load the prelude + __path__ into the env
@@ -76,19 +148,25 @@ fn interactive() -> Result<()> {
         let mut dir_path = "file://".to_owned();
         dir_path.push_str(std::env::current_dir().unwrap().to_str().unwrap());
         dir_path.push_str("/");
+        let dir_path = match &replay_log {
+            Some(r) => r.path(dir_path)?,
+            None => dir_path
+        };
         let dir_path = storage.insert_from(&Value::String(dir_path))?;
         env.insert(String::from("__path__"), dir_path);
-        let lexer = crate::parse::Lexer::new(prelude_src);
-        let parser = crate::grammar::ModuleParser::new();
-        let module : crate::parse::ast::Module = parser.parse(lexer).unwrap();
-        let expr = module.transpile();
-        let prelude_compiled = expr.compile(&storage, &env)?
-                                .store_in(&storage)?;
-        let prelude_module = storage.insert_from(&Value::Thunk(prelude_compiled))?;
+        let prelude = modules.compile(prelude_src)?;
+        let prelude_module = modules.instantiate(&prelude, &env)?;
 
         let exec = LocalExecutor::new();
         future::block_on(exec.run(async {
-            let mach = Machine::new(&storage, cache.clone(), snapshot.clone());
+            let mut mach = Machine::new(storage, cache.clone(), snapshot.clone());
+            mach.set_in_flight(in_flight.clone());
+            mach.set_trace_cache(traces.clone());
+            mach.set_module_cache(modules.clone());
+            mach.set_content_cache(content.clone());
+            if let Some(r) = &replay_log {
+                mach.set_replay_log(r.clone());
+            }
             mach.env_use(prelude_module, &mut env).await?;
             let r: Result<()> = Ok(());
             r
@@ -129,13 +207,22 @@ fn interactive() -> Result<()> {
                 let res : Result<_> = try {
                     let core = expr.transpile();
                     log::debug!("Core: {:?}", core);
-                    let compiled = core.compile(&storage, &env)?
-                                            .store_in(&storage)?;
+                    let compiled = core.compile(storage, &env)?
+                                            .store_in(storage)?;
                     let thunk = storage.insert_from(&Value::Thunk(compiled))?;
                     let exec = LocalExecutor::new();
                     future::block_on(exec.run(async {
-                        let mut mach = Machine::new(&storage, cache.clone(), snapshot.clone());
-                        mach.add_syscall("exec", exec_handler.clone());
+                        let mut mach = Machine::new(storage, cache.clone(), snapshot.clone());
+                        if let Some(h) = &exec_handler {
+                            mach.add_syscall("exec", h.clone());
+                        }
+                        if let Some(r) = &replay_log {
+                            mach.set_replay_log(r.clone());
+                        }
+                        mach.set_in_flight(in_flight.clone());
+                        mach.set_trace_cache(traces.clone());
+                        mach.set_module_cache(modules.clone());
+                        mach.set_content_cache(content.clone());
                         future::or(async {
                             mach.force(&thunk).await
                         }, 
@@ -166,13 +253,22 @@ fn interactive() -> Result<()> {
                     let expr = Module{span: Span::new(0, 0), decl: vec![d]};
                     let core = expr.transpile();
                     log::debug!("Core: {:?}", core);
-                    let compiled = core.compile(&storage, &env)?
-                                            .store_in(&storage)?;
+                    let compiled = core.compile(storage, &env)?
+                                            .store_in(storage)?;
                     let thunk = storage.insert_from(&Value::Thunk(compiled))?;
                     let exec = LocalExecutor::new();
                     future::block_on(exec.run(async {
-                        let mut mach = Machine::new(&storage, cache.clone(), snapshot.clone());
-                        mach.add_syscall("exec", exec_handler.clone());
+                        let mut mach = Machine::new(storage, cache.clone(), snapshot.clone());
+                        if let Some(h) = &exec_handler {
+                            mach.add_syscall("exec", h.clone());
+                        }
+                        if let Some(r) = &replay_log {
+                            mach.set_replay_log(r.clone());
+                        }
+                        mach.set_in_flight(in_flight.clone());
+                        mach.set_trace_cache(traces.clone());
+                        mach.set_module_cache(modules.clone());
+                        mach.set_content_cache(content.clone());
                         mach.env_use(thunk, &mut env).await
                     }))?
                 };
@@ -185,25 +281,55 @@ fn interactive() -> Result<()> {
                 log::debug!("Cmd: {:?}", cmd);
                 if cmd == "update_snapshot" {
                     print!("updating snapshot...");
-                    snapshot = Rc::new(Snapshot::new(resources.clone()));
-                    cache = Rc::new(storage.create_thunk_map());
+                    let (new, changed, invalidated) = refresh(&snapshot, &traces, &cache);
+                    println!(" {} resources changed, {} results invalidated", changed, invalidated);
+                    for change in snapshot.lockfile().diff(&new.lockfile()) {
+                        println!("{}", change);
+                    }
+                    snapshot = Rc::new(new);
                 } else if cmd == "toggle_updating" {
                     updating = true;
+                } else if cmd == "dump_traces" {
+                    traces.dump(&mut std::io::stdout())?;
+                } else if cmd == "gc" {
+                    let before = storage.stats();
+                    // the thunk map and the traces of freed thunks go too
+                    let freed = traces.gc(storage);
+                    let after = storage.stats();
+                    println!("freed {} objects ({} KiB), {} objects ({} KiB) live",
+                        freed, before.bytes.saturating_sub(after.bytes) / 1024,
+                        after.objects, after.bytes / 1024);
                 } else {
                     println!("Command not recognized");
                 }
             }
         }
         if updating {
-            snapshot = Rc::new(Snapshot::new(resources.clone()));
-            cache = Rc::new(storage.create_thunk_map());
+            snapshot = Rc::new(refresh(&snapshot, &traces, &cache).0);
+        }
+        if let Err(e) = snapshot.save() {
+            println!("Unable to save lockfile: {}", e);
         }
     }
     let path = dirs.config_dir().join("history.txt");
     rl.save_history(&path).ok();
+    if let (Some(r), Some((Mode::Record, path))) = (&replay_log, &replay) {
+        r.save(path)?;
+        log::info!("recorded {} effects to {}", r.len(), path.display());
+    }
     Ok(())
 }
 
+// Re-fetches the resources in the snapshot, invalidating only the results
+// which depended on resources that changed. Returns the new snapshot along
+// with the number of resources changed and results invalidated
+fn refresh<'s, S: Storage>(snapshot: &Snapshot<'s, S>, traces: &Cache<'s, S>,
+               cache: &S::ThunkMap<'s>) -> (Snapshot<'s, S>, usize, usize) {
+    let (new, changed) = future::block_on(snapshot.update());
+    let invalidated = traces.invalidate(&changed, cache);
+    (new, changed.len(), invalidated)
+}
+
 fn main() {
     interactive().unwrap();
 }
\ No newline at end of file
@@ -1,3 +1,49 @@
+use std::path::{Path, PathBuf};
+use tiny_keccak::{Hasher, Sha3};
+
+// Everything which can change the code compiled for a module
+// (or how it is stored), in the order it is hashed
+const COMPILER_SOURCES: &[&str] = &[
+    "src/grammar.lalrpop", "src/parse", "src/core", "src/compile",
+    "src/store/op.rs", "src/store/serialize.rs"
+];
+
+fn collect(path: &Path, files: &mut Vec<PathBuf>) {
+    if path.is_dir() {
+        let mut entries : Vec<_> = std::fs::read_dir(path).unwrap()
+            .map(|e| e.unwrap().path()).collect();
+        entries.sort();
+        for e in entries {
+            collect(&e, files)
+        }
+    } else {
+        files.push(path.to_path_buf())
+    }
+}
+
+// Sets ATLAS_COMPILER_HASH, a hash of the compiler sources, so
+// cached modules are recompiled whenever the compiler changes
+fn compiler_hash() {
+    let mut files = Vec::new();
+    for src in COMPILER_SOURCES {
+        collect(Path::new(src), &mut files)
+    }
+    let mut hasher = Sha3::v256();
+    for f in files {
+        let content = std::fs::read(&f).unwrap();
+        hasher.update(f.to_string_lossy().as_bytes());
+        hasher.update(&(content.len() as u64).to_le_bytes());
+        hasher.update(&content);
+    }
+    let mut digest = [0u8; 32];
+    hasher.finalize(&mut digest);
+    let hex : String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    println!("cargo:rustc-env=ATLAS_COMPILER_HASH={}", hex);
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
 fn main() {
     lalrpop::process_root().unwrap();
-}
\ No newline at end of file
+    compiler_hash();
+}
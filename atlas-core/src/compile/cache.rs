@@ -0,0 +1,151 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::{Error, ErrorKind};
+use crate::store::{Storage, Storable};
+use crate::store::value::Value;
+use crate::store::hash::{Digest, sha3};
+use crate::store::serialize::{export, import};
+use crate::util::hex;
+use super::{Compile, Env};
+
+// Changes whenever the compiler might produce different
+// code, as it includes a hash of the compiler sources (see build.rs)
+pub const COMPILER_VERSION: &str = concat!("atlas-", env!("CARGO_PKG_VERSION"),
+                                           "-", env!("ATLAS_COMPILER_HASH"));
+
+// Module code compiled with its free variables as inputs
+pub struct CompiledModule<H> {
+    pub code: H,
+    pub free: Vec<String>
+}
+
+impl<H: Clone> Clone for CompiledModule<H> {
+    fn clone(&self) -> Self {
+        Self { code: self.code.clone(), free: self.free.clone() }
+    }
+}
+
+// Compiled modules by a hash of the compiler version and source,
+// kept in memory and (optionally) in a directory on disk
+pub struct ModuleCache<'s, S: Storage + 's> {
+    store: &'s S,
+    modules: RefCell<HashMap<Digest, CompiledModule<S::Handle<'s>>>>,
+    dir: Option<PathBuf>
+}
+
+impl<'s, S: Storage + 's> ModuleCache<'s, S> {
+    pub fn new(store: &'s S) -> Self {
+        Self { store, modules: RefCell::new(HashMap::new()), dir: None }
+    }
+
+    pub fn with_dir<P: Into<PathBuf>>(store: &'s S, dir: P) -> Self {
+        Self { store, modules: RefCell::new(HashMap::new()), dir: Some(dir.into()) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn compile(&self, source: &str) -> Result<CompiledModule<S::Handle<'s>>, Error> {
+        let mut key = COMPILER_VERSION.as_bytes().to_vec();
+        key.push(0);
+        key.extend_from_slice(source.as_bytes());
+        let key = sha3(&key);
+        if let Some(m) = self.modules.borrow().get(&key) {
+            return Ok(m.clone())
+        }
+        let module = match self.load(&key) {
+            Some(m) => m,
+            None => {
+                let lexer = crate::parse::Lexer::new(source);
+                let parser = crate::grammar::ModuleParser::new();
+                let module : crate::parse::ast::Module = parser.parse(lexer)
+                    .map_err(|e| Error::new_kind(ErrorKind::Compile, e.to_string()))?;
+                let (graph, free) = module.transpile().compile_open(self.store)?;
+                let m = CompiledModule { code: graph.store_in(self.store)?, free };
+                self.save(&key, &m);
+                m
+            }
+        };
+        self.modules.borrow_mut().insert(key, module.clone());
+        Ok(module)
+    }
+
+    // A thunk for the module, with its free variables taken from env
+    pub fn instantiate(&self, module: &CompiledModule<S::Handle<'s>>, env: &Env<S::Handle<'s>>)
+                -> Result<S::Handle<'s>, Error> {
+        let args = module.free.iter().map(|v| env.get(v).cloned().ok_or_else(||
+                        Error::new_kind(ErrorKind::Compile, format!("Variable {v} not found"))))
+                        .collect::<Result<Vec<_>, Error>>()?;
+        let target = if args.is_empty() {
+            module.code.clone()
+        } else {
+            self.store.insert_from(&Value::Partial(module.code.clone(), args))?
+        };
+        self.store.insert_from(&Value::Thunk(target))
+    }
+
+    // On disk, a module is the u32 number of free variables,
+    // each a u32 length and the name, then the exported code
+    fn load(&self, key: &Digest) -> Option<CompiledModule<S::Handle<'s>>> {
+        let path = self.dir.as_ref()?.join(hex::encode(key));
+        let bytes = std::fs::read(&path).ok()?;
+        let res : Result<_, Error> = (|| {
+            let mut rest = bytes.as_slice();
+            let mut take = |n: usize| {
+                if rest.len() < n {
+                    return Err(Error::new_const(ErrorKind::BadFormat, "Truncated module"))
+                }
+                let (head, tail) = rest.split_at(n);
+                rest = tail;
+                Ok(head)
+            };
+            let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+            let mut free = Vec::new();
+            for _ in 0..count {
+                let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+                let name = std::str::from_utf8(take(len)?)
+                    .map_err(|_| Error::new_const(ErrorKind::BadFormat, "Bad variable name"))?;
+                free.push(name.to_string());
+            }
+            Ok(CompiledModule { code: import(self.store, rest)?, free })
+        })();
+        match res {
+            Ok(m) => Some(m),
+            Err(e) => {
+                log::warn!("Ignoring cached module {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn save(&self, key: &Digest, module: &CompiledModule<S::Handle<'s>>) {
+        let dir = match &self.dir {
+            Some(d) => d,
+            None => return
+        };
+        let res : Result<(), Error> = (|| {
+            let mut bytes = (module.free.len() as u32).to_le_bytes().to_vec();
+            for v in module.free.iter() {
+                bytes.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(v.as_bytes());
+            }
+            bytes.extend_from_slice(&export(&module.code)?);
+            std::fs::create_dir_all(dir)?;
+            // Write then rename, so a reader never sees half a module
+            let tmp = dir.join(format!("{}.tmp{}", hex::encode(key), std::process::id()));
+            std::fs::write(&tmp, bytes)?;
+            std::fs::rename(&tmp, dir.join(hex::encode(key)))?;
+            Ok(())
+        })();
+        if let Err(e) = res {
+            log::warn!("Unable to cache module: {}", e)
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod op_graph;
+pub mod cache;
 
 #[cfg(test)]
 mod test;
@@ -41,6 +42,23 @@ pub trait Compile : FreeVariables {
         graph.set_root(res);
         Ok(graph)
     }
+
+    // Like compile, but the free variables become inputs (in the
+    // order returned) rather than being bound from an env, so the
+    // code can be reused with any env
+    fn compile_open<'s, S: Storage + 's>(&self, store: &'s S)
+                        -> Result<(CodeGraph<S::Handle<'s>>, Vec<String>), Error> {
+        let mut graph = CodeGraph::default();
+        let mut cenv= CompileEnv::new();
+        let mut free : Vec<&str> = self.free_variables(&HashSet::new()).into_iter().collect();
+        free.sort();
+        for (i, var) in free.iter().enumerate() {
+            cenv.add(var, graph.insert(OpNode::Input(i)));
+        }
+        let res = self.compile_with(store, &cenv, &mut graph)?;
+        graph.set_root(res);
+        Ok((graph, free.into_iter().map(String::from).collect()))
+    }
 }
 
 impl Compile for Var {
@@ -62,4 +62,32 @@ fn test_compile_prelude() {
         println!("code: {}", code_doc.pretty(80));
     }
     todo!()
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_module_cache() {
+    use super::cache::ModuleCache;
+    use crate::store::serialize::export;
+    let dir = std::env::temp_dir().join(format!("atlas-modules-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let src = "pub let x = $add(y, 1);";
+
+    let storage = HeapStorage::new();
+    let cache = ModuleCache::with_dir(&storage, &dir);
+    let a = cache.compile(src).unwrap();
+    let b = cache.compile(src).unwrap();
+    assert_eq!(a.code, b.code);
+    assert_eq!(a.free, vec!["y".to_string()]);
+    let mut env = Env::new();
+    assert!(cache.instantiate(&a, &env).is_err());
+    env.insert("y".to_string(), storage.insert_from(&Value::Int(1)).unwrap());
+    cache.instantiate(&a, &env).unwrap();
+
+    // A new process loads the module from disk
+    let other = HeapStorage::new();
+    let cache = ModuleCache::with_dir(&other, &dir);
+    let c = cache.compile(src).unwrap();
+    assert_eq!(export(&c.code).unwrap(), export(&a.code).unwrap());
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::ops::Deref;
+
+use crate::Error;
+
+use super::{Storage, Handle, ObjectReader, ReaderWhich, IndirectBuilder,
+            StringReader, BufferReader, TupleReader, RecordReader,
+            PartialReader, CodeReader};
+use super::value::{self, Value};
+
+// The handles an object refers to
+fn children<'s, H: Handle<'s>>(h: &H) -> Result<Vec<H>, Error> {
+    use ReaderWhich::*;
+    let reader = h.reader()?;
+    Ok(match reader.which() {
+        Indirect(h) | Thunk(h) => vec![h.borrow().clone()],
+        Cons(a, b) | Variant(a, b) => vec![a.borrow().clone(), b.borrow().clone()],
+        Tuple(t) => t.iter().map(|x| x.borrow().clone()).collect(),
+        Record(r) => r.iter().flat_map(|(k, v)| [k.borrow().clone(), v.borrow().clone()]).collect(),
+        Code(c) => c.iter_values().map(|x| x.borrow().clone()).collect(),
+        Partial(p) => std::iter::once(p.get_code().borrow().clone())
+                        .chain(p.iter_args().map(|x| x.borrow().clone())).collect(),
+        _ => Vec::new()
+    })
+}
+
+fn is_indirect<'s, H: Handle<'s>>(h: &H) -> Result<bool, Error> {
+    Ok(matches!(h.reader()?.which(), ReaderWhich::Indirect(_)))
+}
+
+// The objects reachable from root (skipping those which are done),
+// with every object after its children. Only indirects can come
+// before the objects they refer to, which is how cycles are broken.
+// The walk uses an explicit stack, so long lists are fine
+pub(super) fn postorder<'s, H, F>(root: &H, done: F) -> Result<Vec<H>, Error>
+        where H: Handle<'s>, F: Fn(&H) -> bool {
+    let mut seen = std::collections::HashSet::new();
+    let mut order = Vec::new();
+    // (handle, whether its children have been pushed)
+    let mut stack = vec![(root.clone(), false)];
+    while let Some((h, expanded)) = stack.pop() {
+        if seen.contains(&h) || done(&h) { continue }
+        if is_indirect(&h)? {
+            // Number indirects before their target, so cycles are closed
+            order.push(h.clone());
+            seen.insert(h.clone());
+            stack.extend(children(&h)?.into_iter().map(|c| (c, false)));
+        } else if expanded {
+            order.push(h.clone());
+            seen.insert(h);
+        } else {
+            let children = children(&h)?;
+            stack.push((h, true));
+            stack.extend(children.into_iter()
+                .filter(|c| !seen.contains(c)).map(|c| (c, false)));
+        }
+    }
+    Ok(order)
+}
+
+// Copies objects from one storage into another. Objects copied by
+// earlier calls are reused, so sharing between roots is preserved
+pub struct Copier<'s, 't, H: Handle<'s>, T: Storage + 't> {
+    dest: &'t T,
+    copied: HashMap<H, T::Handle<'t>>,
+    phantom: std::marker::PhantomData<&'s ()>
+}
+
+impl<'s, 't, H: Handle<'s>, T: Storage + 't> Copier<'s, 't, H, T> {
+    pub fn new(dest: &'t T) -> Self {
+        Self { dest, copied: HashMap::new(), phantom: std::marker::PhantomData }
+    }
+
+    pub fn copy(&mut self, root: &H) -> Result<T::Handle<'t>, Error> {
+        let order = postorder(root, |h| self.copied.contains_key(h))?;
+        let mut indirects = Vec::new();
+        for h in order {
+            let reader = h.reader()?;
+            let copy = match reader.which() {
+                ReaderWhich::Indirect(target) => {
+                    let b = self.dest.indirect()?;
+                    let handle = b.handle();
+                    indirects.push((b, target.borrow().clone()));
+                    handle
+                },
+                _ => {
+                    let copied = &self.copied;
+                    let value = to_value(&reader, |c| copied[c].clone());
+                    self.dest.insert_from(&value)?
+                }
+            };
+            self.copied.insert(h.clone(), copy);
+        }
+        for (b, target) in indirects {
+            b.build(self.copied[&target].clone());
+        }
+        Ok(self.copied[root].clone())
+    }
+}
+
+pub fn copy<'s, 't, H: Handle<'s>, T: Storage + 't>(root: &H, dest: &'t T) -> Result<T::Handle<'t>, Error> {
+    Copier::new(dest).copy(root)
+}
+
+// Converts a reader into a value, using map to
+// get the new handle for each child
+fn to_value<'p, 's, 't, R, T, F>(src: &R, map: F) -> Value<'t, T>
+        where R: ObjectReader<'p, 's>, T: Handle<'t>, F: Fn(&R::Handle) -> T {
+    use ReaderWhich::*;
+    match src.which() {
+        Bot | Indirect(_) => Value::Bot, Unit => Value::Unit, Nil => Value::Nil,
+        Char(c) => Value::Char(c), Bool(b) => Value::Bool(b),
+        Float(f) => Value::Float(f), Int(i) => Value::Int(i),
+        String(s) => Value::String(s.as_slice().deref().to_string()),
+        Buffer(b) => Value::Buffer(b.to_bytes()),
+        Record(r) => Value::Record(r.iter().map(|(k, v)| (map(k.borrow()), map(v.borrow()))).collect()),
+        Tuple(t) => Value::Tuple(t.iter().map(|v| map(v.borrow())).collect()),
+        Variant(t, v) => Value::Variant(map(t.borrow()), map(v.borrow())),
+        Cons(h, t) => Value::Cons(map(h.borrow()), map(t.borrow())),
+        Code(c) => Value::Code(value::Code::new(c.get_ret(), c.iter_ready().collect(),
+                        c.iter_ops().collect(), c.iter_values().map(|v| map(v.borrow())).collect())),
+        Partial(p) => Value::Partial(map(p.get_code().borrow()),
+                        p.iter_args().map(|v| map(v.borrow())).collect()),
+        Thunk(p) => Value::Thunk(map(p.borrow()))
+    }
+}
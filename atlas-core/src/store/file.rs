@@ -0,0 +1,521 @@
+use std::rc::{Rc, Weak};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use bytes::Bytes;
+
+use crate::{Error, ErrorKind};
+
+use super::{Storage, StorageStats, ThunkMap, ObjectReader};
+use super::op::{Op, OpCase, Dest, BuiltinOp};
+use super::heap::{Ptr, Item, Code, ItemStore, ItemHandle,
+                  ItemIndirectBuilder, item_from};
+use super::hash::{Digest, digest_item, indirect_digest};
+
+// A storage backed by files in a directory:
+//   objects.log -- an append-only log of object records,
+//                  each a u32 length followed by the encoded item
+//   objects.idx -- for each pointer, the u64 log offset of its latest record
+//                  followed by the digest of its contents (see hash.rs)
+//   thunks.log  -- an append-only log of (thunk, result) pointer pairs
+//                  for the persistent thunk map, a result of 0 removes the entry.
+//                  Volatile results (see ThunkMap) are not logged, and the log
+//                  is compacted on opening once most of its entries are dead
+// Building an indirect appends a new record for it and
+// rewrites its index entry, so the log is never modified in place.
+// Objects with the same contents are stored once, so thunks
+// built again in a later process map to the same pointer.
+pub struct FileStorage {
+    dir: PathBuf,
+    log: File,
+    index: File,
+    thunks: File,
+    log_len: Cell<u64>,
+    // ptr - 1 --> offset of the record, digest
+    entries: RefCell<Vec<(u64, Digest)>>,
+    ptrs: RefCell<HashMap<Digest, Ptr>>,
+    // Decoded items which are still in use, so that
+    // all live handles to an object share the same item
+    items: RefCell<HashMap<Ptr, Weak<Item>>>,
+    // The size items can grow to before dead entries are pruned
+    prune_at: Cell<usize>,
+    // Set if an indirect could not be written, after which the files no
+    // longer match what is in memory, so nothing more is written to them
+    failed: RefCell<Option<Error>>
+}
+
+const INDEX_ENTRY: usize = 8 + 32;
+const MIN_PRUNE: usize = 1024;
+// The number of dead entries thunks.log can have before it is compacted
+const MIN_COMPACT: usize = 256;
+
+impl FileStorage {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let log = OpenOptions::new().read(true).append(true).create(true)
+                    .open(dir.join("objects.log"))?;
+        let index = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+                    .open(dir.join("objects.idx"))?;
+        let thunks = OpenOptions::new().read(true).append(true).create(true)
+                    .open(dir.join("thunks.log"))?;
+        let log_len = log.metadata()?.len();
+
+        let mut raw = vec![0u8; index.metadata()?.len() as usize];
+        index.read_exact_at(&mut raw, 0)?;
+        let mut entries = Vec::new();
+        let mut ptrs = HashMap::new();
+        for entry in raw.chunks_exact(INDEX_ENTRY) {
+            let off = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let digest : Digest = entry[8..].try_into().unwrap();
+            // Drop entries whose records did not make it to disk
+            if off + 4 > log_len { break }
+            let mut len = [0u8; 4];
+            log.read_exact_at(&mut len, off)?;
+            if off + 4 + u32::from_le_bytes(len) as u64 > log_len { break }
+            entries.push((off, digest));
+            ptrs.insert(digest, entries.len());
+        }
+        index.set_len((entries.len() * INDEX_ENTRY) as u64)?;
+        Ok(Self {
+            dir, log, index, thunks,
+            log_len: Cell::new(log_len),
+            entries: RefCell::new(entries),
+            ptrs: RefCell::new(ptrs),
+            items: RefCell::new(HashMap::new()),
+            prune_at: Cell::new(MIN_PRUNE),
+            failed: RefCell::new(None)
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // The number of objects in the storage
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn digest(&self, h: &ItemHandle<'_, Self>) -> Option<Digest> {
+        self.digest_of(h.ptr)
+    }
+
+    fn digest_of(&self, ptr: Ptr) -> Option<Digest> {
+        let entries = self.entries.borrow();
+        ptr.checked_sub(1).and_then(|i| entries.get(i)).map(|(_, d)| *d)
+    }
+
+    // Appends a record to the log, returning its offset
+    fn append(&self, item: &Item) -> Result<u64, Error> {
+        let mut buf = vec![0u8; 4];
+        encode(item, &mut buf);
+        let len = (buf.len() - 4) as u32;
+        buf[0..4].copy_from_slice(&len.to_le_bytes());
+        let off = self.log_len.get();
+        (&self.log).write_all(&buf)?;
+        self.log_len.set(off + buf.len() as u64);
+        Ok(off)
+    }
+
+    fn set_offset(&self, ptr: Ptr, off: u64) -> Result<(), Error> {
+        self.index.write_all_at(&off.to_le_bytes(), ((ptr - 1) * INDEX_ENTRY) as u64)?;
+        self.entries.borrow_mut()[ptr - 1].0 = off;
+        Ok(())
+    }
+
+    // Fails once the storage has failed to write an indirect
+    fn check(&self) -> Result<(), Error> {
+        match &*self.failed.borrow() {
+            Some(e) => Err(e.clone().context("The storage failed to write an indirect earlier")),
+            None => Ok(())
+        }
+    }
+
+    fn push<'s>(&'s self, digest: Digest, item: Item) -> Result<ItemHandle<'s, Self>, Error> {
+        self.check()?;
+        let existing = self.ptrs.borrow().get(&digest).cloned();
+        if let Some(ptr) = existing {
+            return Ok(self.get(ptr))
+        }
+        let off = self.append(&item)?;
+        let ptr = self.len() + 1;
+        let mut entry = off.to_le_bytes().to_vec();
+        entry.extend_from_slice(&digest);
+        self.index.write_all_at(&entry, ((ptr - 1) * INDEX_ENTRY) as u64)?;
+        self.entries.borrow_mut().push((off, digest));
+        self.ptrs.borrow_mut().insert(digest, ptr);
+
+        let entry = Rc::new(item);
+        self.cache(ptr, &entry);
+        Ok(ItemHandle { store: self, ptr, entry: Some(entry) })
+    }
+
+    fn cache(&self, ptr: Ptr, entry: &Rc<Item>) {
+        let mut items = self.items.borrow_mut();
+        // once the table has doubled since it was last pruned,
+        // drop the entries of items which are no longer in use
+        if items.len() >= self.prune_at.get() {
+            items.retain(|_, w| w.strong_count() > 0);
+            self.prune_at.set(std::cmp::max(2 * items.len(), MIN_PRUNE));
+        }
+        items.insert(ptr, Rc::downgrade(entry));
+    }
+
+    fn read(&self, ptr: Ptr) -> Result<Item, Error> {
+        let off = match ptr.checked_sub(1).and_then(|i| self.entries.borrow().get(i).cloned()) {
+            Some((off, _)) => off,
+            None => return Err(Error::new_const(ErrorKind::BadPointer, "Bad handle!"))
+        };
+        let mut len = [0u8; 4];
+        self.log.read_exact_at(&mut len, off)?;
+        let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.log.read_exact_at(&mut buf, off + 4)?;
+        decode(&buf)
+    }
+
+    // Replays thunks.log, ignoring a partially written last entry
+    fn read_thunks(&self) -> Result<HashMap<Ptr, Ptr>, Error> {
+        let mut raw = vec![0u8; self.thunks.metadata()?.len() as usize];
+        self.thunks.read_exact_at(&mut raw, 0)?;
+        let mut map = HashMap::new();
+        for entry in raw.chunks_exact(16) {
+            let k = u64::from_le_bytes(entry[..8].try_into().unwrap()) as Ptr;
+            let v = u64::from_le_bytes(entry[8..].try_into().unwrap()) as Ptr;
+            if v == 0 { map.remove(&k); } else { map.insert(k, v); }
+        }
+        if raw.len() / 16 > 2 * map.len() + MIN_COMPACT {
+            self.compact_thunks(&map)?;
+        }
+        Ok(map)
+    }
+
+    // Rewrites thunks.log with only the live entries. If this is
+    // interrupted, the thunk map loses entries, which is harmless
+    fn compact_thunks(&self, map: &HashMap<Ptr, Ptr>) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(map.len() * 16);
+        for (k, v) in map.iter() {
+            buf.extend_from_slice(&(*k as u64).to_le_bytes());
+            buf.extend_from_slice(&(*v as u64).to_le_bytes());
+        }
+        self.thunks.set_len(0)?;
+        (&self.thunks).write_all(&buf)?;
+        Ok(())
+    }
+
+    fn write_thunk(&self, k: Ptr, v: Ptr) {
+        if self.check().is_err() { return }
+        let mut entry = (k as u64).to_le_bytes().to_vec();
+        entry.extend_from_slice(&(v as u64).to_le_bytes());
+        if let Err(e) = (&self.thunks).write_all(&entry) {
+            log::error!("Unable to persist thunk map entry: {}", e)
+        }
+    }
+}
+
+impl ItemStore for FileStorage {
+    fn get<'s>(&'s self, ptr: Ptr) -> ItemHandle<'s, Self> {
+        if let Some(entry) = self.items.borrow().get(&ptr).and_then(|w| w.upgrade()) {
+            return ItemHandle { store: self, ptr, entry: Some(entry) }
+        }
+        let entry = match self.read(ptr) {
+            Ok(item) => Rc::new(item),
+            Err(e) => {
+                log::error!("Unable to read object {}: {}", ptr, e);
+                return ItemHandle { store: self, ptr, entry: None }
+            }
+        };
+        self.cache(ptr, &entry);
+        ItemHandle { store: self, ptr, entry: Some(entry) }
+    }
+
+    // Building an indirect can't fail, so an error here is kept
+    // and returned by everything which writes to the storage afterwards
+    fn set_indirect(&self, ptr: Ptr, dest: Ptr) {
+        let res = self.check()
+                      .and_then(|_| self.append(&Item::Indirect(Cell::new(dest))))
+                      .and_then(|off| self.set_offset(ptr, off));
+        if let Err(e) = res {
+            log::error!("Unable to write indirect {}: {}", ptr, e);
+            self.failed.borrow_mut().get_or_insert(e);
+        }
+    }
+}
+
+// A thunk map which is kept in thunks.log, so results
+// forced by one process are reused by the next
+pub struct FileThunkMap<'s> {
+    map: RefCell<HashMap<Ptr, Ptr>>,
+    // The thunks whose results are not logged
+    volatile: RefCell<HashSet<Ptr>>,
+    store: &'s FileStorage
+}
+
+impl<'s> ThunkMap<'s> for FileThunkMap<'s> {
+    type Handle = ItemHandle<'s, FileStorage>;
+    fn get(&self, h: &Self::Handle) -> Option<Self::Handle> {
+        let m = self.map.borrow();
+        m.get(&h.ptr).map(|x| self.store.get(*x))
+    }
+    fn insert(&self, k: &Self::Handle, v : &Self::Handle) {
+        let prev = self.map.borrow_mut().insert(k.ptr, v.ptr);
+        let was_volatile = self.volatile.borrow_mut().remove(&k.ptr);
+        if prev != Some(v.ptr) || was_volatile {
+            self.store.write_thunk(k.ptr, v.ptr)
+        }
+    }
+    fn remove(&self, k: &Self::Handle) {
+        let was_volatile = self.volatile.borrow_mut().remove(&k.ptr);
+        if self.map.borrow_mut().remove(&k.ptr).is_some() && !was_volatile {
+            self.store.write_thunk(k.ptr, 0)
+        }
+    }
+    fn insert_volatile(&self, k: &Self::Handle, v: &Self::Handle) {
+        let prev = self.map.borrow_mut().insert(k.ptr, v.ptr);
+        let logged = prev.is_some() && self.volatile.borrow_mut().insert(k.ptr);
+        self.volatile.borrow_mut().insert(k.ptr);
+        // drop the result logged before
+        if logged {
+            self.store.write_thunk(k.ptr, 0)
+        }
+    }
+    fn is_volatile(&self, k: &Self::Handle) -> bool {
+        self.volatile.borrow().contains(&k.ptr)
+    }
+}
+
+impl Storage for FileStorage {
+    type Handle<'s> = ItemHandle<'s, Self> where Self : 's;
+    type IndirectBuilder<'s> = ItemIndirectBuilder<'s, Self> where Self : 's;
+    type ThunkMap<'s> = FileThunkMap<'s> where Self : 's;
+
+    fn create_thunk_map<'s>(&'s self) -> Self::ThunkMap<'s> {
+        let map = self.read_thunks().unwrap_or_else(|e| {
+            log::error!("Unable to read the thunk map: {}", e);
+            HashMap::new()
+        });
+        FileThunkMap { map: RefCell::new(map), volatile: RefCell::new(HashSet::new()), store: self }
+    }
+
+    fn indirect<'s>(&'s self) -> Result<Self::IndirectBuilder<'s>, Error> {
+        // Indirects are never shared, so the digest only has to be unique
+        let digest = indirect_digest(self.len() as u64 + 1);
+        let handle = self.push(digest, Item::Indirect(Cell::new(0)))?;
+        Ok(ItemIndirectBuilder { handle })
+    }
+
+    fn insert<'s, 'p, R>(&'s self, src: &R) -> Result<Self::Handle<'s>, Error>
+                where R: ObjectReader<'p, 's, Handle=Self::Handle<'s>> {
+        let item = item_from(src);
+        let digest = digest_item(&item, |p| self.digest_of(p))?;
+        self.push(digest, item)
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats { objects: self.len(), bytes: self.log_len.get() as usize }
+    }
+}
+
+// The record encoding. All integers are little-endian,
+// vectors are prefixed by their u32 length
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes())
+}
+
+fn put_ptr(buf: &mut Vec<u8>, p: Ptr) {
+    buf.extend_from_slice(&(p as u64).to_le_bytes())
+}
+
+fn put_bytes(buf: &mut Vec<u8>, b: &[u8]) {
+    put_u32(buf, b.len() as u32);
+    buf.extend_from_slice(b)
+}
+
+fn put_u32s(buf: &mut Vec<u8>, v: &[u32]) {
+    put_u32(buf, v.len() as u32);
+    for x in v { put_u32(buf, *x) }
+}
+
+fn put_dest(buf: &mut Vec<u8>, d: &Dest) {
+    put_u32(buf, d.reg);
+    put_u32s(buf, &d.uses)
+}
+
+fn put_op(buf: &mut Vec<u8>, op: &Op) {
+    use Op::*;
+    match op {
+        SetValue(d, v) => { buf.push(0); put_dest(buf, d); put_u32(buf, *v) },
+        SetInput(d, i) => { buf.push(1); put_dest(buf, d); put_u32(buf, *i) },
+        Force(d, r) => { buf.push(2); put_dest(buf, d); put_u32(buf, *r) },
+        Bind(d, r, args) => { buf.push(3); put_dest(buf, d); put_u32(buf, *r); put_u32s(buf, args) },
+        Invoke(d, r) => { buf.push(4); put_dest(buf, d); put_u32(buf, *r) },
+        Builtin(d, b, args) => {
+            buf.push(5); put_dest(buf, d);
+            let name : &'static str = (*b).into();
+            put_bytes(buf, name.as_bytes());
+            put_u32s(buf, args)
+        },
+        Match(d, r, cases) => {
+            buf.push(6); put_dest(buf, d); put_u32(buf, *r);
+            put_u32(buf, cases.len() as u32);
+            for c in cases {
+                match c {
+                    OpCase::Tag(v, r) => { buf.push(0); put_u32(buf, *v); put_u32(buf, *r) },
+                    OpCase::Eq(v, r) => { buf.push(1); put_u32(buf, *v); put_u32(buf, *r) },
+                    OpCase::Default(r) => { buf.push(2); put_u32(buf, *r) }
+                }
+            }
+        }
+    }
+}
+
+pub(super) fn encode(item: &Item, buf: &mut Vec<u8>) {
+    encode_with(item, buf, &put_ptr)
+}
+
+// Encodes an item, writing pointers using put_ptr
+pub(super) fn encode_with<F>(item: &Item, buf: &mut Vec<u8>, put_ptr: &F)
+        where F: Fn(&mut Vec<u8>, Ptr) {
+    let put_ptrs = |buf: &mut Vec<u8>, v: &[Ptr]| {
+        put_u32(buf, v.len() as u32);
+        for p in v { put_ptr(buf, *p) }
+    };
+    match item {
+        Item::Bot => buf.push(0),
+        Item::Indirect(c) => { buf.push(1); put_ptr(buf, c.get()) },
+        Item::Unit => buf.push(2),
+        Item::Char(c) => { buf.push(3); put_u32(buf, *c as u32) },
+        Item::Bool(b) => buf.extend_from_slice(&[4, *b as u8]),
+        Item::Float(f) => { buf.push(5); buf.extend_from_slice(&f.to_le_bytes()) },
+        Item::Int(i) => { buf.push(6); buf.extend_from_slice(&i.to_le_bytes()) },
+        Item::String(s) => { buf.push(7); put_bytes(buf, s.as_bytes()) },
+        Item::Buffer(b) => { buf.push(8); put_bytes(buf, b) },
+        Item::Nil => buf.push(9),
+        Item::Cons(h, t) => { buf.push(10); put_ptr(buf, *h); put_ptr(buf, *t) },
+        Item::Tuple(v) => { buf.push(11); put_ptrs(buf, v) },
+        Item::Record(r) => {
+            buf.push(12);
+            put_u32(buf, r.len() as u32);
+            for (k, v) in r { put_ptr(buf, *k); put_ptr(buf, *v) }
+        },
+        Item::Variant(t, v) => { buf.push(13); put_ptr(buf, *t); put_ptr(buf, *v) },
+        Item::Code(c) => {
+            buf.push(14);
+            put_u32(buf, c.ret);
+            put_u32s(buf, &c.ready);
+            put_ptrs(buf, &c.values);
+            put_u32(buf, c.ops.len() as u32);
+            for op in c.ops.iter() { put_op(buf, op) }
+        },
+        Item::Partial(c, args) => { buf.push(15); put_ptr(buf, *c); put_ptrs(buf, args) },
+        Item::Thunk(p) => { buf.push(16); put_ptr(buf, *p) }
+    }
+}
+
+struct Decoder<'b> {
+    buf: &'b [u8],
+    off: usize
+}
+
+fn corrupt() -> Error {
+    Error::new_const(ErrorKind::BadFormat, "Corrupt object record")
+}
+
+impl<'b> Decoder<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8], Error> {
+        let s = self.buf.get(self.off..self.off + n).ok_or_else(corrupt)?;
+        self.off += n;
+        Ok(s)
+    }
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    fn ptr(&mut self) -> Result<Ptr, Error> {
+        Ok(self.u64()? as Ptr)
+    }
+    fn bytes(&mut self) -> Result<&'b [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    fn ptrs(&mut self) -> Result<Vec<Ptr>, Error> {
+        (0..self.u32()?).map(|_| self.ptr()).collect()
+    }
+    fn u32s(&mut self) -> Result<Vec<u32>, Error> {
+        (0..self.u32()?).map(|_| self.u32()).collect()
+    }
+    fn dest(&mut self) -> Result<Dest, Error> {
+        Ok(Dest { reg: self.u32()?, uses: self.u32s()? })
+    }
+    fn op(&mut self) -> Result<Op, Error> {
+        use Op::*;
+        Ok(match self.u8()? {
+            0 => SetValue(self.dest()?, self.u32()?),
+            1 => SetInput(self.dest()?, self.u32()?),
+            2 => Force(self.dest()?, self.u32()?),
+            3 => Bind(self.dest()?, self.u32()?, self.u32s()?),
+            4 => Invoke(self.dest()?, self.u32()?),
+            5 => {
+                let d = self.dest()?;
+                let name = std::str::from_utf8(self.bytes()?).map_err(|_| corrupt())?;
+                Builtin(d, BuiltinOp::try_from(name)?, self.u32s()?)
+            },
+            6 => {
+                let d = self.dest()?;
+                let r = self.u32()?;
+                let cases = (0..self.u32()?).map(|_| Ok(match self.u8()? {
+                    0 => OpCase::Tag(self.u32()?, self.u32()?),
+                    1 => OpCase::Eq(self.u32()?, self.u32()?),
+                    2 => OpCase::Default(self.u32()?),
+                    _ => return Err(corrupt())
+                })).collect::<Result<_, Error>>()?;
+                Match(d, r, cases)
+            },
+            _ => return Err(corrupt())
+        })
+    }
+}
+
+pub(super) fn decode(buf: &[u8]) -> Result<Item, Error> {
+    let mut d = Decoder { buf, off: 0 };
+    Ok(match d.u8()? {
+        0 => Item::Bot,
+        1 => Item::Indirect(Cell::new(d.ptr()?)),
+        2 => Item::Unit,
+        3 => Item::Char(char::from_u32(d.u32()?).ok_or_else(corrupt)?),
+        4 => Item::Bool(d.u8()? != 0),
+        5 => Item::Float(f64::from_bits(d.u64()?)),
+        6 => Item::Int(d.u64()? as i64),
+        7 => Item::String(std::str::from_utf8(d.bytes()?).map_err(|_| corrupt())?.to_string()),
+        8 => Item::Buffer(Bytes::copy_from_slice(d.bytes()?)),
+        9 => Item::Nil,
+        10 => Item::Cons(d.ptr()?, d.ptr()?),
+        11 => Item::Tuple(d.ptrs()?),
+        12 => Item::Record((0..d.u32()?).map(|_| Ok((d.ptr()?, d.ptr()?)))
+                            .collect::<Result<_, Error>>()?),
+        13 => Item::Variant(d.ptr()?, d.ptr()?),
+        14 => {
+            let ret = d.u32()?;
+            let ready = d.u32s()?;
+            let values = d.ptrs()?;
+            let ops = (0..d.u32()?).map(|_| d.op()).collect::<Result<_, Error>>()?;
+            Item::Code(Code { ret, ready, ops, values })
+        },
+        15 => Item::Partial(d.ptr()?, d.ptrs()?),
+        16 => Item::Thunk(d.ptr()?),
+        _ => return Err(corrupt())
+    })
+}
@@ -0,0 +1,190 @@
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use tiny_keccak::{Hasher, Sha3};
+
+use crate::{Error, ErrorKind};
+use crate::util::hex;
+
+use super::{Storage, StorageStats, ObjectReader};
+use super::heap::{Ptr, Item, ItemStore, ItemHandle,
+                  ItemIndirectBuilder, PtrThunkMap, item_from};
+use super::file::encode_with;
+
+pub type Digest = [u8; 32];
+
+// A content-addressed storage. Every object is identified by the
+// SHA3-256 digest of its encoding, with children replaced by their digests,
+// so identical objects are stored once and share a handle.
+// Indirects can be cyclic and are built after their handle is
+// handed out, so each indirect instead gets a digest of its own, from
+// a counter. So indirects (and objects which refer to them) are never
+// deduplicated, and their digests only identify them within this storage.
+// A handle is the first word of its object's digest, so the same object
+// has the same handle in every storage and every process. Two digests
+// which agree on that word are reported rather than conflated.
+// The storage lives in memory only, so results are shared between
+// processes (or machines) by digest, using digest() and lookup().
+#[derive(Default)]
+pub struct HashStorage {
+    // ptr --> the digest and item
+    items: RefCell<HashMap<Ptr, (Digest, Rc<Item>)>>,
+    indirects: Cell<u64>
+}
+
+pub(crate) fn sha3(bytes: &[u8]) -> Digest {
+    let mut hasher = Sha3::v256();
+    hasher.update(bytes);
+    let mut digest = [0u8; 32];
+    hasher.finalize(&mut digest);
+    digest
+}
+
+// The digest of an item, given the digests of its children.
+// A child without a digest is not in the storage, so is an error
+pub(super) fn digest_item<F>(item: &Item, child: F) -> Result<Digest, Error>
+        where F: Fn(Ptr) -> Option<Digest> {
+    let mut hasher = Sha3::v256();
+    match item {
+        // Buffers can be large (or mapped), so are hashed in
+        // place rather than copied into their encoding
+        Item::Buffer(b) => {
+            hasher.update(&[8]);
+            hasher.update(&(b.len() as u32).to_le_bytes());
+            hasher.update(b);
+        },
+        _ => {
+            let mut buf = Vec::new();
+            let missing = Cell::new(None);
+            encode_with(item, &mut buf, &|buf: &mut Vec<u8>, p| {
+                match child(p) {
+                    Some(d) => buf.extend_from_slice(&d),
+                    None => missing.set(Some(p))
+                }
+            });
+            if let Some(p) = missing.get() {
+                return Err(Error::new_kind(ErrorKind::BadPointer,
+                                           format!("No object &{} in the storage", p)))
+            }
+            hasher.update(&buf);
+        }
+    }
+    let mut digest = [0u8; 32];
+    hasher.finalize(&mut digest);
+    Ok(digest)
+}
+
+// The digest of the nth indirect. 0xff is not the tag of any
+// encoded item, so this cannot collide with the digest of an object
+pub(super) fn indirect_digest(n: u64) -> Digest {
+    let mut seed = b"\xffindirect".to_vec();
+    seed.extend_from_slice(&n.to_le_bytes());
+    sha3(&seed)
+}
+
+// The handle of a digest. 0 is the null handle, so is never handed out
+fn ptr_of(digest: &Digest) -> Ptr {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&digest[..8]);
+    u64::from_le_bytes(word) as Ptr
+}
+
+impl HashStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The number of distinct objects in the storage
+    pub fn len(&self) -> usize {
+        self.items.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn digest(&self, h: &ItemHandle<'_, Self>) -> Option<Digest> {
+        self.digest_of(h.ptr)
+    }
+
+    pub fn lookup<'s>(&'s self, digest: &Digest) -> Option<ItemHandle<'s, Self>> {
+        let ptr = ptr_of(digest);
+        match self.items.borrow().get(&ptr) {
+            Some((d, entry)) if d == digest =>
+                Some(ItemHandle { store: self, ptr, entry: Some(entry.clone()) }),
+            _ => None
+        }
+    }
+
+    fn digest_of(&self, ptr: Ptr) -> Option<Digest> {
+        self.items.borrow().get(&ptr).map(|(d, _)| *d)
+    }
+
+    fn push<'s>(&'s self, digest: Digest, item: Item) -> Result<ItemHandle<'s, Self>, Error> {
+        let ptr = ptr_of(&digest);
+        let mut items = self.items.borrow_mut();
+        let entry = match items.get(&ptr) {
+            Some((d, entry)) if *d == digest => entry.clone(),
+            Some((d, _)) => return Err(Error::new_kind(ErrorKind::Internal,
+                format!("Digests #{} and #{} share a handle",
+                        hex::encode(&digest), hex::encode(d)))),
+            None if ptr == 0 => return Err(Error::new_kind(ErrorKind::Internal,
+                format!("Digest #{} has the null handle", hex::encode(&digest)))),
+            None => {
+                let entry = Rc::new(item);
+                items.insert(ptr, (digest, entry.clone()));
+                entry
+            }
+        };
+        Ok(ItemHandle { store: self, ptr, entry: Some(entry) })
+    }
+}
+
+impl ItemStore for HashStorage {
+    fn get<'s>(&'s self, ptr: Ptr) -> ItemHandle<'s, Self> {
+        let entry = self.items.borrow().get(&ptr).map(|(_, e)| e.clone());
+        ItemHandle { store: self, ptr, entry }
+    }
+
+    fn fmt_ptr(&self, ptr: Ptr, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.digest_of(ptr) {
+            Some(d) => {
+                write!(fmt, "#{}", hex::encode(&d[..8]))
+            },
+            None => write!(fmt, "&{}", ptr)
+        }
+    }
+}
+
+impl Storage for HashStorage {
+    type Handle<'s> = ItemHandle<'s, Self> where Self : 's;
+    type IndirectBuilder<'s> = ItemIndirectBuilder<'s, Self> where Self : 's;
+    type ThunkMap<'s> = PtrThunkMap<'s, Self> where Self : 's;
+
+    fn create_thunk_map<'s>(&'s self) -> Self::ThunkMap<'s> {
+        PtrThunkMap { map: Default::default(), store: self }
+    }
+
+    fn indirect<'s>(&'s self) -> Result<Self::IndirectBuilder<'s>, Error> {
+        let n = self.indirects.get();
+        self.indirects.set(n + 1);
+        let handle = self.push(indirect_digest(n), Item::Indirect(Cell::new(0)))?;
+        Ok(ItemIndirectBuilder { handle })
+    }
+
+    fn insert<'s, 'p, R>(&'s self, src: &R) -> Result<Self::Handle<'s>, Error>
+                where R: ObjectReader<'p, 's, Handle=Self::Handle<'s>> {
+        let item = item_from(src);
+        let digest = digest_item(&item, |p| self.digest_of(p))?;
+        self.push(digest, item)
+    }
+
+    fn stats(&self) -> StorageStats {
+        let items = self.items.borrow();
+        StorageStats {
+            objects: items.len(),
+            bytes: items.values().map(|(_, i)| i.size()).sum()
+        }
+    }
+}
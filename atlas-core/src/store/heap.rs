@@ -1,10 +1,10 @@
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use bytes::Bytes;
 use std::ops::Deref;
 use std::cell::{Cell, RefCell};
 use crate::{Error, ErrorKind};
 
-use super::{Storage, ThunkMap, Handle, ObjectReader, ReaderWhich, ObjectType,
+use super::{Storage, StorageStats, ThunkMap, Handle, WeakHandle, ObjectReader, ReaderWhich, ObjectType,
     StringReader, BufferReader, TupleReader,
     RecordReader, PartialReader, CodeReader, IndirectBuilder};
 
@@ -12,24 +12,50 @@ use super::{Storage, ThunkMap, Handle, ObjectReader, ReaderWhich, ObjectType,
 // Equivalent to value::{Value, Code}
 // but uses pointers instead of handles
 
-type Ptr = usize;
+pub(super) type Ptr = usize;
+
+// A storage whose objects are items addressed by pointer.
+// The handles and readers below work for any such storage
+pub trait ItemStore : Sized {
+    fn get<'s>(&'s self, ptr: Ptr) -> ItemHandle<'s, Self>;
+    // Called once an indirect has been built
+    fn set_indirect(&self, _ptr: Ptr, _dest: Ptr) {}
+    // How handles are displayed
+    fn fmt_ptr(&self, ptr: Ptr, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "&{}", ptr)
+    }
+    // Remakes the handle for a weak one. Objects are never freed
+    // unless the storage says otherwise, so this always succeeds
+    fn upgrade<'s>(&'s self, weak: &WeakItemHandle<'s, Self>) -> Option<ItemHandle<'s, Self>> {
+        match weak.entry.upgrade() {
+            Some(e) => Some(ItemHandle { store: self, ptr: weak.ptr, entry: Some(e) }),
+            None => Some(self.get(weak.ptr))
+        }
+    }
+}
 
-use std::collections::HashMap;
-pub struct PtrThunkMap<'s> {
-    map: RefCell<HashMap<Ptr, Ptr>>,
-    store: &'s HeapStorage
+use std::collections::{HashMap, HashSet};
+type PtrMap = RefCell<HashMap<Ptr, Ptr>>;
+
+pub struct PtrThunkMap<'s, S = HeapStorage> {
+    // Shared with the storage, so that gc can see every entry
+    pub(super) map: Rc<PtrMap>,
+    pub(super) store: &'s S
 }
 
-impl<'s> ThunkMap<'s> for PtrThunkMap<'s> {
-    type Handle = ItemHandle<'s>;
+impl<'s, S: ItemStore> ThunkMap<'s> for PtrThunkMap<'s, S> {
+    type Handle = ItemHandle<'s, S>;
     fn get(&self, h: &Self::Handle) -> Option<Self::Handle> {
-        let m = self.map.borrow();
+        let m = RefCell::borrow(&self.map);
         m.get(&h.ptr).map(|x| self.store.get(*x))
     }
     fn insert(&self, k: &Self::Handle, v : &Self::Handle) {
         let mut m =  self.map.borrow_mut();
         m.insert(k.ptr, v.ptr);
     }
+    fn remove(&self, k: &Self::Handle) {
+        self.map.borrow_mut().remove(&k.ptr);
+    }
 }
 
 
@@ -37,13 +63,18 @@ use slab::Slab;
 
 #[derive(Default)]
 pub struct HeapStorage {
-    slab: RefCell<Slab<Rc<Item>>>
+    slab: RefCell<Slab<Rc<Item>>>,
+    // The maps of every thunk map created by this storage
+    thunk_maps: RefCell<Vec<Weak<PtrMap>>>
 }
 
 impl HeapStorage {
     pub fn new() -> Self {
-        Self { slab: RefCell::new(Slab::new()) }
+        Self::default()
     }
+}
+
+impl ItemStore for HeapStorage {
     fn get<'s>(&'s self, ptr: Ptr) -> ItemHandle<'s> {
         let entry = {
             let slab = self.slab.borrow();
@@ -51,15 +82,38 @@ impl HeapStorage {
         };
         ItemHandle { store: self, ptr, entry }
     }
+
+    // The slab holds every item until it is freed (after which
+    // its pointer may be reused), so a dead entry was freed
+    fn upgrade<'s>(&'s self, weak: &WeakItemHandle<'s>) -> Option<ItemHandle<'s>> {
+        weak.entry.upgrade().map(|e| ItemHandle { store: self, ptr: weak.ptr, entry: Some(e) })
+    }
+}
+
+impl HeapStorage {
+    fn mark(&self, marked: &mut HashSet<Ptr>, roots: Vec<Ptr>) {
+        let slab = self.slab.borrow();
+        let mut stack = roots;
+        while let Some(ptr) = stack.pop() {
+            if ptr == 0 || !marked.insert(ptr) { continue }
+            if let Some(item) = slab.get(ptr - 1) {
+                stack.extend(item.children())
+            }
+        }
+    }
 }
 
 impl Storage for HeapStorage {
     type Handle<'s> = ItemHandle<'s> where Self : 's;
-    type IndirectBuilder<'s> = HeapIndirectBuilder<'s> where Self : 's;
+    type IndirectBuilder<'s> = ItemIndirectBuilder<'s> where Self : 's;
     type ThunkMap<'s> = PtrThunkMap<'s> where Self : 's;
 
     fn create_thunk_map<'s>(&'s self) -> Self::ThunkMap<'s> {
-        PtrThunkMap { map: RefCell::new(HashMap::new()), store: self }
+        let map = Rc::new(RefCell::new(HashMap::new()));
+        let mut maps = self.thunk_maps.borrow_mut();
+        maps.retain(|m| m.strong_count() > 0);
+        maps.push(Rc::downgrade(&map));
+        PtrThunkMap { map, store: self }
     }
 
     fn indirect<'s>(&'s self) -> Result<Self::IndirectBuilder<'s>, Error> {
@@ -69,55 +123,101 @@ impl Storage for HeapStorage {
             slab.insert(r.clone())
         };
         let handle = ItemHandle { store: self, ptr: key + 1, entry: Some(r) };
-        Ok(HeapIndirectBuilder{ handle })
+        Ok(ItemIndirectBuilder{ handle })
     }
 
     fn insert<'s, 'p, R>(&'s self, src: &R) -> Result<Self::Handle<'s>, Error>
                 where R: ObjectReader<'p, 's, Handle=Self::Handle<'s>> { 
-        use ReaderWhich::*;
-        let item = match src.borrow().which() {
+        let r = Rc::new(item_from(src));
+        let key = {
+            let mut slab = self.slab.borrow_mut();
+            slab.insert(r.clone()) + 1
+        };
+        Ok(ItemHandle { store: self, ptr: key, entry: Some(r) })
+    }
+
+    fn stats(&self) -> StorageStats {
+        let slab = self.slab.borrow();
+        StorageStats {
+            objects: slab.len(),
+            bytes: slab.iter().map(|(_, i)| i.size()).sum()
+        }
+    }
+
+    fn gc(&self) -> usize {
+        let maps : Vec<_> = self.thunk_maps.borrow().iter()
+            .filter_map(|m| m.upgrade()).collect();
+        // Any item referenced outside of the slab has a live handle
+        let roots : Vec<Ptr> = self.slab.borrow().iter()
+            .filter(|(_, i)| Rc::strong_count(i) > 1)
+            .map(|(k, _)| k + 1).collect();
+        let mut marked = HashSet::new();
+        self.mark(&mut marked, roots);
+        // A result is live once its thunk is, which may make more thunks live
+        loop {
+            let results : Vec<Ptr> = maps.iter().flat_map(|m| {
+                RefCell::borrow(m).iter()
+                    .filter(|(k, v)| marked.contains(k) && !marked.contains(v))
+                    .map(|(_, v)| *v).collect::<Vec<_>>()
+            }).collect();
+            if results.is_empty() { break }
+            self.mark(&mut marked, results);
+        }
+        // Entries for freed thunks would otherwise refer to reused pointers
+        for m in maps.iter() {
+            m.borrow_mut().retain(|k, _| marked.contains(k))
+        }
+        let mut slab = self.slab.borrow_mut();
+        let before = slab.len();
+        slab.retain(|k, _| marked.contains(&(k + 1)));
+        before - slab.len()
+    }
+}
+
+// Converts any reader over item handles into an item
+pub(super) fn item_from<'s, 'p, S, R>(src: &R) -> Item
+        where S: ItemStore + 's, R: ObjectReader<'p, 's, Handle=ItemHandle<'s, S>> {
+    item_with(src, |h| h.ptr)
+}
+
+// Converts a reader into an item, using ptr to
+// get the pointer for each child handle
+pub(super) fn item_with<'s, 'p, R, F>(src: &R, ptr: F) -> Item
+        where R: ObjectReader<'p, 's>, F: Fn(&R::Handle) -> Ptr {
+    use ReaderWhich::*;
+    match src.borrow().which() {
         Bot => Item::Bot, Unit => Item::Unit, Nil => Item::Nil,
-        Indirect(h) => Item::Indirect(Cell::new(h.borrow().ptr)),
+        Indirect(h) => Item::Indirect(Cell::new(ptr(h.borrow()))),
         Char(c) => Item::Char(c), Bool(b) => Item::Bool(b),
         Float(f) => Item::Float(f), Int(i) => Item::Int(i),
         String(s) => {
             let slice = s.slice(0, s.len());
             Item::String(slice.deref().to_string())
         },
-        Buffer(b) => {
-            let slice = b.slice(0, b.len());
-            Item::Buffer(Bytes::copy_from_slice(slice.deref()))
-        },
+        Buffer(b) => Item::Buffer(b.to_bytes()),
         Record(r) =>
-            Item::Record(r.iter().map(|(k, v)| (k.borrow().ptr, v.borrow().ptr)).collect()),
+            Item::Record(r.iter().map(|(k, v)| (ptr(k.borrow()), ptr(v.borrow()))).collect()),
         Tuple(t) =>
-            Item::Tuple(t.iter().map(|v| v.borrow().ptr).collect()),
+            Item::Tuple(t.iter().map(|v| ptr(v.borrow())).collect()),
         Variant(k, v) =>
-            Item::Variant(k.borrow().ptr, v.borrow().ptr),
+            Item::Variant(ptr(k.borrow()), ptr(v.borrow())),
         Cons(h, t) =>
-            Item::Cons(h.borrow().ptr, t.borrow().ptr),
+            Item::Cons(ptr(h.borrow()), ptr(t.borrow())),
         Code(c) =>
             Item::Code(self::Code {
                 ret: c.get_ret(),
                 ready: c.iter_ready().collect(),
                 ops: c.iter_ops().collect(),
-                values: c.iter_values().map(|x| x.borrow().ptr).collect()
+                values: c.iter_values().map(|x| ptr(x.borrow())).collect()
             }),
         Partial(p) =>
-            Item::Partial(p.get_code().borrow().ptr, p.iter_args().map(|x| x.borrow().ptr).collect()),
+            Item::Partial(ptr(p.get_code().borrow()), p.iter_args().map(|x| ptr(x.borrow())).collect()),
         Thunk(p) =>
-            Item::Thunk(p.borrow().ptr)
-        };
-        let r = Rc::new(item);
-        let key = {
-            let mut slab = self.slab.borrow_mut();
-            slab.insert(r.clone()) + 1
-        };
-        Ok(ItemHandle { store: self, ptr: key, entry: Some(r) })
+            Item::Thunk(ptr(p.borrow()))
     }
 }
 
-enum Item {
+pub(super) enum Item {
     Indirect(Cell<Ptr>),
     Unit,
     Bot,
@@ -135,47 +235,82 @@ enum Item {
     Thunk(Ptr),
 }
 
-struct Code {
-    ret: OpAddr,
-    ready: Vec<OpAddr>,
-    ops: Vec<Op>,
-    values: Vec<Ptr>
+impl Item {
+    pub(super) fn children(&self) -> Vec<Ptr> {
+        match self {
+            Item::Indirect(c) => vec![c.get()],
+            Item::Cons(a, b) | Item::Variant(a, b) => vec![*a, *b],
+            Item::Tuple(v) => v.clone(),
+            Item::Record(r) => r.iter().flat_map(|(k, v)| [*k, *v]).collect(),
+            Item::Code(c) => c.values.clone(),
+            Item::Partial(c, args) => std::iter::once(*c).chain(args.iter().cloned()).collect(),
+            Item::Thunk(p) => vec![*p],
+            _ => Vec::new()
+        }
+    }
+
+    // Approximate memory used by the item
+    pub(super) fn size(&self) -> usize {
+        use std::mem::size_of;
+        size_of::<Item>() + match self {
+            Item::String(s) => s.capacity(),
+            Item::Buffer(b) => b.len(),
+            Item::Tuple(v) => v.capacity() * size_of::<Ptr>(),
+            Item::Record(r) => r.capacity() * size_of::<(Ptr, Ptr)>(),
+            Item::Code(c) => c.ops.capacity() * size_of::<Op>()
+                + (c.ready.capacity() + c.values.capacity()) * size_of::<Ptr>(),
+            Item::Partial(_, args) => args.capacity() * size_of::<Ptr>(),
+            _ => 0
+        }
+    }
+}
+
+pub(super) struct Code {
+    pub(super) ret: OpAddr,
+    pub(super) ready: Vec<OpAddr>,
+    pub(super) ops: Vec<Op>,
+    pub(super) values: Vec<Ptr>
 }
 
-#[derive(Clone)]
-pub struct ItemHandle<'s> {
-    store: &'s HeapStorage,
-    ptr: Ptr,
-    entry: Option<Rc<Item>> // Will be none if a bad handle
+pub struct ItemHandle<'s, S = HeapStorage> {
+    pub(super) store: &'s S,
+    pub(super) ptr: Ptr,
+    pub(super) entry: Option<Rc<Item>> // Will be none if a bad handle
+}
+
+impl<'s, S> Clone for ItemHandle<'s, S> {
+    fn clone(&self) -> Self {
+        Self { store: self.store, ptr: self.ptr, entry: self.entry.clone() }
+    }
 }
 
-impl<'s> std::hash::Hash for ItemHandle<'s> {
+impl<'s, S> std::hash::Hash for ItemHandle<'s, S> {
     fn hash<H>(&self, hasher: &mut H)
             where H: std::hash::Hasher {
         self.ptr.hash(hasher);
     }
 }
-impl<'s> PartialEq for ItemHandle<'s> {
+impl<'s, S> PartialEq for ItemHandle<'s, S> {
     fn eq(&self, rhs: &Self) -> bool {
         self.ptr == rhs.ptr
     }
 }
-impl<'s> Eq for ItemHandle<'s> {}
+impl<'s, S> Eq for ItemHandle<'s, S> {}
 
 use std::fmt;
-impl<'s> fmt::Display for ItemHandle<'s> {
+impl<'s, S: ItemStore> fmt::Display for ItemHandle<'s, S> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(fmt, "&{}", self.ptr)
+        self.store.fmt_ptr(self.ptr, fmt)
     }
 }
-impl<'s> fmt::Debug for ItemHandle<'s> {
+impl<'s, S: ItemStore> fmt::Debug for ItemHandle<'s, S> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(fmt, "&{}", self.ptr)
+        self.store.fmt_ptr(self.ptr, fmt)
     }
 }
 
 
-impl<'s> Handle<'s> for ItemHandle<'s> {
+impl<'s, S: ItemStore> Handle<'s> for ItemHandle<'s, S> {
     type Reader<'p> = &'p Self where Self : 'p;
 
     fn reader<'p>(&'p self) -> Result<Self::Reader<'p>, Error> {
@@ -184,18 +319,62 @@ impl<'s> Handle<'s> for ItemHandle<'s> {
             None => Err(Error::new_const(ErrorKind::BadPointer, "Bad handle!"))
         }
     }
+
+    type Weak = WeakItemHandle<'s, S>;
+    fn downgrade(&self) -> Self::Weak {
+        let entry = self.entry.as_ref().map(Rc::downgrade).unwrap_or_default();
+        WeakItemHandle { store: self.store, ptr: self.ptr, entry }
+    }
+}
+
+pub struct WeakItemHandle<'s, S = HeapStorage> {
+    store: &'s S,
+    ptr: Ptr,
+    entry: Weak<Item>
+}
+
+impl<'s, S> Clone for WeakItemHandle<'s, S> {
+    fn clone(&self) -> Self {
+        Self { store: self.store, ptr: self.ptr, entry: self.entry.clone() }
+    }
 }
 
-impl<'p, 's> ObjectReader<'p, 's> for &'p ItemHandle<'s> {
+impl<'s, S> std::hash::Hash for WeakItemHandle<'s, S> {
+    fn hash<H>(&self, hasher: &mut H)
+            where H: std::hash::Hasher {
+        self.ptr.hash(hasher);
+    }
+}
+impl<'s, S> PartialEq for WeakItemHandle<'s, S> {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.ptr == rhs.ptr
+    }
+}
+impl<'s, S> Eq for WeakItemHandle<'s, S> {}
+
+impl<'s, S: ItemStore> fmt::Display for WeakItemHandle<'s, S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.store.fmt_ptr(self.ptr, fmt)
+    }
+}
+
+impl<'s, S: ItemStore> WeakHandle<'s> for WeakItemHandle<'s, S> {
+    type Handle = ItemHandle<'s, S>;
+    fn upgrade(&self) -> Option<Self::Handle> {
+        self.store.upgrade(self)
+    }
+}
+
+impl<'p, 's, S: ItemStore> ObjectReader<'p, 's> for &'p ItemHandle<'s, S> {
     type StringReader = StringItemReader<'p>;
     type BufferReader = BufferItemReader<'p>;
-    type TupleReader = TupleItemReader<'p, 's>;
-    type RecordReader = RecordItemReader<'p, 's>;
-    type CodeReader = CodeItemReader<'p, 's>;
-    type PartialReader = PartialItemReader<'p, 's>;
+    type TupleReader = TupleItemReader<'p, 's, S>;
+    type RecordReader = RecordItemReader<'p, 's, S>;
+    type CodeReader = CodeItemReader<'p, 's, S>;
+    type PartialReader = PartialItemReader<'p, 's, S>;
 
-    type Handle = ItemHandle<'s>;
-    type Subhandle = ItemHandle<'s>;
+    type Handle = ItemHandle<'s, S>;
+    type Subhandle = ItemHandle<'s, S>;
 
     fn get_type(&self) -> ObjectType {
         use ObjectType::*;
@@ -232,7 +411,7 @@ impl<'p, 's> ObjectReader<'p, 's> for &'p ItemHandle<'s> {
             Item::Unit => Unit, Item::Char(c) => Char(*c), Item::Bool(b) => Bool(*b),
             Item::Int(i) => Int(*i), Item::Float(f) => Float(*f),
             Item::String(b) => String(StringItemReader{ s: b.deref() }),
-            Item::Buffer(b) => Buffer(BufferItemReader{ s: b.deref() }),
+            Item::Buffer(b) => Buffer(BufferItemReader{ s: b }),
             Item::Record(record) => Record(RecordItemReader { record, store: self.store }),
             Item::Tuple(tuple) => Tuple(TupleItemReader { tuple, store: self.store }),
             Item::Variant(t, v) => Variant(self.store.get(*t), self.store.get(*v)),
@@ -263,32 +442,35 @@ impl<'p> StringReader<'p> for StringItemReader<'p> {
 }
 
 pub struct BufferItemReader<'p> {
-    s: &'p [u8],
+    s: &'p Bytes,
 }
 
 impl<'p> BufferReader<'p> for BufferItemReader<'p> {
     type BufferSlice<'sl> = &'sl [u8] where Self : 'sl;
 
     fn slice<'sl>(&'sl self, start: usize, len: usize) -> &'sl [u8] {
-        &self.s.borrow()[start..start+len]
+        &self.s[start..start+len]
     }
     fn len(&self) -> usize { self.s.len() }
+    fn to_bytes(&self) -> Bytes {
+        self.s.clone()
+    }
 }
 
-pub struct PtrVecIter<'r, 's> {
+pub struct PtrVecIter<'r, 's, S = HeapStorage> {
     v: &'r Vec<Ptr>,
-    store: &'s HeapStorage,
+    store: &'s S,
     off: usize
 }
 
-impl<'r, 's> PtrVecIter<'r, 's> {
-    fn new(v: &'r Vec<Ptr>, store: &'s HeapStorage) -> Self {
+impl<'r, 's, S> PtrVecIter<'r, 's, S> {
+    fn new(v: &'r Vec<Ptr>, store: &'s S) -> Self {
         Self { v, store, off: 0 }
     }
 }
 
-impl<'r, 's> Iterator for PtrVecIter<'r, 's> {
-    type Item = ItemHandle<'s>;
+impl<'r, 's, S: ItemStore> Iterator for PtrVecIter<'r, 's, S> {
+    type Item = ItemHandle<'s, S>;
     fn next(&mut self) -> Option<Self::Item> {
         let res = match self.v.get(self.off) {
         Some(v) => Some(self.store.get(*v)),
@@ -299,17 +481,17 @@ impl<'r, 's> Iterator for PtrVecIter<'r, 's> {
     }
 }
 
-pub struct TupleItemReader<'p, 's> {
+pub struct TupleItemReader<'p, 's, S = HeapStorage> {
     tuple: &'p Vec<Ptr>,
-    store: &'s HeapStorage
+    store: &'s S
 }
 
 
-impl<'p,'s> TupleReader<'p, 's> for TupleItemReader<'p, 's> {
-    type Subhandle = ItemHandle<'s>;
-    type Handle = ItemHandle<'s>;
+impl<'p,'s, S: ItemStore> TupleReader<'p, 's> for TupleItemReader<'p, 's, S> {
+    type Subhandle = ItemHandle<'s, S>;
+    type Handle = ItemHandle<'s, S>;
 
-    type EntryIter<'r> = PtrVecIter<'r, 's> where Self : 'r;
+    type EntryIter<'r> = PtrVecIter<'r, 's, S> where Self : 'r;
 
     fn iter<'r>(&'r self) -> Self::EntryIter<'r> {
         PtrVecIter::new(self.tuple, self.store)
@@ -322,19 +504,19 @@ impl<'p,'s> TupleReader<'p, 's> for TupleItemReader<'p, 's> {
     }
 }
 
-pub struct RecordItemReader<'p, 's> {
+pub struct RecordItemReader<'p, 's, S = HeapStorage> {
     record: &'p Vec<(Ptr, Ptr)>,
-    store: &'s HeapStorage
+    store: &'s S
 }
 
-pub struct RecordIter<'p, 's> {
+pub struct RecordIter<'p, 's, S = HeapStorage> {
     record: &'p Vec<(Ptr, Ptr)>,
-    store: &'s HeapStorage,
+    store: &'s S,
     off: usize
 }
 
-impl<'p, 's> Iterator for RecordIter<'p, 's> {
-    type Item = (ItemHandle<'s>, ItemHandle<'s>);
+impl<'p, 's, S: ItemStore> Iterator for RecordIter<'p, 's, S> {
+    type Item = (ItemHandle<'s, S>, ItemHandle<'s, S>);
     fn next(&mut self) -> Option<Self::Item> {
         let res = match self.record.get(self.off) {
         Some((k, v)) => Some((self.store.get(*k), self.store.get(*v))),
@@ -345,11 +527,11 @@ impl<'p, 's> Iterator for RecordIter<'p, 's> {
     }
 }
 
-impl<'p, 's> RecordReader<'p, 's> for RecordItemReader<'p, 's> {
-    type Handle = ItemHandle<'s>;
-    type Subhandle = ItemHandle<'s>;
+impl<'p, 's, S: ItemStore> RecordReader<'p, 's> for RecordItemReader<'p, 's, S> {
+    type Handle = ItemHandle<'s, S>;
+    type Subhandle = ItemHandle<'s, S>;
 
-    type EntryIter<'r> = RecordIter<'r, 's> where Self : 'r;
+    type EntryIter<'r> = RecordIter<'r, 's, S> where Self : 'r;
 
     fn iter<'r>(&'r self) -> Self::EntryIter<'r> {
         RecordIter { record: self.record, store: self.store, off: 0}
@@ -370,16 +552,16 @@ impl<'p, 's> RecordReader<'p, 's> for RecordItemReader<'p, 's> {
     }
 }
 
-pub struct PartialItemReader<'p, 's> {
+pub struct PartialItemReader<'p, 's, S = HeapStorage> {
     code: &'p Ptr,
     args: &'p Vec<Ptr>,
-    store: &'s HeapStorage
+    store: &'s S
 }
 
-impl<'p, 's> PartialReader<'p, 's> for PartialItemReader<'p, 's> {
-    type Handle = ItemHandle<'s>;
-    type Subhandle = ItemHandle<'s>;
-    type ArgsIter<'r> = PtrVecIter<'r, 's> where Self : 'r;
+impl<'p, 's, S: ItemStore> PartialReader<'p, 's> for PartialItemReader<'p, 's, S> {
+    type Handle = ItemHandle<'s, S>;
+    type Subhandle = ItemHandle<'s, S>;
+    type ArgsIter<'r> = PtrVecIter<'r, 's, S> where Self : 'r;
 
     fn get_code(&self) -> Self::Subhandle {
         self.store.get(*self.code)
@@ -396,18 +578,18 @@ impl<'p, 's> PartialReader<'p, 's> for PartialItemReader<'p, 's> {
     }
 }
 
-pub struct CodeItemReader<'p, 's> {
+pub struct CodeItemReader<'p, 's, S = HeapStorage> {
     code: &'p Code,
-    store: &'s HeapStorage
+    store: &'s S
 }
 
-impl<'p, 's> CodeReader<'p, 's> for CodeItemReader<'p, 's> {
-    type Handle = ItemHandle<'s>;
-    type Subhandle = ItemHandle<'s>;
+impl<'p, 's, S: ItemStore> CodeReader<'p, 's> for CodeItemReader<'p, 's, S> {
+    type Handle = ItemHandle<'s, S>;
+    type Subhandle = ItemHandle<'s, S>;
 
     type ReadyIter<'h> = std::iter::Cloned<std::slice::Iter<'h, OpAddr>> where Self : 'h;
     type OpIter<'h> = std::iter::Cloned<std::slice::Iter<'h, Op>> where Self : 'h;
-    type ValueIter<'h> = PtrVecIter<'p, 's> where Self : 'h;
+    type ValueIter<'h> = PtrVecIter<'p, 's, S> where Self : 'h;
 
     fn get_op(&self, a: OpAddr) -> Op {
         self.code.ops[a as usize].clone()
@@ -431,17 +613,17 @@ impl<'p, 's> CodeReader<'p, 's> for CodeItemReader<'p, 's> {
 
 // The indirect builder
 
-pub struct HeapIndirectBuilder<'s> {
-    handle : ItemHandle<'s>
+pub struct ItemIndirectBuilder<'s, S = HeapStorage> {
+    pub(super) handle : ItemHandle<'s, S>
 }
 
-impl<'s> IndirectBuilder<'s> for HeapIndirectBuilder<'s> {
-    type Handle = ItemHandle<'s>;
-    fn handle(&self) -> ItemHandle<'s> {
+impl<'s, S: ItemStore> IndirectBuilder<'s> for ItemIndirectBuilder<'s, S> {
+    type Handle = ItemHandle<'s, S>;
+    fn handle(&self) -> ItemHandle<'s, S> {
         self.handle.clone()
     }
 
-    fn build(self, dest: ItemHandle<'s>) -> ItemHandle<'s> {
+    fn build(self, dest: ItemHandle<'s, S>) -> ItemHandle<'s, S> {
         match &self.handle.entry {
             None => panic!("Bad handle"),
             Some(item) => {
@@ -451,6 +633,7 @@ impl<'s> IndirectBuilder<'s> for HeapIndirectBuilder<'s> {
                 }
             }
         };
+        self.handle.store.set_indirect(self.handle.ptr, dest.ptr);
         self.handle
     }
 }
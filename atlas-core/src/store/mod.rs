@@ -1,14 +1,21 @@
 use crate::Error;
+use bytes::Bytes;
 
 pub mod op;
 pub mod value;
 pub mod heap;
+pub mod file;
+pub mod hash;
+pub mod serialize;
+pub mod copy;
 pub mod print;
 
 #[cfg(test)]
 pub mod test;
 
 pub use heap::HeapStorage;
+pub use file::FileStorage;
+pub use hash::HashStorage;
 
 use std::fmt;
 use print::Depth;
@@ -18,6 +25,15 @@ pub trait ThunkMap<'s> {
     type Handle;
     fn get(&self, h: &Self::Handle) -> Option<Self::Handle>;
     fn insert(&self, s: &Self::Handle, v: &Self::Handle);
+    fn remove(&self, s: &Self::Handle);
+    // A result which depends on resources read while it was evaluated,
+    // so may not be valid in a later process
+    fn insert_volatile(&self, s: &Self::Handle, v: &Self::Handle) {
+        self.insert(s, v)
+    }
+    fn is_volatile(&self, _s: &Self::Handle) -> bool {
+        false
+    }
 }
 
 
@@ -43,6 +59,26 @@ pub trait Storage {
             where R: ObjectReader<'p, 's, Handle=Self::Handle<'s>> {
         self.insert(&src)
     }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats::default()
+    }
+
+    // Reclaims the objects which are not reachable from a live handle.
+    // A thunk map entry keeps its result only while the thunk is reachable,
+    // and entries for freed thunks are dropped from every thunk map.
+    // Returns the number of objects freed.
+    // Storages which never free anything can ignore this
+    fn gc(&self) -> usize {
+        0
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StorageStats {
+    pub objects: usize,
+    // Approximate, including the contents of objects
+    pub bytes: usize
 }
 
 pub trait Storable<'s, S: Storage> {
@@ -56,12 +92,23 @@ pub trait Handle<'s> : Sized + Clone + fmt::Display + fmt::Debug + Sized +
     type Reader<'p>: ObjectReader<'p, 's, Handle=Self> where Self: 'p;
     fn reader<'p>(&'p self) -> Result<Self::Reader<'p>, Error>;
 
+    // A reference which does not keep the object from being collected
+    type Weak: WeakHandle<'s, Handle=Self>;
+    fn downgrade(&self) -> Self::Weak;
+
     fn pretty<'a, D, A>(&self, depth: Depth, a: &'a D) -> DocBuilder<'a, D, A> 
             where A: 'a, D: ?Sized + DocAllocator<'a, A> {
         print::pretty_handle(self, depth, a)
     }
 }
 
+// Compares and hashes as the handle it was made from
+pub trait WeakHandle<'s> : Clone + fmt::Display + Hash + Eq {
+    type Handle;
+    // None once the object has been freed
+    fn upgrade(&self) -> Option<Self::Handle>;
+}
+
 
 #[derive(Eq, PartialEq, Hash, Debug)]
 pub enum ObjectType {
@@ -200,6 +247,10 @@ pub trait BufferReader<'p> {
     fn as_slice<'r>(&'r self) -> Self::BufferSlice<'r> {
         self.slice(0, self.len())
     }
+    // The whole buffer, shared rather than copied where possible
+    fn to_bytes(&self) -> Bytes {
+        Bytes::copy_from_slice(&self.as_slice())
+    }
     fn len(&self) -> usize;
 }
 
@@ -13,6 +13,7 @@ pub enum BuiltinOp {
     EmptyTuple, Append,
     Nil, Cons, 
     JoinUrl, DecodeUtf8, EncodeUtf8,
+    Untar, Unzip, Gunzip, Tar,
     Compile, Fetch, Sys
 }
 impl<'a> TryFrom<&'a str> for BuiltinOp {
@@ -37,6 +38,10 @@ impl<'a> TryFrom<&'a str> for BuiltinOp {
         "join_url" => JoinUrl,
         "decode_utf8" => DecodeUtf8,
         "encode_utf8" => EncodeUtf8,
+        "untar" => Untar,
+        "unzip" => Unzip,
+        "gunzip" => Gunzip,
+        "tar" => Tar,
         "sys" => Sys,
         _ => return Err(Error::new(format!("Unrecognized op {}", v)))
         })
@@ -63,6 +68,10 @@ impl Into<&'static str> for BuiltinOp {
         JoinUrl => "join_url",
         DecodeUtf8 => "decode_utf8",
         EncodeUtf8 => "encode_utf8",
+        Untar => "untar",
+        Unzip => "unzip",
+        Gunzip => "gunzip",
+        Tar => "tar",
         Sys => "sys"
         }
     }
@@ -0,0 +1,130 @@
+// A portable encoding of the objects reachable from a handle:
+//   "ATLS", u32 version, u32 object count,
+//   the objects, each a u32 length followed by the item encoding of file.rs,
+//   u32 id of the root
+// Objects are numbered from 1 in the order they are written, with 0
+// standing for an unbuilt indirect. Children are written before their
+// parents, so only indirects may refer to objects written after them.
+// Shared objects are written once, so sharing and cycles
+// (which always pass through an indirect) are preserved.
+use std::collections::HashMap;
+
+use crate::{Error, ErrorKind};
+
+use super::{Storage, Handle, IndirectBuilder};
+use super::heap::{Ptr, Item, item_with};
+use super::copy::postorder;
+use super::file::{encode, decode};
+use super::value::{Value, Code};
+
+pub const MAGIC: &[u8; 4] = b"ATLS";
+pub const VERSION: u32 = 1;
+
+fn bad_format(msg: &'static str) -> Error {
+    Error::new_const(ErrorKind::BadFormat, msg)
+}
+
+pub fn export<'s, H: Handle<'s>>(root: &H) -> Result<Vec<u8>, Error> {
+    let order = postorder(root, |_| false)?;
+    let ids : HashMap<&H, Ptr> = order.iter().enumerate().map(|(i, h)| (h, i + 1)).collect();
+
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&(order.len() as u32).to_le_bytes());
+    for h in order.iter() {
+        let item = item_with(&h.reader()?, |c| ids[c]);
+        let start = buf.len();
+        buf.extend_from_slice(&[0u8; 4]);
+        encode(&item, &mut buf);
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+    buf.extend_from_slice(&(ids[root] as u32).to_le_bytes());
+    Ok(buf)
+}
+
+fn take<'b>(bytes: &mut &'b [u8], n: usize) -> Result<&'b [u8], Error> {
+    if bytes.len() < n {
+        return Err(bad_format("Truncated export"))
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+pub fn import<'s, S: Storage>(store: &'s S, mut bytes: &[u8]) -> Result<S::Handle<'s>, Error> {
+    if take(&mut bytes, 4)? != MAGIC {
+        return Err(bad_format("Not an exported object"))
+    }
+    let version = take_u32(&mut bytes)?;
+    if version != VERSION {
+        return Err(Error::new_kind(ErrorKind::BadFormat,
+            format!("Unsupported export version {} (expected {})", version, VERSION)))
+    }
+    let count = take_u32(&mut bytes)? as usize;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        let len = take_u32(&mut bytes)? as usize;
+        items.push(decode(take(&mut bytes, len)?)?);
+    }
+    let root = take_u32(&mut bytes)? as Ptr;
+    if !bytes.is_empty() || root == 0 || root > count {
+        return Err(bad_format("Malformed export"))
+    }
+    // Children must be written before their parents, unless they are indirects
+    let is_indirect = |p: Ptr| matches!(items[p - 1], Item::Indirect(_));
+    for (i, item) in items.iter().enumerate() {
+        for c in item.children() {
+            let valid = match item {
+                Item::Indirect(_) => c <= count,
+                _ => c != 0 && (c <= i || (c <= count && is_indirect(c)))
+            };
+            if !valid { return Err(bad_format("Malformed export")) }
+        }
+    }
+
+    let mut builders = Vec::new();
+    let mut handles : Vec<Option<S::Handle<'s>>> = Vec::new();
+    for item in items.iter() {
+        match item {
+            Item::Indirect(c) => {
+                let b = store.indirect()?;
+                handles.push(Some(b.handle()));
+                builders.push((b, c.get()));
+            },
+            _ => handles.push(None)
+        }
+    }
+    for (i, item) in items.into_iter().enumerate() {
+        if let Item::Indirect(_) = item { continue }
+        let h = |p: Ptr| handles[p - 1].clone().unwrap();
+        let value = match item {
+            Item::Indirect(_) => unreachable!(),
+            Item::Bot => Value::Bot, Item::Unit => Value::Unit, Item::Nil => Value::Nil,
+            Item::Char(c) => Value::Char(c), Item::Bool(b) => Value::Bool(b),
+            Item::Float(f) => Value::Float(f), Item::Int(i) => Value::Int(i),
+            Item::String(s) => Value::String(s),
+            Item::Buffer(b) => Value::Buffer(b),
+            Item::Cons(a, b) => Value::Cons(h(a), h(b)),
+            Item::Tuple(v) => Value::Tuple(v.into_iter().map(h).collect()),
+            Item::Record(r) => Value::Record(r.into_iter().map(|(k, v)| (h(k), h(v))).collect()),
+            Item::Variant(t, v) => Value::Variant(h(t), h(v)),
+            Item::Code(c) => Value::Code(Code::new(c.ret, c.ready, c.ops,
+                                            c.values.into_iter().map(h).collect())),
+            Item::Partial(c, args) => Value::Partial(h(c), args.into_iter().map(h).collect()),
+            Item::Thunk(p) => Value::Thunk(h(p))
+        };
+        handles[i] = Some(store.insert_from(&value)?);
+    }
+    for (b, target) in builders {
+        // an indirect which was never built stays bot
+        if target != 0 {
+            b.build(handles[target - 1].clone().unwrap());
+        }
+    }
+    Ok(handles[root - 1].clone().unwrap())
+}
@@ -4,13 +4,24 @@
 // use super::Numeric;
 
 use super::heap::HeapStorage;
+use super::file::FileStorage;
+use super::hash::HashStorage;
 use super::value::Value;
-use super::{Storage, Handle, ObjectReader, ReaderWhich};
+use super::{Storage, Handle, ObjectReader, ReaderWhich, IndirectBuilder,
+            RecordReader, TupleReader, StringReader};
+use std::borrow::Borrow;
+use std::ops::Deref;
+use std::path::PathBuf;
 
-#[test]
-fn test_store_numeric() {
+// A fresh directory for a file storage
+fn temp_store(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("atlas-store-{}-{}", std::process::id(), name));
+    std::fs::remove_dir_all(&dir).ok();
+    dir
+}
+
+fn check_numeric<S: Storage>(storage: &S) {
     // Test store + retrieve int
-    let storage = HeapStorage::new();
     let handle = storage.insert_from(&Value::Int(50)).unwrap();
     // Test store + retreive float on the same storage
     let result = handle.reader().unwrap().which();
@@ -27,6 +38,258 @@ fn test_store_numeric() {
     }
 }
 
+fn check_record<S: Storage>(storage: &S) {
+    let key = storage.insert_from(&Value::String("foo".to_string())).unwrap();
+    let val = storage.insert_from(&Value::Int(42)).unwrap();
+    let record = storage.insert_from(&Value::Record(vec![(key, val.clone())])).unwrap();
+    let reader = record.reader().unwrap();
+    let r = reader.as_record().unwrap();
+    assert_eq!(r.len(), 1);
+    assert_eq!(r.get("foo").unwrap().borrow(), &val);
+    let (k, _) = r.iter().next().unwrap();
+    assert_eq!(k.borrow().reader().unwrap().as_string().unwrap().as_slice().deref(), "foo");
+}
+
+fn check_indirect<S: Storage>(storage: &S) {
+    // An indirect which points to a tuple containing itself
+    let builder = storage.indirect().unwrap();
+    let tuple = storage.insert_from(&Value::Tuple(vec![builder.handle()])).unwrap();
+    let indirect = builder.build(tuple.clone());
+    match indirect.reader().unwrap().which() {
+        ReaderWhich::Indirect(h) => assert_eq!(h.borrow(), &tuple),
+        _ => panic!("Expected indirect")
+    }
+    let reader = tuple.reader().unwrap();
+    assert_eq!(reader.as_tuple().unwrap().get(0).unwrap().borrow(), &indirect);
+}
+
+#[test]
+fn test_store_numeric() {
+    check_numeric(&HeapStorage::new());
+    let dir = temp_store("numeric");
+    check_numeric(&FileStorage::open(&dir).unwrap());
+    std::fs::remove_dir_all(&dir).ok();
+    check_numeric(&HashStorage::new());
+}
+
+#[test]
+fn test_store_record() {
+    check_record(&HeapStorage::new());
+    let dir = temp_store("record");
+    check_record(&FileStorage::open(&dir).unwrap());
+    std::fs::remove_dir_all(&dir).ok();
+    check_record(&HashStorage::new());
+}
+
+#[test]
+fn test_store_indirect() {
+    check_indirect(&HeapStorage::new());
+    let dir = temp_store("indirect");
+    check_indirect(&FileStorage::open(&dir).unwrap());
+    std::fs::remove_dir_all(&dir).ok();
+    check_indirect(&HashStorage::new());
+}
+
+fn build_record<S: Storage>(s: &S) -> S::Handle<'_> {
+    let key = s.insert_from(&Value::String("foo".to_string())).unwrap();
+    let val = s.insert_from(&Value::Int(42)).unwrap();
+    s.insert_from(&Value::Record(vec![(key, val)])).unwrap()
+}
+
+#[test]
+fn test_hash_store_dedup() {
+    let storage = HashStorage::new();
+    let build = build_record::<HashStorage>;
+    let a = build(&storage);
+    let b = build(&storage);
+    assert_eq!(a, b);
+    assert_eq!(storage.len(), 3);
+    let other = storage.insert_from(&Value::Int(43)).unwrap();
+    assert_ne!(storage.digest(&a), storage.digest(&other));
+    // Digests do not depend on the storage
+    let fresh = HashStorage::new();
+    fresh.insert_from(&Value::Unit).unwrap();
+    let c = build(&fresh);
+    assert_eq!(storage.digest(&a), fresh.digest(&c));
+    assert_eq!(fresh.lookup(&storage.digest(&a).unwrap()), Some(c.clone()));
+    // and neither do handles, but nothing outlives the storage
+    assert_eq!(a.ptr, c.ptr);
+    assert!(HashStorage::new().lookup(&storage.digest(&a).unwrap()).is_none());
+    assert!(storage.stats().bytes > 0);
+    // an object must be inserted after its children
+    let orphan = fresh.insert_from(&Value::Unit).unwrap();
+    assert!(storage.insert_from(&Value::Thunk(orphan)).is_err());
+
+    // indirects are not deduplicated, even once built to the same target
+    let (i, j) = (storage.indirect().unwrap(), storage.indirect().unwrap());
+    let (ih, jh) = (i.handle(), j.handle());
+    i.build(a.clone());
+    j.build(a.clone());
+    assert_ne!(storage.digest(&ih), storage.digest(&jh));
+    assert_ne!(storage.insert_from(&Value::Thunk(ih)).unwrap(),
+               storage.insert_from(&Value::Thunk(jh)).unwrap());
+}
+
+#[test]
+fn test_heap_gc() {
+    use super::ThunkMap;
+    let storage = HeapStorage::new();
+    let record = build_record(&storage);
+    let thunk = storage.insert_from(&Value::Thunk(record.clone())).unwrap();
+    let result = storage.insert_from(&Value::Int(1)).unwrap();
+    let map = storage.create_thunk_map();
+    map.insert(&thunk, &result);
+    // every thunk map is seen, not just one
+    let other = storage.create_thunk_map();
+    other.insert(&thunk, &storage.insert_from(&Value::Int(2)).unwrap());
+    for i in 0..10 {
+        storage.insert_from(&Value::Int(i)).unwrap();
+    }
+    assert_eq!(storage.stats().objects, 16);
+    drop(result);
+    // the record and thunk are live, and the thunk keeps its results
+    assert_eq!(storage.gc(), 10);
+    assert_eq!(storage.stats().objects, 6);
+    assert_eq!(map.get(&thunk).unwrap().reader().unwrap().as_int().unwrap(), 1);
+    assert_eq!(other.get(&thunk).unwrap().reader().unwrap().as_int().unwrap(), 2);
+    // the entries go with their thunk
+    drop(thunk);
+    assert_eq!(storage.gc(), 3);
+    assert!(map.map.deref().borrow().is_empty() && other.map.deref().borrow().is_empty());
+    // so a new object in a reused slot has no result
+    let reused = storage.insert_from(&Value::Unit).unwrap();
+    assert!(map.get(&reused).is_none());
+    let reader = record.reader().unwrap();
+    let val = reader.as_record().unwrap().get("foo").unwrap();
+    assert_eq!(val.reader().unwrap().as_int().unwrap(), 42);
+}
+
+#[test]
+fn test_export_import() {
+    use super::op::{Op, Dest, BuiltinOp};
+    use super::serialize::{export, import};
+    let storage = HeapStorage::new();
+    let record = build_record(&storage);
+    // a cycle through an indirect, and a shared record
+    let builder = storage.indirect().unwrap();
+    let cons = storage.insert_from(&Value::Cons(record.clone(), builder.handle())).unwrap();
+    let list = builder.build(cons);
+    let code = storage.insert_from(&Value::Code(super::value::Code::new(1, vec![0], vec![
+        Op::SetValue(Dest { reg: 0, uses: vec![1] }, 0),
+        Op::Builtin(Dest { reg: 1, uses: vec![] }, BuiltinOp::Add, vec![0, 0])
+    ], vec![record.clone()]))).unwrap();
+    let root = storage.insert_from(&Value::Tuple(vec![list, record, code])).unwrap();
+    let bytes = export(&root).unwrap();
+
+    let other = HashStorage::new();
+    let imported = import(&other, &bytes).unwrap();
+    assert_eq!(export(&imported).unwrap(), bytes);
+    // 3 for the record, the indirect, the cons, the code and the tuple
+    assert_eq!(other.len(), 7);
+    let reader = imported.reader().unwrap();
+    let tuple = reader.as_tuple().unwrap();
+    let list = tuple.get(0).unwrap();
+    let cons = match list.reader().unwrap().which() {
+        ReaderWhich::Indirect(c) => c,
+        _ => panic!("Expected indirect")
+    };
+    match cons.reader().unwrap().which() {
+        ReaderWhich::Cons(h, t) => {
+            assert_eq!(h, tuple.get(1).unwrap());
+            assert_eq!(t, list);
+        },
+        _ => panic!("Expected cons")
+    }
+
+    let mut bad = bytes.clone();
+    bad[4] = 2;
+    assert!(import(&other, &bad).is_err());
+    assert!(import(&other, &bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn test_copy() {
+    use super::copy::Copier;
+    let storage = HeapStorage::new();
+    let record = build_record(&storage);
+    let builder = storage.indirect().unwrap();
+    let cons = storage.insert_from(&Value::Cons(record.clone(), builder.handle())).unwrap();
+    let list = builder.build(cons);
+    let dir = temp_store("copy");
+    let dest = FileStorage::open(&dir).unwrap();
+    let mut copier = Copier::new(&dest);
+    let copied = copier.copy(&list).unwrap();
+    // the record is shared with the first copy
+    let copied_record = copier.copy(&record).unwrap();
+    assert_eq!(dest.len(), 5);
+    let cons = match copied.reader().unwrap().which() {
+        ReaderWhich::Indirect(c) => c,
+        _ => panic!("Expected indirect")
+    };
+    match cons.reader().unwrap().which() {
+        ReaderWhich::Cons(h, t) => {
+            assert_eq!(h, copied_record);
+            assert_eq!(t, copied);
+        },
+        _ => panic!("Expected cons")
+    }
+    check_record(&dest);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_file_store_reopen() {
+    let dir = temp_store("reopen");
+    {
+        let storage = FileStorage::open(&dir).unwrap();
+        check_record(&storage);
+        check_indirect(&storage);
+    }
+    let storage = FileStorage::open(&dir).unwrap();
+    assert_eq!(storage.len(), 5);
+    // The record inserted before reopening
+    let record = super::heap::ItemStore::get(&storage, 3);
+    let reader = record.reader().unwrap();
+    let val = reader.as_record().unwrap().get("foo").unwrap();
+    assert_eq!(val.reader().unwrap().as_int().unwrap(), 42);
+    // The indirect, which was built after its tuple was written
+    let indirect = super::heap::ItemStore::get(&storage, 4);
+    match indirect.reader().unwrap().which() {
+        ReaderWhich::Indirect(h) => assert!(matches!(
+            h.reader().unwrap().which(), ReaderWhich::Tuple(_))),
+        _ => panic!("Expected indirect")
+    }
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_file_thunk_log() {
+    use super::ThunkMap;
+    let dir = temp_store("thunk-log");
+    {
+        let storage = FileStorage::open(&dir).unwrap();
+        let map = storage.create_thunk_map();
+        let (a, b) = (storage.insert_from(&Value::Int(1)).unwrap(), storage.insert_from(&Value::Int(2)).unwrap());
+        for _ in 0..200 {
+            map.insert(&a, &b);
+            map.remove(&a);
+        }
+        map.insert(&b, &a);
+        map.insert_volatile(&a, &b);
+        assert!(map.is_volatile(&a));
+    }
+    let log_len = || std::fs::metadata(dir.join("thunks.log")).unwrap().len();
+    assert_eq!(log_len(), 401 * 16);
+    let storage = FileStorage::open(&dir).unwrap();
+    let map = storage.create_thunk_map();
+    // only the persistent entry is kept
+    assert_eq!(log_len(), 16);
+    let (a, b) = (storage.insert_from(&Value::Int(1)).unwrap(), storage.insert_from(&Value::Int(2)).unwrap());
+    assert_eq!(map.get(&b), Some(a.clone()));
+    assert_eq!(map.get(&a), None);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
 // #[test]
 // fn test_store_record() {
 //     // Test store + retrieve int
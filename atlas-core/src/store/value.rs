@@ -90,7 +90,7 @@ impl<'p, 's, H: Handle<'s>> ObjectReader<'p, 's> for &'p Value<'s, H> {
             Value::Unit => Unit, Value::Char(c) => Char(*c), Value::Bool(b) => Bool(*b),
             Value::Int(i) => Int(*i), Value::Float(f) => Float(*f),
             Value::String(b) => String(StringValueReader{ s: b.deref() }),
-            Value::Buffer(b) => Buffer(BufferValueReader{ s: b.deref() }),
+            Value::Buffer(b) => Buffer(BufferValueReader{ s: b }),
             Value::Record(record) => Record(RecordValueReader { record, phantom: PhantomData }),
             Value::Tuple(tuple) => Tuple(TupleValueReader { tuple, phantom: PhantomData }),
             Value::Variant(t, v) => Variant(t, v),
@@ -122,16 +122,19 @@ impl<'p> StringReader<'p> for StringValueReader<'p> {
 }
 
 pub struct BufferValueReader<'p> {
-    s: &'p [u8],
+    s: &'p Bytes,
 }
 
 impl<'p> BufferReader<'p> for BufferValueReader<'p> {
     type BufferSlice<'sl> = &'sl [u8] where Self : 'sl;
 
     fn slice<'sl>(&'sl self, start: usize, len: usize) -> &'sl [u8] {
-        &self.s.borrow()[start..start+len]
+        &self.s[start..start+len]
     }
     fn len(&self) -> usize { self.s.len() }
+    fn to_bytes(&self) -> Bytes {
+        self.s.clone()
+    }
 }
 
 pub struct TupleValueReader<'p, 's, H: Handle<'s>> {
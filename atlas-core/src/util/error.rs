@@ -1,4 +1,8 @@
-#[derive(Debug)]
+use std::sync::Arc;
+
+// Errors are cheap to clone, so that one failure can be
+// handed to everyone waiting on it with its source chain intact
+#[derive(Debug, Clone)]
 pub struct Error(Repr);
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -15,6 +19,12 @@ pub enum ErrorKind {
     Compile,
     Internal,
     IncorrectType,
+    InfiniteLoop,
+    BudgetExceeded,
+    // Content did not match what it was pinned to
+    Integrity,
+    // No resource provider handles the url's scheme
+    UnsupportedScheme,
     Custom
 }
 
@@ -23,7 +33,14 @@ impl Error {
     where
         E: Into<Box<dyn std::error::Error + Send + Sync>>,
     {
-        Error(Repr::Custom(ErrorKind::Custom, error.into()))
+        Error(Repr::Custom(ErrorKind::Custom, Arc::from(error.into())))
+    }
+
+    pub fn new_kind<E>(kind: ErrorKind, error: E) -> Error
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Error(Repr::Custom(kind, Arc::from(error.into())))
     }
 
     pub fn kind(&self) -> ErrorKind {
@@ -37,6 +54,55 @@ impl Error {
     pub fn new_const(kind : ErrorKind, message: &'static str) -> Self {
         Error(Repr::SimpleMessage(kind, message))
     }
+
+    // Wraps the error with a message, keeping its kind
+    // and the original error as the source
+    pub fn context<M: Into<String>>(self, message: M) -> Self {
+        let kind = self.kind();
+        Error(Repr::Custom(kind, Arc::new(Context { message: message.into(), source: self })))
+    }
+
+    // The errors this one was caused by, outermost first
+    pub fn chain(&self) -> Vec<&(dyn std::error::Error + 'static)> {
+        let mut chain = Vec::new();
+        let mut next = std::error::Error::source(self);
+        while let Some(e) = next {
+            chain.push(e);
+            next = e.source();
+        }
+        chain
+    }
+}
+
+#[derive(Debug)]
+struct Context {
+    message: String,
+    source: Error
+}
+
+impl std::fmt::Display for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.message, self.source)
+    }
+}
+
+impl std::error::Error for Context {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.0 {
+            // a context is only a message on the error it wraps
+            Repr::Custom(_, e) => match e.downcast_ref::<Context>() {
+                Some(c) => Some(&c.source),
+                None => Some(e.as_ref())
+            },
+            _ => None
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -51,9 +117,19 @@ impl From<std::io::Error> for Error {
     }
 }
 
-#[derive(Debug)]
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Repr::Simple(k) => write!(f, "{:?}", k),
+            Repr::SimpleMessage(_, m) => write!(f, "{}", m),
+            Repr::Custom(_, e) => write!(f, "{}", e)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 enum Repr {
     Simple(ErrorKind),
     SimpleMessage(ErrorKind, &'static str),
-    Custom(ErrorKind, Box<dyn std::error::Error + Send>)
+    Custom(ErrorKind, Arc<dyn std::error::Error + Send + Sync>)
 }
\ No newline at end of file
@@ -0,0 +1,30 @@
+// Lowercase hex, for digests in file names, lockfiles and messages
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Accepts either case, but only exactly N bytes worth of digits
+pub fn decode<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N || !s.bytes().all(|b| b.is_ascii_hexdigit()) { return None }
+    let mut d = [0u8; N];
+    for (i, b) in d.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[2*i..2*i + 2], 16).ok()?;
+    }
+    Some(d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, decode};
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(encode(&[0x00, 0xab, 0x7f]), "00ab7f");
+        assert_eq!(decode::<3>("00ab7f"), Some([0x00, 0xab, 0x7f]));
+        assert_eq!(decode::<3>("00AB7F"), Some([0x00, 0xab, 0x7f]));
+        assert_eq!(decode::<3>("00ab7"), None);
+        assert_eq!(decode::<3>("00ab7g"), None);
+        assert_eq!(decode::<2>("+1ab"), None);
+    }
+}
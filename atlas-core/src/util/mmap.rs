@@ -0,0 +1,75 @@
+// Read-only memory mappings of files, so that large files are paged
+// in from disk as they are read rather than copied into memory up front
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::Bytes;
+use memmap2::Mmap;
+
+// Files smaller than this are just read
+pub const MIN_MAP_SIZE: u64 = 1 << 20;
+
+fn read(file: &File, len: u64) -> std::io::Result<Bytes> {
+    let mut buf = Vec::with_capacity(len as usize);
+    std::io::Read::read_to_end(&mut &*file, &mut buf)?;
+    Ok(Bytes::from(buf))
+}
+
+// A copy of the contents of a file, which nothing
+// done to the file afterwards can change
+pub fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<Bytes> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    read(&file, len)
+}
+
+// The contents of a file which is never modified in place (such as
+// one written to a temporary file and renamed into place), mapped if
+// it is large. Truncating the file while the bytes are alive would
+// fault whoever reads them, and writing to it would change them.
+// A file whose size or modification time changes while it is
+// being mapped is read instead
+pub fn map_file<P: AsRef<Path>>(path: P) -> std::io::Result<Bytes> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let before = file.metadata()?;
+    if before.len() < MIN_MAP_SIZE {
+        return read(&file, before.len())
+    }
+    // safe as long as the file isn't modified in place, see above
+    let map = unsafe { Mmap::map(&file)? };
+    let after = file.metadata()?;
+    if after.len() != before.len() || after.modified()? != before.modified()? {
+        drop(map);
+        return read_file(path)
+    }
+    Ok(Bytes::from_owner(map))
+}
+
+// The contents of any file, which may be modified in place afterwards.
+// Large files are copied into dir (which the kernel does without going
+// through memory), and the copy is unlinked right away and mapped, so
+// that nothing can change or truncate it while the bytes are alive.
+// A file whose size or modification time changes while it is
+// being copied is read instead
+pub fn snapshot_file<P: AsRef<Path>, D: AsRef<Path>>(path: P, dir: D) -> std::io::Result<Bytes> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let before = file.metadata()?;
+    if before.len() < MIN_MAP_SIZE {
+        return read(&file, before.len())
+    }
+    std::fs::create_dir_all(dir.as_ref())?;
+    let tmp = dir.as_ref().join(format!(".snapshot-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    let mut copy = OpenOptions::new().read(true).write(true).create_new(true).open(&tmp)?;
+    std::fs::remove_file(&tmp)?;
+    std::io::copy(&mut &file, &mut copy)?;
+    let after = file.metadata()?;
+    if after.len() != before.len() || after.modified()? != before.modified()? {
+        return read_file(path)
+    }
+    // the copy has no name, so nothing else can modify it
+    let map = unsafe { Mmap::map(&copy)? };
+    Ok(Bytes::from_owner(map))
+}
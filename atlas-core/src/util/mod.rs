@@ -1,2 +1,4 @@
 pub mod graph;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod hex;
+pub mod mmap;
@@ -0,0 +1,361 @@
+// Unpacking tar, gzip and zip archives into directory records, and
+// packing directory records into tars. Unpacked trees use the format
+// of FileProvider, {entries: {name: entry}} with files as
+// {content, executable}, plus symlinks as {symlink: target} (which
+// may not point outside of the archive).
+// Packed tars are reproducible: owners and times are zeroed, and
+// modes are 755 for executables and directories, 644 otherwise
+use crate::{Error, ErrorKind};
+use crate::store::{Storage, Handle, ObjectReader, RecordReader, ReaderWhich,
+                   StringReader, BufferReader};
+use crate::store::value::Value;
+use super::machine::Machine;
+use super::resource::{Alloc, file_entry, symlink_entry, dir_record};
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+use std::ops::Deref;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use tar::EntryType;
+
+// The most an archive may unpack to, when the
+// machine unpacking it has no byte budget
+pub const MAX_UNPACKED: usize = 1 << 32;
+
+fn bad(message: &'static str) -> Error {
+    Error::new_const(ErrorKind::BadFormat, message)
+}
+
+fn too_large() -> Error {
+    Error::new_const(ErrorKind::BudgetExceeded, "Archive unpacks to more than the allowed size")
+}
+
+enum Node {
+    File { content: Bytes, executable: bool },
+    Symlink(String),
+    Dir(BTreeMap<String, Node>)
+}
+
+#[derive(Default)]
+pub struct Tree {
+    root: BTreeMap<String, Node>
+}
+
+impl Tree {
+    // The components of an archive path, which may not leave the root
+    fn components(path: &str) -> Result<Vec<&str>, Error> {
+        let parts : Vec<&str> = path.split('/').filter(|p| !p.is_empty() && *p != ".").collect();
+        if parts.contains(&"..") {
+            return Err(Error::new_kind(ErrorKind::BadFormat,
+                format!("Archive entry {} is outside of the archive", path)))
+        }
+        Ok(parts)
+    }
+
+    // The most symlinks followed while resolving one, as on linux
+    const MAX_HOPS: usize = 40;
+
+    // Resolves a symlink target relative to the directory at path,
+    // following the symlinks already in the tree, or None if it leads
+    // outside of the archive (trees are unpacked into sandboxes, which
+    // shouldn't be able to reach anything else)
+    fn resolve(&self, mut path: Vec<String>, target: &str, hops: &mut usize) -> Result<Option<Vec<String>>, Error> {
+        if target.starts_with('/') {
+            return Ok(None)
+        }
+        for p in target.split('/') {
+            match p {
+                "" | "." => (),
+                ".." => if path.pop().is_none() {
+                    return Ok(None)
+                },
+                _ => {
+                    path.push(p.to_string());
+                    if let Some(Node::Symlink(next)) = self.lookup(&path) {
+                        *hops += 1;
+                        if *hops > Self::MAX_HOPS {
+                            return Err(bad("Too many levels of symlinks in archive"))
+                        }
+                        path.pop();
+                        path = match self.resolve(path, next, hops)? {
+                            Some(path) => path,
+                            None => return Ok(None)
+                        };
+                    }
+                }
+            }
+        }
+        Ok(Some(path))
+    }
+
+    // Checks that no symlink leads outside of the archive, once all
+    // entries are in (a symlink may only escape through one added later)
+    fn check_symlinks(&self) -> Result<(), Error> {
+        let mut stack = vec![(Vec::new(), &self.root)];
+        while let Some((path, dir)) = stack.pop() {
+            for (name, node) in dir {
+                match node {
+                    Node::Symlink(target) => {
+                        if self.resolve(path.clone(), target, &mut 0)?.is_none() {
+                            let mut link = path.clone();
+                            link.push(name.clone());
+                            return Err(Error::new_kind(ErrorKind::BadFormat,
+                                format!("Symlink {} to {} is outside of the archive", link.join("/"), target)))
+                        }
+                    },
+                    Node::Dir(d) => {
+                        let mut sub = path.clone();
+                        sub.push(name.clone());
+                        stack.push((sub, d));
+                    },
+                    Node::File { .. } => ()
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Later entries replace earlier ones, except that a directory
+    // is only created if it isn't there already
+    fn insert(&mut self, path: &str, node: Node) -> Result<(), Error> {
+        let parts = Self::components(path)?;
+        let (name, parents) = match parts.split_last() {
+            Some(p) => p,
+            None => return Ok(())
+        };
+        let mut dir = &mut self.root;
+        for p in parents {
+            let entry = dir.entry(p.to_string()).or_insert_with(|| Node::Dir(BTreeMap::new()));
+            if !matches!(entry, Node::Dir(_)) {
+                *entry = Node::Dir(BTreeMap::new())
+            }
+            dir = match entry {
+                Node::Dir(d) => d,
+                _ => unreachable!()
+            };
+        }
+        match (dir.get(*name), &node) {
+            (Some(Node::Dir(_)), Node::Dir(_)) => (),
+            _ => { dir.insert(name.to_string(), node); }
+        }
+        Ok(())
+    }
+
+    fn get(&self, path: &str) -> Option<&Node> {
+        self.lookup(&Self::components(path).ok()?)
+    }
+
+    fn lookup<P: AsRef<str>>(&self, parts: &[P]) -> Option<&Node> {
+        let (name, parents) = parts.split_last()?;
+        let mut dir = &self.root;
+        for p in parents {
+            dir = match dir.get(p.as_ref())? {
+                Node::Dir(d) => d,
+                _ => return None
+            };
+        }
+        dir.get(name.as_ref())
+    }
+
+    pub(crate) fn store_in<'s, S: Storage + 's, A: Alloc<'s, Store=S> + Copy>(self, store: A) -> Result<S::Handle<'s>, Error> {
+        fn dir<'s, S: Storage + 's, A: Alloc<'s, Store=S> + Copy>(store: A, entries: BTreeMap<String, Node>) -> Result<S::Handle<'s>, Error> {
+            let entries = entries.into_iter().map(|(name, node)| {
+                let value = match node {
+                    Node::File { content, executable } =>
+                        file_entry(store, store.alloc(&Value::Buffer(content))?, executable)?,
+                    Node::Symlink(target) => symlink_entry(store, target)?,
+                    Node::Dir(d) => dir(store, d)?
+                };
+                Ok((name, value))
+            }).collect::<Result<Vec<_>, Error>>()?;
+            dir_record(store, entries, Vec::new())
+        }
+        dir(store, self.root)
+    }
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| bad("Non-utf8 name in archive"))
+}
+
+// Archives are unpacked from memory, so any error reading one is a format error
+fn corrupt<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> Error {
+    Error::new_kind(ErrorKind::BadFormat, e)
+}
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+// Decompresses (the first member of) a gzip file,
+// which may decompress to at most limit bytes
+pub fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    if !is_gzip(data) {
+        return Err(bad("Not a gzip file"))
+    }
+    let mut out = Vec::new();
+    GzDecoder::new(data).take(limit as u64 + 1).read_to_end(&mut out)
+        .map_err(|e| corrupt(e).context("Corrupt gzip data"))?;
+    if out.len() > limit {
+        return Err(too_large())
+    }
+    Ok(out)
+}
+
+// Unpacks a tar, which may be gzipped (in which
+// case it may decompress to at most limit bytes)
+pub fn untar(data: &Bytes, limit: usize) -> Result<Tree, Error> {
+    if is_gzip(data) {
+        return untar(&Bytes::from(gunzip(data, limit)?), limit)
+    }
+    let mut tree = Tree::default();
+    let mut archive = tar::Archive::new(&data[..]);
+    for entry in archive.entries().map_err(corrupt)? {
+        let entry = entry.map_err(corrupt)?;
+        let name = utf8(&entry.path_bytes())?;
+        let link = match entry.link_name_bytes() {
+            Some(l) => utf8(&l)?,
+            None => String::new()
+        };
+        let executable = entry.header().mode().map_err(corrupt)? & 0o111 != 0;
+        // sizes come from the archive, so may be anything
+        let start = entry.raw_file_position() as usize;
+        let end = usize::try_from(entry.size()).ok().and_then(|size| start.checked_add(size))
+            .filter(|end| *end <= data.len())
+            .ok_or_else(|| bad("Truncated tar file"))?;
+        let body = data.slice(start..end);
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous if name.ends_with('/') =>
+                tree.insert(&name, Node::Dir(BTreeMap::new()))?,
+            EntryType::Regular | EntryType::Continuous =>
+                tree.insert(&name, Node::File { content: body, executable })?,
+            EntryType::Link => {
+                let node = match tree.get(&link) {
+                    Some(Node::File { content, .. }) => Node::File { content: content.clone(), executable },
+                    _ => return Err(Error::new_kind(ErrorKind::BadFormat,
+                            format!("Hard link {} to a missing file {}", name, link)))
+                };
+                tree.insert(&name, node)?
+            },
+            EntryType::Symlink => tree.insert(&name, Node::Symlink(link))?,
+            EntryType::Directory => tree.insert(&name, Node::Dir(BTreeMap::new()))?,
+            // global pax headers
+            EntryType::XGlobalHeader => (),
+            k => log::warn!("Skipping {} (tar entry type {}), which can't be unpacked", name, k.as_byte() as char)
+        }
+    }
+    tree.check_symlinks()?;
+    Ok(tree)
+}
+
+// Unpacks a zip, whose entries may decompress to at most limit bytes in total
+pub fn unzip(data: &Bytes, limit: usize) -> Result<Tree, Error> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(&data[..])).map_err(corrupt)?;
+    let mut tree = Tree::default();
+    let mut unpacked : usize = 0;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(corrupt)?;
+        let name = utf8(file.name_raw())?;
+        let size = usize::try_from(file.size()).map_err(|_| too_large())?;
+        unpacked = unpacked.saturating_add(size);
+        if unpacked > limit {
+            return Err(too_large())
+        }
+        // the size comes from the archive, so don't read past it
+        let mut content = Vec::new();
+        (&mut file).take(size as u64 + 1).read_to_end(&mut content)
+            .map_err(|e| corrupt(e).context(format!("Corrupt data for {} in zip", name)))?;
+        if content.len() != size {
+            return Err(Error::new_kind(ErrorKind::BadFormat, format!("Size mismatch for {} in zip", name)))
+        }
+        let mode = file.unix_mode().unwrap_or(0);
+        let node = if file.is_dir() {
+            Node::Dir(BTreeMap::new())
+        } else if mode & 0o170000 == 0o120000 {
+            Node::Symlink(utf8(&content)?)
+        } else {
+            Node::File { content: content.into(), executable: mode & 0o111 != 0 }
+        };
+        tree.insert(&name, node)?;
+    }
+    tree.check_symlinks()?;
+    Ok(tree)
+}
+
+// The fields of a record, which is forced
+fn fields<'s, S: Storage + 's>(h: &S::Handle<'s>) -> Result<Vec<(String, S::Handle<'s>)>, Error> {
+    let reader = h.reader()?;
+    let record = reader.as_record()?;
+    record.iter().map(|(k, v)| {
+        let key = k.borrow().reader()?.as_string()?.as_slice().to_string();
+        Ok((key, v.borrow().clone()))
+    }).collect()
+}
+
+fn field<'s, S: Storage + 's>(fields: &[(String, S::Handle<'s>)], name: &str) -> Option<S::Handle<'s>> {
+    fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+}
+
+// A header for an entry of a packed tar, with the owner and time zeroed
+fn tar_header(kind: EntryType, mode: u32, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+// Packs a directory record into a tar, forcing whatever it needs to
+pub async fn pack_tar<'s, S: Storage + 's>(mach: &Machine<'s, S>, dir: S::Handle<'s>) -> Result<Vec<u8>, Error> {
+    let mut out = tar::Builder::new(Vec::new());
+    // (path, entry) in the order they are written
+    let mut stack = vec![(String::new(), dir)];
+    while let Some((path, entry)) = stack.pop() {
+        let entry = mach.force(&entry).await?;
+        let entry = fields::<S>(&entry)?;
+        if let Some(entries) = field::<S>(&entry, "entries") {
+            if !path.is_empty() {
+                out.append_data(&mut tar_header(EntryType::Directory, 0o755, 0), &path, std::io::empty())
+                    .map_err(|e| Error::new_kind(ErrorKind::BadFormat, e))?;
+            }
+            let entries = mach.force(&entries).await?;
+            let mut entries = fields::<S>(&entries)?;
+            entries.sort_by(|a, b| b.0.cmp(&a.0));
+            for (name, child) in entries {
+                if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                    return Err(Error::new_kind(ErrorKind::BadFormat, format!("Bad entry name {:?}", name)))
+                }
+                let child_path = if path.is_empty() { name } else { format!("{}/{}", path, name) };
+                stack.push((child_path, child));
+            }
+        } else if let Some(target) = field::<S>(&entry, "symlink") {
+            let target = mach.force(&target).await?;
+            let target = target.reader()?.as_string()?.as_slice().to_string();
+            out.append_link(&mut tar_header(EntryType::Symlink, 0o777, 0), &path, &target)
+                .map_err(|e| Error::new_kind(ErrorKind::BadFormat, e))?;
+        } else {
+            let content = field::<S>(&entry, "content")
+                .ok_or_else(|| Error::new_kind(ErrorKind::BadFormat, format!("{} is not a file or directory", path)))?;
+            let content = mach.force(&content).await?;
+            let executable = match field::<S>(&entry, "executable") {
+                Some(e) => mach.force(&e).await?.reader()?.as_bool()?,
+                None => false
+            };
+            let reader = content.reader()?;
+            let content = match reader.which() {
+                ReaderWhich::Buffer(b) => b.to_bytes(),
+                ReaderWhich::String(s) => Bytes::copy_from_slice(s.as_slice().deref().as_bytes()),
+                _ => return Err(Error::new_const(ErrorKind::BadType, "Content must be buffer or string"))
+            };
+            let mode = if executable { 0o755 } else { 0o644 };
+            out.append_data(&mut tar_header(EntryType::Regular, mode, content.len() as u64), &path, &content[..])
+                .map_err(|e| Error::new_kind(ErrorKind::BadFormat, e))?;
+        }
+    }
+    out.into_inner().map_err(|e| Error::new_kind(ErrorKind::IO, e))
+}
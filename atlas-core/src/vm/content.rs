@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use bytes::Bytes;
+
+use crate::Error;
+use crate::store::Storage;
+use crate::store::value::Value;
+use crate::util::hex;
+use crate::util::mmap::map_file;
+
+pub type Sha256 = [u8; 32];
+
+// SHA-256, for checking content against the digests people
+// publish. Everything internal uses SHA3 (see store::hash)
+pub fn sha256(bytes: &[u8]) -> Sha256 {
+    use sha2::Digest;
+    sha2::Sha256::digest(bytes).into()
+}
+
+// Verified content by its SHA-256, kept in memory and (optionally)
+// in a directory on disk, so that content fetched with an expected
+// hash never needs fetching again
+pub struct ContentCache<'s, S: Storage + 's> {
+    store: &'s S,
+    items: RefCell<HashMap<Sha256, S::Handle<'s>>>,
+    dir: Option<PathBuf>
+}
+
+impl<'s, S: Storage + 's> ContentCache<'s, S> {
+    pub fn new(store: &'s S) -> Self {
+        Self { store, items: RefCell::new(HashMap::new()), dir: None }
+    }
+
+    pub fn with_dir<P: Into<PathBuf>>(store: &'s S, dir: P) -> Self {
+        Self { store, items: RefCell::new(HashMap::new()), dir: Some(dir.into()) }
+    }
+
+    pub fn get(&self, digest: &Sha256) -> Option<S::Handle<'s>> {
+        if let Some(h) = self.items.borrow().get(digest) {
+            return Some(h.clone())
+        }
+        let path = self.dir.as_ref()?.join(hex::encode(digest));
+        // cached files are only ever renamed into place, never modified
+        let bytes = map_file(&path).ok()?;
+        // the file may have been damaged since it was written
+        if &sha256(&bytes) != digest {
+            log::warn!("Ignoring corrupt cached content {}", path.display());
+            return None
+        }
+        self.insert(digest, bytes).ok()
+    }
+
+    // Caches bytes which are known to have the given digest
+    pub fn put(&self, digest: &Sha256, bytes: Bytes) -> Result<S::Handle<'s>, Error> {
+        if let Some(dir) = &self.dir {
+            let res : Result<(), Error> = (|| {
+                std::fs::create_dir_all(dir)?;
+                let tmp = dir.join(format!("{}.tmp{}", hex::encode(digest), std::process::id()));
+                std::fs::write(&tmp, &bytes)?;
+                std::fs::rename(&tmp, dir.join(hex::encode(digest)))?;
+                Ok(())
+            })();
+            if let Err(e) = res {
+                log::warn!("Unable to cache content: {}", e)
+            }
+        }
+        self.insert(digest, bytes)
+    }
+
+    fn insert(&self, digest: &Sha256, bytes: Bytes) -> Result<S::Handle<'s>, Error> {
+        let h = self.store.insert_from(&Value::Buffer(bytes))?;
+        self.items.borrow_mut().insert(*digest, h.clone());
+        Ok(h)
+    }
+}
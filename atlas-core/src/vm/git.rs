@@ -0,0 +1,233 @@
+// Reads trees out of git repositories, from urls like
+//   git+file:///path/to/repo?rev=main
+//   git+https://host/repo.git?rev=v1.0
+// The rev (HEAD if not given) is resolved to a commit, and the tree read
+// as a directory record, {commit, entries}, in the format of FileProvider.
+// Entries fetch urls pinned to the commit (with a path=... parameter),
+// so a snapshot of the tree stays at that commit however the ref moves.
+// Symlinks are entries of the form {symlink: target}
+use crate::store::Storage;
+use crate::store::hash::sha3;
+use crate::store::value::Value;
+use crate::{Error, ErrorKind};
+use crate::util::hex;
+use super::resource::{ResourceProvider, LazyFetch, file_entry, symlink_entry, dir_record, unsupported};
+
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use async_trait::async_trait;
+
+enum Repo {
+    Local(PathBuf),
+    // The url git fetches from
+    Remote(String)
+}
+
+struct GitUrl {
+    repo: Repo,
+    // The url without the query
+    base: Url,
+    rev: String,
+    path: String
+}
+
+impl GitUrl {
+    fn parse(res: &Url) -> Result<Self, Error> {
+        let transport = res.scheme().strip_prefix("git+").ok_or_else(|| unsupported(res))?;
+        let mut base = res.clone();
+        base.set_query(None);
+        base.set_fragment(None);
+        let inner = &base.as_str()["git+".len()..];
+        let repo = if transport == "file" {
+            let path = Url::parse(inner).ok().and_then(|u| u.to_file_path().ok())
+                .ok_or_else(|| Error::new_const(ErrorKind::BadFormat, "Not a file path"))?;
+            Repo::Local(path)
+        } else {
+            Repo::Remote(inner.to_string())
+        };
+        let mut rev = "HEAD".to_string();
+        let mut path = String::new();
+        for (k, v) in res.query_pairs() {
+            match k.as_ref() {
+                "rev" => rev = v.into_owned(),
+                "path" => path = v.trim_matches('/').to_string(),
+                _ => ()
+            }
+        }
+        // the rev goes on git's command line, where it would be taken as an option
+        if rev.starts_with('-') {
+            return Err(Error::new_kind(ErrorKind::BadFormat, format!("Bad git revision {}", rev)))
+        }
+        Ok(Self { repo, base, rev, path })
+    }
+
+    // The url of a path in the tree of the commit
+    fn pinned(&self, commit: &str, path: &str) -> Url {
+        let mut url = self.base.clone();
+        url.query_pairs_mut().append_pair("rev", commit).append_pair("path", path);
+        url
+    }
+}
+
+fn is_commit_id(rev: &str) -> bool {
+    rev.len() == 40 && rev.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// Runs git in the given directory, returning its output
+async fn git(dir: PathBuf, args: Vec<String>) -> Result<Vec<u8>, Error> {
+    log::trace!(target: "resource", "git {} (in {})", args.join(" "), dir.display());
+    let cmd_args = args.clone();
+    let out = blocking::unblock(move || {
+        std::process::Command::new("git").arg("-C").arg(&dir).args(&cmd_args)
+            .env_remove("GIT_DIR").env("GIT_TERMINAL_PROMPT", "0").output()
+    }).await.map_err(|e| Error::from(e).context("Unable to run git"))?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        return Err(Error::new_kind(ErrorKind::IO,
+            format!("git {} failed: {}", args.join(" "), stderr.trim())))
+    }
+    Ok(out.stdout)
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+pub struct GitProvider<'s, S: Storage + 's> {
+    store: &'s S,
+    lazy: LazyFetch<'s, S>,
+    // Where remote repositories are mirrored
+    dir: Option<PathBuf>,
+    offline: Cell<bool>
+}
+
+impl<'s, S: Storage + 's> GitProvider<'s, S> {
+    // Only reads local repositories
+    pub fn new(store: &'s S) -> Self {
+        Self { store, lazy: LazyFetch::new(store), dir: None, offline: Cell::new(false) }
+    }
+
+    pub fn with_cache_dir<P: Into<PathBuf>>(store: &'s S, dir: P) -> Self {
+        Self { store, lazy: LazyFetch::new(store), dir: Some(dir.into()), offline: Cell::new(false) }
+    }
+
+    // Offline, remote revs resolve to whatever they were last fetched as
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.set(offline)
+    }
+
+    // The repository git reads objects from, fetching the rev into the
+    // mirror of a remote repository if it isn't there already. Returns
+    // the local ref the rev can be resolved by
+    async fn local(&self, url: &GitUrl) -> Result<(PathBuf, String), Error> {
+        let remote = match &url.repo {
+            Repo::Local(path) => {
+                if !path.exists() {
+                    return Err(Error::new_kind(ErrorKind::NotFound,
+                        format!("No repository at {}", path.display())))
+                }
+                return Ok((path.clone(), url.rev.clone()))
+            },
+            Repo::Remote(remote) => remote
+        };
+        let dir = self.dir.as_ref().ok_or_else(|| Error::new_const(ErrorKind::NotFound,
+            "Remote git repositories need a cache directory"))?;
+        let key = sha3(remote.as_bytes());
+        let mirror = dir.join(hex::encode(&key));
+        if !mirror.join("HEAD").exists() {
+            std::fs::create_dir_all(&mirror)?;
+            git(mirror.clone(), args(&["init", "--bare", "-q"])).await?;
+        }
+        // revs are fetched into refs of their own, so they can be resolved offline
+        let rev_key = sha3(url.rev.as_bytes());
+        let local_ref = format!("refs/atlas/{}", hex::encode(&rev_key[..8]));
+        let have_commit = is_commit_id(&url.rev) && git(mirror.clone(),
+            args(&["cat-file", "-e", &format!("{}^{{commit}}", url.rev)])).await.is_ok();
+        if have_commit {
+            return Ok((mirror, url.rev.clone()))
+        }
+        if self.offline.get() {
+            log::debug!(target: "resource", "Offline, using the last fetch of {} {}", remote, url.rev);
+        } else {
+            log::info!(target: "resource", "Fetching {} from {}", url.rev, remote);
+            git(mirror.clone(), args(&["fetch", "-q", "--no-tags", remote,
+                    &format!("+{}:{}", url.rev, local_ref)])).await
+                .map_err(|e| e.context(format!("Unable to fetch {} from {}", url.rev, remote)))?;
+        }
+        Ok((mirror, local_ref))
+    }
+
+    async fn resolve(&self, repo: &Path, rev: &str) -> Result<String, Error> {
+        let out = git(repo.to_path_buf(), args(&["rev-parse", "--verify", "-q", &format!("{}^{{commit}}", rev)])).await
+            .map_err(|_| Error::new_kind(ErrorKind::NotFound,
+                format!("Unknown revision {} in {}", rev, repo.display())))?;
+        Ok(String::from_utf8_lossy(&out).trim().to_string())
+    }
+
+    async fn read_tree(&self, url: &GitUrl, repo: &Path, commit: &str) -> Result<S::Handle<'s>, Error> {
+        let tree = if url.path.is_empty() {
+            format!("{}^{{tree}}", commit)
+        } else {
+            format!("{}:{}", commit, url.path)
+        };
+        let listing = git(repo.to_path_buf(), args(&["ls-tree", "-z", &tree])).await?;
+        let mut entries = Vec::new();
+        for line in listing.split(|b| *b == 0).filter(|l| !l.is_empty()) {
+            // <mode> <type> <object>\t<name>
+            let line = std::str::from_utf8(line).map_err(|_| Error::new_const(ErrorKind::BadFormat,
+                                                    "Non-utf8 file name in git tree"))?;
+            let (info, name) = line.split_once('\t')
+                .ok_or_else(|| Error::new_const(ErrorKind::BadFormat, "Malformed git tree"))?;
+            let mut info = info.split(' ');
+            let (mode, object) = (info.next().unwrap_or(""), info.nth(1).unwrap_or(""));
+            let path = if url.path.is_empty() { name.to_string() } else { format!("{}/{}", url.path, name) };
+            let child = url.pinned(commit, &path);
+            let value = match mode {
+                "040000" => self.lazy.thunk(&child)?,
+                "100644" | "100755" => file_entry(self.store, self.lazy.thunk(&child)?, mode == "100755")?,
+                "120000" => {
+                    let target = git(repo.to_path_buf(), args(&["cat-file", "blob", object])).await?;
+                    let target = String::from_utf8(target).map_err(|_|
+                        Error::new_const(ErrorKind::BadFormat, "Non-utf8 symlink target"))?;
+                    symlink_entry(self.store, target)?
+                },
+                _ => {
+                    log::warn!(target: "resource", "Skipping {} (mode {}) in {}", path, mode, url.base);
+                    continue
+                }
+            };
+            entries.push((name.to_string(), value));
+        }
+        let commit = self.store.insert_from(&Value::String(commit.to_string()))?;
+        dir_record(self.store, entries, vec![("commit", commit)])
+    }
+}
+
+#[async_trait(?Send)]
+impl<'s, S: Storage + 's> ResourceProvider<'s, S> for GitProvider<'s, S> {
+    fn schemes(&self) -> Vec<&'static str> {
+        vec!["git+file", "git+http", "git+https", "git+ssh"]
+    }
+    async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
+        let url = GitUrl::parse(res)?;
+        let (repo, rev) = self.local(&url).await?;
+        let commit = self.resolve(&repo, &rev).await?;
+        if url.path.is_empty() {
+            return self.read_tree(&url, &repo, &commit).await
+        }
+        let object = format!("{}:{}", commit, url.path);
+        let kind = git(repo.clone(), args(&["cat-file", "-t", &object])).await
+            .map_err(|_| Error::new_kind(ErrorKind::NotFound,
+                format!("No {} at {} in {}", url.path, commit, url.base)))?;
+        match String::from_utf8_lossy(&kind).trim() {
+            "tree" => self.read_tree(&url, &repo, &commit).await,
+            "blob" => {
+                let content = git(repo, args(&["cat-file", "blob", &object])).await?;
+                self.store.insert_from(&Value::Buffer(content.into()))
+            },
+            k => Err(Error::new_kind(ErrorKind::BadFormat, format!("Can't read a git {}", k)))
+        }
+    }
+}
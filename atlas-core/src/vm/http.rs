@@ -0,0 +1,473 @@
+use crate::store::Storage;
+use crate::store::hash::sha3;
+use crate::store::value::Value;
+use crate::{Error, ErrorKind};
+use crate::util::hex;
+use crate::util::mmap::map_file;
+use super::resource::{ResourceProvider, unsupported};
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use bytes::Bytes;
+use url::Url;
+
+use async_trait::async_trait;
+use curl::easy::{Easy, List};
+use smol::Timer;
+
+// How downloads are done. Read from a config file of lines
+//   header <host> <Name>: <value>
+//   follow-redirects yes|no
+//   max-redirects <n>
+//   connect-timeout <seconds>
+//   timeout <seconds>
+//   retries <n>
+//   backoff <milliseconds>
+// Header values can refer to environment variables as $VAR or ${VAR}
+// (so tokens needn't be in the file), and more headers can be
+// given as lines of "<host> <Name>: <value>" in $ATLAS_HTTP_HEADERS.
+// A host of * matches every host, and otherwise also matches its subdomains
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub follow_redirects: bool,
+    pub max_redirects: u32,
+    pub connect_timeout: Option<Duration>,
+    // For the whole transfer
+    pub timeout: Option<Duration>,
+    // Transfers which fail to connect, time out or get a 5xx or 429
+    // are retried, waiting backoff, then twice that, and so on
+    pub retries: u32,
+    pub backoff: Duration,
+    // (host, header)
+    pub headers: Vec<(String, String)>
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            follow_redirects: true, max_redirects: 10,
+            connect_timeout: Some(Duration::from_secs(30)), timeout: None,
+            retries: 3, backoff: Duration::from_millis(500),
+            headers: Vec::new()
+        }
+    }
+}
+
+// Replaces $VAR and ${VAR} with the value of the environment variable
+fn expand_env(s: &str) -> Result<String, Error> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, tail) = if let Some(r) = rest.strip_prefix('{') {
+            let end = r.find('}').ok_or(Error::new_const(ErrorKind::BadFormat, "Unterminated ${"))?;
+            (&r[..end], &r[end + 1..])
+        } else {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        let value = std::env::var(name).map_err(|_| Error::new_kind(ErrorKind::NotFound,
+                        format!("Environment variable {} is not set", name)))?;
+        out.push_str(&value);
+        rest = tail;
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn parse_header(line: &str) -> Option<(String, String)> {
+    let (host, header) = line.trim().split_once(' ')?;
+    let header = header.trim();
+    if !header.contains(':') { return None }
+    Some((host.to_string(), header.to_string()))
+}
+
+impl HttpConfig {
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut config = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue }
+            let bad = || Error::new_kind(ErrorKind::BadFormat,
+                            format!("Malformed http config on line {}", n + 1));
+            let (key, value) = line.split_once(' ').ok_or_else(bad)?;
+            let value = value.trim();
+            let secs = || value.parse::<f64>().ok().filter(|s| *s >= 0.0)
+                            .map(Duration::from_secs_f64).ok_or_else(bad);
+            match key {
+                "header" => config.headers.push(parse_header(value).ok_or_else(bad)?),
+                "follow-redirects" => config.follow_redirects = match value {
+                    "yes" => true, "no" => false, _ => return Err(bad())
+                },
+                "max-redirects" => config.max_redirects = value.parse().map_err(|_| bad())?,
+                "connect-timeout" => config.connect_timeout = Some(secs()?),
+                "timeout" => config.timeout = Some(secs()?),
+                "retries" => config.retries = value.parse().map_err(|_| bad())?,
+                "backoff" => config.backoff = Duration::from_millis(value.parse().map_err(|_| bad())?),
+                _ => return Err(bad())
+            }
+        }
+        Ok(config)
+    }
+
+    // The config file (if there is one) along with $ATLAS_HTTP_HEADERS
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into())
+        };
+        if let Ok(headers) = std::env::var("ATLAS_HTTP_HEADERS") {
+            for line in headers.lines().filter(|l| !l.trim().is_empty()) {
+                config.headers.push(parse_header(line).ok_or(
+                    Error::new_const(ErrorKind::BadFormat, "Malformed header in ATLAS_HTTP_HEADERS"))?);
+            }
+        }
+        Ok(config)
+    }
+
+    // The headers to send with a request to the url
+    pub fn headers_for(&self, url: &Url) -> Result<Vec<String>, Error> {
+        let host = url.host_str().unwrap_or("");
+        self.headers.iter().filter(|(h, _)| {
+            h == "*" || h == host || host.ends_with(&format!(".{}", h))
+        }).map(|(_, header)| expand_env(header)).collect()
+    }
+}
+
+struct Response {
+    status: u32,
+    // lowercased name --> value
+    headers: HashMap<String, String>,
+    body: Body
+}
+
+enum Body {
+    Memory(Vec<u8>),
+    // Streamed into a temporary file (in the cache directory)
+    File(PathBuf)
+}
+
+enum Failure {
+    // Worth trying again (i.e the connection failed or timed out)
+    Transient(Error),
+    Fatal(Error)
+}
+
+// Performs a request, writing the body to the file at tmp if given
+fn perform(url: &Url, headers: &[String], config: &HttpConfig, tmp: Option<&Path>) -> Result<Response, Failure> {
+    let mut handle = Easy::new();
+    let mut body = Vec::new();
+    let save_err = |e: std::io::Error| Failure::Fatal(Error::from(e).context(format!("Unable to save {}", url)));
+    let mut file = match tmp {
+        Some(path) => Some(BufWriter::new(File::create(path).map_err(save_err)?)),
+        None => None
+    };
+    let mut write_err = None;
+    let mut response_headers = HashMap::new();
+    let fetch_err = |e: curl::Error| {
+        let err = Error::new_kind(ErrorKind::IO, format!("Unable to fetch {}: {}", url, e));
+        if e.is_couldnt_connect() || e.is_operation_timedout() || e.is_couldnt_resolve_host()
+                || e.is_recv_error() || e.is_send_error() || e.is_got_nothing() || e.is_partial_file() {
+            Failure::Transient(err)
+        } else {
+            Failure::Fatal(err)
+        }
+    };
+    handle.url(url.as_str()).map_err(fetch_err)?;
+    if let Some(t) = config.connect_timeout {
+        handle.connect_timeout(t).map_err(fetch_err)?;
+    }
+    if let Some(t) = config.timeout {
+        handle.timeout(t).map_err(fetch_err)?;
+    }
+    let mut list = List::new();
+    for h in headers {
+        list.append(h).map_err(fetch_err)?;
+    }
+    handle.http_headers(list).map_err(fetch_err)?;
+    let res = {
+        let mut transfer = handle.transfer();
+        transfer.write_function(|new_data| {
+            match &mut file {
+                Some(f) => if let Err(e) = f.write_all(new_data) {
+                    // which makes curl give up on the transfer
+                    write_err = Some(e);
+                    return Ok(0)
+                },
+                None => body.extend_from_slice(new_data)
+            }
+            Ok(new_data.len())
+        }).map_err(fetch_err)?;
+        transfer.header_function(|line| {
+            let line = String::from_utf8_lossy(line);
+            if let Some((name, value)) = line.split_once(':') {
+                response_headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+            }
+            true
+        }).map_err(fetch_err)?;
+        transfer.perform()
+    };
+    if let Some(e) = write_err {
+        return Err(save_err(e))
+    }
+    res.map_err(fetch_err)?;
+    let status = handle.response_code().map_err(fetch_err)?;
+    let body = match (file, tmp) {
+        (Some(f), Some(path)) => {
+            f.into_inner().map_err(|e| save_err(e.into_error()))?;
+            Body::File(path.to_path_buf())
+        },
+        _ => Body::Memory(body)
+    };
+    Ok(Response { status, headers: response_headers, body })
+}
+
+fn status_error(url: &Url, status: u32) -> Error {
+    let kind = match status {
+        404 | 410 => ErrorKind::NotFound,
+        _ => ErrorKind::IO
+    };
+    Error::new_kind(kind, format!("Fetching {} failed with HTTP status {}", url, status))
+}
+
+// What is known about a cached download. Kept next
+// to the body as text, one "<field> <value>" per line
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct CacheMeta {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    // sha3-256 of the body, in hex
+    sha3: String
+}
+
+impl CacheMeta {
+    fn parse(text: &str) -> Option<Self> {
+        let mut meta = CacheMeta::default();
+        for line in text.lines() {
+            let (field, value) = line.split_once(' ')?;
+            match field {
+                "url" => meta.url = value.to_string(),
+                "etag" => meta.etag = Some(value.to_string()),
+                "last-modified" => meta.last_modified = Some(value.to_string()),
+                "sha3" => meta.sha3 = value.to_string(),
+                _ => ()
+            }
+        }
+        Some(meta)
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!("url {}\n", self.url);
+        if let Some(e) = &self.etag {
+            text.push_str(&format!("etag {}\n", e));
+        }
+        if let Some(m) = &self.last_modified {
+            text.push_str(&format!("last-modified {}\n", m));
+        }
+        text.push_str(&format!("sha3 {}\n", self.sha3));
+        text
+    }
+}
+
+// Downloads over http(s). Downloads are kept for the life of the
+// provider and (given a cache directory) on disk, where they are
+// revalidated with the server using their ETag and Last-Modified
+pub struct HttpProvider<'s, S: Storage> {
+    store: &'s S,
+    cache: RefCell<HashMap<Url, S::Handle<'s>>>,
+    dir: Option<PathBuf>,
+    // Only serve what is in the cache directory
+    offline: Cell<bool>,
+    config: HttpConfig
+}
+
+impl<'s, S: Storage> HttpProvider<'s, S> {
+    pub fn new(store: &'s S) -> Self {
+        Self { store, cache: RefCell::new(HashMap::new()), dir: None,
+               offline: Cell::new(false), config: HttpConfig::default() }
+    }
+
+    pub fn with_cache_dir<P: Into<PathBuf>>(store: &'s S, dir: P) -> Self {
+        Self { store, cache: RefCell::new(HashMap::new()), dir: Some(dir.into()),
+               offline: Cell::new(false), config: HttpConfig::default() }
+    }
+
+    pub fn set_config(&mut self, config: HttpConfig) {
+        self.config = config;
+    }
+
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.set(offline)
+    }
+
+    // Where the body for a url is cached, with its metadata alongside
+    fn cache_path(dir: &Path, url: &str) -> PathBuf {
+        dir.join(hex::encode(&sha3(url.as_bytes())))
+    }
+
+    // The cached body for a url and what is known about it
+    fn load(&self, url: &Url) -> Option<(CacheMeta, Bytes)> {
+        let path = Self::cache_path(self.dir.as_ref()?, url.as_str());
+        let meta = CacheMeta::parse(&std::fs::read_to_string(path.with_extension("meta")).ok()?)?;
+        let body = map_file(&path).ok()?;
+        if meta.url != url.as_str() || hex::encode(&sha3(&body)) != meta.sha3 {
+            log::warn!(target: "resource", "Ignoring corrupt cached download {}", path.display());
+            return None
+        }
+        Some((meta, body))
+    }
+
+    // Moves a body streamed into tmp (next to where it is cached) into
+    // place, then writes its metadata (so that the metadata never
+    // describes a body that isn't there)
+    fn save(&self, mut meta: CacheMeta, tmp: &Path) -> Result<Bytes, Error> {
+        let path = tmp.with_extension("");
+        std::fs::rename(tmp, &path)?;
+        let body = map_file(&path)?;
+        meta.sha3 = hex::encode(&sha3(&body));
+        let meta_tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&meta_tmp, meta.to_text())?;
+        std::fs::rename(&meta_tmp, path.with_extension("meta"))?;
+        Ok(body)
+    }
+
+    async fn download(&self, url: &Url) -> Result<Bytes, Error> {
+        let cached = self.load(url);
+        if self.offline.get() {
+            return match cached {
+                Some((_, body)) => Ok(body),
+                None => Err(Error::new_kind(ErrorKind::NotFound,
+                            format!("{} is not cached and we are offline", url)))
+            }
+        }
+        let mut headers = Vec::new();
+        if let Some((meta, _)) = &cached {
+            if let Some(e) = &meta.etag {
+                headers.push(format!("If-None-Match: {}", e));
+            }
+            if let Some(m) = &meta.last_modified {
+                headers.push(format!("If-Modified-Since: {}", m));
+            }
+        }
+        // given a cache directory, the body is streamed into a file there
+        let tmp = self.dir.as_ref().filter(|d| match std::fs::create_dir_all(d) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!(target: "resource", "Unable to cache download: {}", e);
+                false
+            }
+        }).map(|d| Self::cache_path(d, url.as_str()).with_extension(format!("part{}", std::process::id())));
+        let resp = self.request(url, headers, tmp.clone()).await;
+        let res = match resp {
+            Ok(resp) => match (resp.status, cached, resp.body) {
+                (304, Some((_, body)), _) => {
+                    log::debug!(target: "resource", "{} not modified", url);
+                    Ok(body)
+                },
+                (200..=299, _, body) => {
+                    let meta = CacheMeta {
+                        url: url.to_string(),
+                        etag: resp.headers.get("etag").cloned(),
+                        last_modified: resp.headers.get("last-modified").cloned(),
+                        sha3: String::new()
+                    };
+                    match body {
+                        Body::File(tmp) => self.save(meta, &tmp)
+                            .map_err(|e| e.context(format!("Unable to cache download of {}", url))),
+                        Body::Memory(body) => Ok(Bytes::from(body))
+                    }
+                },
+                (status, _, _) => Err(status_error(url, status))
+            },
+            Err(e) => Err(e)
+        };
+        // unless it was moved into place
+        if let Some(tmp) = &tmp {
+            std::fs::remove_file(tmp).ok();
+        }
+        res
+    }
+
+    // Performs the request, following redirects here rather than in curl
+    // so that the configured headers are worked out again for every hop
+    // (and a token for one host is never sent on to another)
+    async fn request(&self, url: &Url, extra: Vec<String>, tmp: Option<PathBuf>) -> Result<Response, Error> {
+        let mut url = url.clone();
+        let mut hops = 0;
+        loop {
+            let mut headers = self.config.headers_for(&url)?;
+            headers.extend(extra.iter().cloned());
+            let resp = self.attempt(&url, headers, tmp.clone()).await?;
+            let location = match resp.status {
+                301 | 302 | 303 | 307 | 308 if self.config.follow_redirects => resp.headers.get("location"),
+                _ => None
+            };
+            let location = match location {
+                Some(l) => l,
+                None => return Ok(resp)
+            };
+            if hops >= self.config.max_redirects {
+                return Err(Error::new_kind(ErrorKind::IO,
+                    format!("Fetching {} took more than {} redirects", url, self.config.max_redirects)))
+            }
+            let next = url.join(location).ok().filter(|u| u.scheme() == "http" || u.scheme() == "https")
+                .ok_or_else(|| Error::new_kind(ErrorKind::IO, format!("Bad redirect from {} to {}", url, location)))?;
+            log::debug!(target: "resource", "{} redirected to {}", url, next);
+            url = next;
+            hops += 1;
+        }
+    }
+
+    // Performs a single request, retrying with backoff
+    async fn attempt(&self, url: &Url, headers: Vec<String>, tmp: Option<PathBuf>) -> Result<Response, Error> {
+        let mut attempt = 0;
+        loop {
+            log::info!(target: "resource", "Fetching remote resource {}", url);
+            let (req_url, req_headers, config, tmp) = (url.clone(), headers.clone(), self.config.clone(), tmp.clone());
+            let res = blocking::unblock(move || perform(&req_url, &req_headers, &config, tmp.as_deref())).await;
+            let err = match res {
+                Ok(resp) if resp.status >= 500 || resp.status == 429 => status_error(url, resp.status),
+                Ok(resp) => return Ok(resp),
+                Err(Failure::Transient(e)) => e,
+                Err(Failure::Fatal(e)) => return Err(e)
+            };
+            if attempt >= self.config.retries {
+                return Err(err)
+            }
+            let wait = self.config.backoff * 2u32.saturating_pow(attempt);
+            log::warn!(target: "resource", "{}, retrying in {:?}", err, wait);
+            Timer::after(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<'s, S: Storage + 's> ResourceProvider<'s, S> for HttpProvider<'s, S> {
+    fn schemes(&self) -> Vec<&'static str> {
+        vec!["http", "https"]
+    }
+    async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
+        if res.scheme() != "http" && res.scheme() != "https" {
+            return Err(unsupported(res))
+        }
+        {
+            let cache = self.cache.borrow_mut();
+            if let Some(h) = cache.get(res) {
+                return Ok(h.clone())
+            }
+        }
+        let body = self.download(res).await?;
+        let val = Value::Buffer(body);
+        let handle = self.store.insert_from(&val)?;
+        self.cache.borrow_mut().insert(res.clone(), handle.clone());
+        Ok(handle)
+    }
+}
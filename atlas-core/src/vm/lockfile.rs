@@ -0,0 +1,152 @@
+// Pins the content of every resource a snapshot has fetched, so that
+// a later run (or another machine) gets exactly the same inputs.
+// The lockfile is text, one resource per line and sorted by url:
+//   <sha3-256 of the content, in hex> <size in bytes> <url>
+// The content of a string or buffer is its bytes, and of
+// anything else its export (see store::serialize)
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Deref;
+use std::path::Path;
+use url::Url;
+
+use crate::{Error, ErrorKind};
+use crate::store::{Handle, ObjectReader, ReaderWhich, StringReader, BufferReader};
+use crate::store::hash::{Digest, sha3};
+use crate::store::serialize::export;
+use crate::util::hex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockEntry {
+    pub digest: Digest,
+    pub size: u64
+}
+
+impl LockEntry {
+    pub fn of_bytes(bytes: &[u8]) -> Self {
+        Self { digest: sha3(bytes), size: bytes.len() as u64 }
+    }
+
+    pub fn of<'s, H: Handle<'s>>(h: &H) -> Result<Self, Error> {
+        let reader = h.reader()?;
+        Ok(match reader.which() {
+            ReaderWhich::String(s) => Self::of_bytes(s.as_slice().deref().as_bytes()),
+            ReaderWhich::Buffer(b) => Self::of_bytes(b.as_slice().deref()),
+            _ => Self::of_bytes(&export(h)?)
+        })
+    }
+}
+
+impl fmt::Display for LockEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", hex::encode(&self.digest), self.size)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockChange {
+    Added(Url, LockEntry),
+    Removed(Url, LockEntry),
+    Changed(Url, LockEntry, LockEntry)
+}
+
+impl fmt::Display for LockChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockChange::Added(u, e) => write!(f, "+ {} {}", e, u),
+            LockChange::Removed(u, e) => write!(f, "- {} {}", e, u),
+            LockChange::Changed(u, old, new) => write!(f, "- {} {}\n+ {} {}", old, u, new, u)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lockfile {
+    entries: BTreeMap<Url, LockEntry>
+}
+
+impl Lockfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut entries = BTreeMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue }
+            let bad = || Error::new_kind(ErrorKind::BadFormat,
+                            format!("Malformed lockfile entry on line {}", n + 1));
+            let mut parts = line.splitn(3, ' ');
+            let digest = parts.next().and_then(hex::decode).ok_or_else(bad)?;
+            let size = parts.next().and_then(|s| s.parse().ok()).ok_or_else(bad)?;
+            let url = parts.next().and_then(|u| Url::parse(u).ok()).ok_or_else(bad)?;
+            entries.insert(url, LockEntry { digest, size });
+        }
+        Ok(Self { entries })
+    }
+
+    // A missing lockfile is an empty one
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&tmp, self.to_string())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, url: &Url) -> Option<&LockEntry> {
+        self.entries.get(url)
+    }
+
+    pub fn insert(&mut self, url: Url, entry: LockEntry) -> Option<LockEntry> {
+        self.entries.insert(url, entry)
+    }
+
+    pub fn urls(&self) -> impl Iterator<Item=&Url> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // What changed going from this lockfile to the new one
+    pub fn diff(&self, new: &Lockfile) -> Vec<LockChange> {
+        let mut changes = Vec::new();
+        for (u, e) in self.entries.iter() {
+            match new.entries.get(u) {
+                None => changes.push(LockChange::Removed(u.clone(), *e)),
+                Some(n) if n != e => changes.push(LockChange::Changed(u.clone(), *e, *n)),
+                _ => ()
+            }
+        }
+        for (u, n) in new.entries.iter() {
+            if !self.entries.contains_key(u) {
+                changes.push(LockChange::Added(u.clone(), *n))
+            }
+        }
+        changes
+    }
+}
+
+impl fmt::Display for Lockfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (u, e) in self.entries.iter() {
+            writeln!(f, "{} {}", e, u)?;
+        }
+        Ok(())
+    }
+}
@@ -1,23 +1,33 @@
 use crate::{Error, ErrorKind};
-use crate::compile::{Compile, Env};
-use crate::store::{Storage, ThunkMap, Storable, PartialReader, ObjectType, ObjectReader, CodeReader, 
+use crate::compile::Env;
+use crate::compile::cache::ModuleCache;
+use crate::store::{Storage, ThunkMap, PartialReader, ObjectType, ObjectReader, CodeReader, 
                     RecordReader, TupleReader, Handle, ReaderWhich, Numeric, 
                     StringReader, BufferReader};
-use crate::store::op::{Op, BuiltinOp};
+use crate::store::op::{Op, BuiltinOp, OpAddr};
 use crate::store::value::Value;
 use crate::store::print::Depth;
 
-use super::resource::ResourceProvider;
-use super::scope::{ExecQueue, Registers, ExecItem};
-use super::scope;
+use super::resource::{ResourceProvider, Alloc};
+use super::scope::{ExecQueue, Registers, ExecItem, Frame, FrameID, RootID, Waiter, InFlight};
+use super::trace::{Cache, TraceContext};
+use super::replay::{ReplayLog, Effect, sys_key};
+use super::content::{ContentCache, Sha256, sha256};
+use super::archive;
+use crate::util::hex;
 
 use std::borrow::Borrow;
 use std::ops::Deref;
-use smol::LocalExecutor;
+use smol::{LocalExecutor, Task, Timer};
+use futures_lite::future;
 use pretty::{BoxAllocator, BoxDoc};
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
 use url::Url;
 
 use async_trait::async_trait;
@@ -28,18 +38,157 @@ pub trait SyscallHandler<'s, S : Storage + 's> {
         -> Result<S::Handle<'s>, Error>;
 }
 
+// Limits on the work a machine may do, to bound the
+// evaluation of untrusted code. The op and allocation counts are
+// cumulative over all of the forces done by the machine
+#[derive(Default, Clone, Debug)]
+pub struct Budget {
+    // Maximum number of ops executed
+    pub ops: Option<u64>,
+    // Maximum number of objects the machine inserts into the store
+    pub allocations: Option<u64>,
+    // Maximum total size of the strings and buffers among those
+    // objects. Archives unpack to at most what is left of this
+    pub bytes: Option<u64>,
+    pub deadline: Option<Instant>
+}
+
+// How many queue items a driver executes before
+// yielding to other tasks (i.e interrupt handlers)
+const YIELD_INTERVAL : usize = 256;
+
 pub struct Machine<'s, S: Storage> {
     store: &'s S, 
     thunk_map: Rc<S::ThunkMap<'s>>,
     resources: Rc<dyn ResourceProvider<'s, S> + 's>,
-    syscalls: HashMap<String, Rc<dyn SyscallHandler<'s, S> + 's>>
+    syscalls: HashMap<String, Rc<dyn SyscallHandler<'s, S> + 's>>,
+    // The thunks currently under evaluation
+    frames: RefCell<HashMap<FrameID, Rc<Frame<'s, S>>>>,
+    // thunk --> the frame evaluating it, so that the same thunk
+    // is never under evaluation twice
+    in_progress: RefCell<HashMap<S::Handle<'s>, FrameID>>,
+    // thunks under evaluation by this or any other machine
+    // sharing the table
+    in_flight: Rc<InFlight<'s, S>>,
+    frame_counter: Cell<FrameID>,
+    root_counter: Cell<RootID>,
+    // Ops (across all frames) which are ready to execute
+    queue: ExecQueue<'s, S>,
+    // Where traces are recorded (if at all)
+    traces: Option<Rc<Cache<'s, S>>>,
+    budget: Budget,
+    ops: Cell<u64>,
+    allocations: Cell<u64>,
+    bytes: Cell<u64>,
+    modules: Rc<ModuleCache<'s, S>>,
+    // Fetched content which has been checked against an expected hash
+    content: Rc<ContentCache<'s, S>>,
+    // Where fetches and syscalls are recorded (or replayed from)
+    replay: Option<Rc<ReplayLog<'s, S>>>,
+    // The frame whose builtin task is being polled, if any
+    acting_for: Cell<Option<FrameID>>
+}
+
+// The async builtins (fetch, compile, sys) spawned
+// by a particular force() call. These are all awaited before
+// the force() call returns, since other frames may depend on them.
+// Each task is kept along with the frame it completes (if any)
+struct TaskSet<'t> {
+    ex: LocalExecutor<'t>,
+    tasks: RefCell<Vec<(Option<FrameID>, Task<()>)>>
+}
+
+impl<'t> TaskSet<'t> {
+    fn new() -> Self {
+        Self { ex: LocalExecutor::new(), tasks: RefCell::new(Vec::new()) }
+    }
+
+    fn spawn<F: std::future::Future<Output=()> + 't>(&self, frame: Option<FrameID>, f: F) {
+        let t = self.ex.spawn(f);
+        self.tasks.borrow_mut().push((frame, t));
+    }
+
+    async fn join(&self) {
+        loop {
+            let t = self.tasks.borrow_mut().pop();
+            match t {
+                Some((_, t)) => t.await,
+                None => return
+            }
+        }
+    }
+
+    // The frames waiting on tasks which haven't finished
+    fn pending(&self) -> HashSet<FrameID> {
+        self.tasks.borrow().iter()
+            .filter(|(_, t)| !t.is_finished())
+            .filter_map(|(f, _)| *f).collect()
+    }
+}
+
+// Polls a builtin's task as acting for the frame which spawned it, so
+// that anything the task forces (i.e from a syscall handler) counts as
+// that frame waiting, for the sake of cycle detection
+struct ActingFor<'m, F: Future> {
+    cell: &'m Cell<Option<FrameID>>,
+    frame: FrameID,
+    inner: Pin<Box<F>>
+}
+
+impl<'m, F: Future> Future for ActingFor<'m, F> {
+    type Output = F::Output;
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<F::Output> {
+        let this = self.get_mut();
+        let prev = this.cell.replace(Some(this.frame));
+        let res = this.inner.as_mut().poll(cx);
+        this.cell.set(prev);
+        res
+    }
+}
+
+// Fails the frames started by a force() which is dropped (or runs
+// past its deadline) before they finish, since nothing will drive
+// them anymore and anyone forcing their thunks again would wait forever
+struct Abandon<'m, 't, 's, S: Storage + 's> {
+    mach: &'m Machine<'s, S>,
+    root: RootID,
+    tasks: &'m TaskSet<'t>,
+    done: bool
+}
+
+impl<'m, 't, 's, S: Storage> Drop for Abandon<'m, 't, 's, S> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.mach.abandon(self.root, self.tasks, &Error::new_const(ErrorKind::Interrupted, "Force was abandoned"))
+        }
+    }
+}
+
+enum Wake<'s, S: Storage + 's> {
+    Done(Result<S::Handle<'s>, Error>),
+    Item(ExecItem<'s, S>)
 }
 
 impl<'s, S: Storage> Machine<'s, S> {
     pub fn new(store: &'s S, thunk_map: Rc<S::ThunkMap<'s>>, resources: Rc<dyn ResourceProvider<'s, S> + 's>) -> Self {
         Self { 
             store, thunk_map, resources,
-            syscalls: HashMap::new()
+            syscalls: HashMap::new(),
+            frames: RefCell::new(HashMap::new()),
+            in_progress: RefCell::new(HashMap::new()),
+            in_flight: Rc::new(InFlight::new()),
+            frame_counter: Cell::new(0),
+            root_counter: Cell::new(0),
+            queue: ExecQueue::new(),
+            traces: None,
+            budget: Budget::default(),
+            ops: Cell::new(0),
+            allocations: Cell::new(0),
+            bytes: Cell::new(0),
+            modules: Rc::new(ModuleCache::new(store)),
+            content: Rc::new(ContentCache::new(store)),
+            replay: None,
+            acting_for: Cell::new(None)
         }
     }
 
@@ -47,6 +196,33 @@ impl<'s, S: Storage> Machine<'s, S> {
         self.store
     }
 
+    // Should be shared by all machines which share a thunk map,
+    // and set before anything is forced
+    pub fn set_in_flight(&mut self, in_flight: Rc<InFlight<'s, S>>) {
+        self.in_flight = in_flight;
+    }
+
+    // Records a trace of every thunk this machine evaluates into the cache
+    pub fn set_trace_cache(&mut self, traces: Rc<Cache<'s, S>>) {
+        self.traces = Some(traces);
+    }
+
+    pub fn set_module_cache(&mut self, modules: Rc<ModuleCache<'s, S>>) {
+        self.modules = modules;
+    }
+
+    pub fn set_content_cache(&mut self, content: Rc<ContentCache<'s, S>>) {
+        self.content = content;
+    }
+
+    pub fn set_replay_log(&mut self, replay: Rc<ReplayLog<'s, S>>) {
+        self.replay = Some(replay);
+    }
+
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget;
+    }
+
     pub fn add_syscall<O: Into<String>>(&mut self, sys: O, handler: Rc<dyn SyscallHandler<'s, S> + 's>) {
         self.syscalls.insert(sys.into(), handler);
     }
@@ -64,34 +240,331 @@ impl<'s, S: Storage> Machine<'s, S> {
         Ok(())
     }
 
-    // Does the actual forcing in a loop, and checks the trace cache first
+    // Forces a thunk to WHNF. Nested forces do not recurse,
+    // but are instead pushed as frames onto the machine's
+    // run queue, so that the depth of evaluation is bounded
+    // by the heap and not the native stack. Every call to force()
+    // helps drive the queue until its own result is available.
     pub async fn force(&self, thunk_ref: &S::Handle<'s>)
             -> Result<S::Handle<'s>, Error> {
-        let mut thunk_ref = thunk_ref.clone();
-        loop {
-            // first check the cache for this thunk
-            let _type = thunk_ref.reader()?.get_type();
-            if ObjectType::Thunk != _type {
-                return Ok(thunk_ref)
+        // a builtin acting for a frame queries the thunk
+        // just like one of the frame's own Force ops
+        let query = self.acting_for.get().and_then(|id| {
+            let q = self.frame(id)?.trace.borrow_mut().as_mut()?.force(thunk_ref);
+            Some((id, q))
+        });
+        let res = self.force_root(thunk_ref).await;
+        if let (Some((id, q)), Ok(h)) = (query, &res) {
+            if let Some(f) = self.frame(id) {
+                if let Some(t) = f.trace.borrow_mut().as_mut() {
+                    t.result(q, h)
+                }
             }
-            // check the cache for this particular thunk
-            let next_thunk = if let Some(v) = self.thunk_map.get(&thunk_ref) {
-                v
-            } else {
-                log::trace!(target: "vm", "forcing {}", thunk_ref);
-                let res = self.force_stack(thunk_ref.clone()).await?;
-                self.thunk_map.insert(&thunk_ref, &res);
+        }
+        res
+    }
+
+    async fn force_root(&self, thunk_ref: &S::Handle<'s>)
+            -> Result<S::Handle<'s>, Error> {
+        let ty = thunk_ref.reader()?.get_type();
+        if ty != ObjectType::Thunk && ty != ObjectType::Indirect {
+            return Ok(thunk_ref.clone())
+        }
+        let (sender, recv) = smol::channel::bounded(1);
+        let tasks = TaskSet::new();
+        let root = self.root_counter.get();
+        self.root_counter.set(root + 1);
+        let mut guard = Abandon { mach: self, root, tasks: &tasks, done: false };
+        self.demand(thunk_ref.clone(), Waiter::Root(sender, self.acting_for.get(), root), &tasks);
+        let res = tasks.ex.run(async {
+            let run = async {
+                let res = self.drive(&tasks, &recv).await;
+                tasks.join().await;
                 res
             };
-            thunk_ref = next_thunk;
-            if ObjectType::Thunk != thunk_ref.reader()?.get_type() {
-                return Ok(thunk_ref)
+            match self.budget.deadline {
+                // whatever is still running when the deadline
+                // passes is dropped along with the tasks
+                Some(d) => future::or(run, async {
+                    Timer::at(d).await;
+                    let err = Error::new_const(ErrorKind::BudgetExceeded, "Evaluation deadline exceeded");
+                    self.abandon(root, &tasks, &err);
+                    Err(err)
+                }).await,
+                None => run.await
+            }
+        }).await;
+        guard.done = true;
+        res
+    }
+
+    // Fails the frames of a force which is being abandoned, along
+    // with any frames waiting on its tasks (which are dropped with it).
+    // Frames which another force is also waiting on are handed over to it
+    fn abandon(&self, root: RootID, tasks: &TaskSet<'_>, err: &Error) {
+        let mut dead = tasks.pending();
+        let mut owned : Vec<FrameID> = self.frames.borrow().values()
+            .filter(|f| f.root.get() == root && !dead.contains(&f.id))
+            .map(|f| f.id).collect();
+        // handing over a frame can make its dependencies wanted by another force
+        loop {
+            let before = owned.len();
+            owned.retain(|id| match self.wanted_by(*id, root) {
+                Some(other) => {
+                    self.frame(*id).unwrap().root.set(other);
+                    false
+                },
+                None => true
+            });
+            if owned.len() == before { break }
+        }
+        dead.extend(owned);
+        let mut ids : Vec<FrameID> = dead.into_iter().collect();
+        ids.sort();
+        for id in ids {
+            let frame = match self.frames.borrow_mut().remove(&id) {
+                Some(f) => f,
+                None => continue
+            };
+            log::trace!(target: "vm", "abandoning thunk {}", frame.thunk);
+            self.in_progress.borrow_mut().remove(&frame.thunk);
+            self.in_flight.land(&frame.thunk, Err(err));
+            let waiters = std::mem::take(&mut *frame.waiters.borrow_mut());
+            for w in waiters {
+                self.resume(w, Err(err.clone()))
             }
         }
     }
 
-    // Does a single stack worth of forcing (and returns)
-    async fn force_stack(&self, thunk_ref: S::Handle<'s>) -> Result<S::Handle<'s>, Error> {
+    // Another force (than the given one) waiting on the frame, if any
+    fn wanted_by(&self, id: FrameID, root: RootID) -> Option<RootID> {
+        let frame = self.frame(id)?;
+        let waiters = frame.waiters.borrow();
+        waiters.iter().find_map(|w| {
+            let other = match w {
+                Waiter::Root(_, _, r) => *r,
+                Waiter::Frame(f, _) => self.frame(*f)?.root.get()
+            };
+            if other != root { Some(other) } else { None }
+        })
+    }
+
+    async fn drive<'t>(&'t self, tasks: &TaskSet<'t>, 
+                recv: &smol::channel::Receiver<Result<S::Handle<'s>, Error>>)
+                -> Result<S::Handle<'s>, Error> {
+        let mut steps : usize = 0;
+        loop {
+            if let Ok(res) = recv.try_recv() {
+                return res
+            }
+            let item = match self.queue.try_next() {
+                Some(item) => item,
+                None => {
+                    // Nothing is ready, wait for either a
+                    // background task to complete or our result
+                    let wake = future::or(
+                        async { Wake::Done(recv.recv().await
+                            .unwrap_or(Err(Error::new_const(ErrorKind::Internal, "Force was abandoned")))) },
+                        async { Wake::Item(self.queue.next().await) }
+                    ).await;
+                    match wake {
+                        Wake::Done(res) => return res,
+                        Wake::Item(item) => item
+                    }
+                }
+            };
+            self.exec_item(item, tasks);
+            steps = steps + 1;
+            if steps % YIELD_INTERVAL == 0 {
+                future::yield_now().await;
+            }
+        }
+    }
+
+    fn exec_item<'t>(&'t self, item: ExecItem<'s, S>, tasks: &TaskSet<'t>) {
+        match item {
+            ExecItem::Op(id, addr) => {
+                // The frame may have already errored out
+                let frame = match self.frame(id) {
+                    Some(f) => f,
+                    None => return
+                };
+                let res = self.charge_op().and_then(|_| self.exec_op(&frame, addr, tasks))
+                    .and_then(|_| self.charge_alloc(frame.regs.take_indirects(), 0));
+                if let Err(e) = res {
+                    self.queue.notify_error(id, e)
+                }
+            },
+            ExecItem::Ret(id, h) => self.finish(id, Ok(h), tasks),
+            ExecItem::Err(id, e) => self.finish(id, Err(e), tasks),
+            ExecItem::Resume(w, Ok(h)) => self.demand(h, w, tasks),
+            ExecItem::Resume(w, Err(e)) => self.resume(w, Err(e))
+        }
+    }
+
+    fn charge_op(&self) -> Result<(), Error> {
+        let ops = self.ops.get() + 1;
+        self.ops.set(ops);
+        match self.budget.ops {
+            Some(max) if ops > max => 
+                Err(Error::new_const(ErrorKind::BudgetExceeded, "Evaluation op limit exceeded")),
+            _ => Ok(())
+        }
+    }
+
+    // Inserts into the store, counting against the allocation budget
+    fn alloc<'p, R>(&self, src: R) -> Result<S::Handle<'s>, Error>
+            where R: ObjectReader<'p, 's, Handle=S::Handle<'s>> {
+        let size = match src.which() {
+            ReaderWhich::String(s) => s.len(),
+            ReaderWhich::Buffer(b) => b.len(),
+            _ => 0
+        };
+        self.charge_alloc(1, size as u64)?;
+        self.store.insert_from(src)
+    }
+
+    // Counts objects (and the bytes of their contents) inserted
+    // into the store against the budget
+    fn charge_alloc(&self, objects: u64, size: u64) -> Result<(), Error> {
+        let allocations = self.allocations.get() + objects;
+        self.allocations.set(allocations);
+        let bytes = self.bytes.get() + size;
+        self.bytes.set(bytes);
+        match (self.budget.allocations, self.budget.bytes) {
+            (Some(max), _) if allocations > max => 
+                Err(Error::new_const(ErrorKind::BudgetExceeded, "Evaluation allocation limit exceeded")),
+            (_, Some(max)) if bytes > max => 
+                Err(Error::new_const(ErrorKind::BudgetExceeded, "Evaluation byte limit exceeded")),
+            _ => Ok(())
+        }
+    }
+
+    // How large an archive may unpack to
+    fn unpack_limit(&self) -> usize {
+        match self.budget.bytes {
+            Some(max) => (max.saturating_sub(self.bytes.get()) as usize).min(archive::MAX_UNPACKED),
+            None => archive::MAX_UNPACKED
+        }
+    }
+
+    fn acting_for<F: Future>(&self, frame: FrameID, f: F) -> ActingFor<'_, F> {
+        ActingFor { cell: &self.acting_for, frame, inner: Box::pin(f) }
+    }
+
+    fn frame(&self, id: FrameID) -> Option<Rc<Frame<'s, S>>> {
+        self.frames.borrow().get(&id).cloned()
+    }
+
+    // Arrange for the waiter to get the WHNF of the given handle,
+    // checking the thunk map first. If the thunk is already
+    // under evaluation the waiter joins the existing frame
+    // (unless doing so would mean the thunk is waiting on itself).
+    // If another machine is evaluating the thunk, we wait on its result
+    fn demand<'t>(&'t self, thunk_ref: S::Handle<'s>, waiter: Waiter<'s, S>, tasks: &TaskSet<'t>) {
+        let res : Result<S::Handle<'s>, Error> = try {
+            let mut thunk_ref = thunk_ref;
+            loop {
+                // follow indirects (i.e from rec bindings)
+                let next = match thunk_ref.reader()?.which() {
+                    ReaderWhich::Indirect(h) => Some(h.borrow().clone()),
+                    ReaderWhich::Thunk(_) => None,
+                    _ => break thunk_ref.clone()
+                };
+                if let Some(h) = next {
+                    thunk_ref = h;
+                    continue
+                }
+                if let Some(v) = self.thunk_map.get(&thunk_ref) {
+                    if self.thunk_map.is_volatile(&thunk_ref) {
+                        self.taint(&waiter)
+                    }
+                    thunk_ref = v;
+                    continue
+                }
+                let running = self.in_progress.borrow().get(&thunk_ref).cloned();
+                let frame = match running {
+                    Some(id) => {
+                        if let Some(from) = waiter.frame() {
+                            if self.waits_on(from, id) {
+                                Err(Error::new_kind(ErrorKind::InfiniteLoop,
+                                    format!("infinite loop detected: thunk {} depends on itself", thunk_ref)))?
+                            }
+                        }
+                        self.frame(id).unwrap()
+                    },
+                    None => {
+                        if let Some(mut recv) = self.in_flight.wait(&thunk_ref) {
+                            tasks.spawn(waiter.frame(), async move {
+                                let res = InFlight::<S>::landed(&mut recv).await;
+                                if self.thunk_map.is_volatile(&thunk_ref) {
+                                    self.taint(&waiter)
+                                }
+                                self.queue.notify_resume(waiter, res)
+                            });
+                            return
+                        }
+                        self.start(thunk_ref, self.root_of(&waiter))?
+                    }
+                };
+                frame.waiters.borrow_mut().push(waiter);
+                return
+            }
+        };
+        self.resume(waiter, res)
+    }
+
+    // Whether the frame target is (transitively) waiting
+    // on the result of frame from
+    fn waits_on(&self, from: FrameID, target: FrameID) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(id) = stack.pop() {
+            if id == target { return true }
+            if !seen.insert(id) { continue }
+            if let Some(f) = self.frame(id) {
+                stack.extend(f.waiters.borrow().iter().filter_map(|w| w.frame()));
+            }
+        }
+        false
+    }
+
+    // The force a frame started for the waiter belongs to
+    fn root_of(&self, waiter: &Waiter<'s, S>) -> RootID {
+        match waiter {
+            Waiter::Root(_, _, root) => *root,
+            Waiter::Frame(id, _) => self.frame(*id).map(|f| f.root.get()).unwrap_or_default()
+        }
+    }
+
+    // Marks the waiting frame as depending on a volatile result
+    fn taint(&self, waiter: &Waiter<'s, S>) {
+        if let Some(f) = waiter.frame().and_then(|id| self.frame(id)) {
+            f.volatile.set(true)
+        }
+    }
+
+    fn resume(&self, waiter: Waiter<'s, S>, res: Result<S::Handle<'s>, Error>) {
+        match waiter {
+            Waiter::Root(s, _, _) => { s.try_send(res).ok(); },
+            Waiter::Frame(id, dest) => {
+                if let Some(f) = self.frame(id) {
+                    f.complete(&self.queue, &dest, res)
+                }
+            }
+        }
+    }
+
+    // Complete a register of a frame (if the frame is still live)
+    fn complete(&self, id: FrameID, dest: &crate::store::op::Dest, res: Result<S::Handle<'s>, Error>) {
+        if let Some(f) = self.frame(id) {
+            f.complete(&self.queue, dest, res)
+        }
+    }
+
+    // Sets up a frame for a thunk and releases its ready ops
+    fn start(&self, thunk_ref: S::Handle<'s>, root: RootID) -> Result<Rc<Frame<'s, S>>, Error> {
+        log::trace!(target: "vm", "forcing {}", thunk_ref);
         // get the entry ref 
         let entry_ref = thunk_ref.reader()?.as_thunk()?;
         let (code_ref, inputs) = match entry_ref.borrow().reader()?.which() {
@@ -102,70 +575,106 @@ impl<'s, S: Storage> Machine<'s, S> {
             _ => return Err(Error::new_const(ErrorKind::Internal, "Force target is not code or a partial"))
         };
         let code_reader = code_ref.reader()?.as_code()?;
-        let queue = ExecQueue::new();
         let regs = Registers::new(self.store, code_reader.get_ret());
 
-        {
+        if log::log_enabled!(target: "vm", log::Level::Trace) {
             let code_doc: BoxDoc<'_, ()> = code_reader.pretty(Depth::Fixed(2), &BoxAllocator).into_doc();
             log::trace!(target: "vm", "thunk {} executing:\n{}", thunk_ref, code_doc.pretty(80));
         }
 
-        for op_addr in code_reader.iter_ready() {
-            queue.push(op_addr);
+        let id = self.frame_counter.get();
+        self.frame_counter.set(id + 1);
+        let frame = Rc::new(Frame::new(id, root, thunk_ref.clone(), code_ref.clone(), inputs, regs));
+        if self.traces.is_some() {
+            let mut t = TraceContext::new(thunk_ref.clone());
+            for (i, h) in frame.inputs.iter().enumerate() {
+                t.input(h, i)
+            }
+            *frame.trace.borrow_mut() = Some(t);
         }
+        self.frames.borrow_mut().insert(id, frame.clone());
+        self.in_progress.borrow_mut().insert(frame.thunk.clone(), id);
+        self.in_flight.begin(&frame.thunk);
 
-        log::trace!(target: "vm", "populated thunk {}", thunk_ref);
+        for op_addr in code_reader.iter_ready() {
+            self.queue.push(id, op_addr);
+        }
+        log::trace!(target: "vm", "populated thunk {}", frame.thunk);
+        Ok(frame)
+    }
 
-        // We need to drop the local executor before the queue, regs
-        let thunk_ex = LocalExecutor::new();
-        let res : Result<S::Handle<'s>, Error> = thunk_ex.run(async {
-            loop {
-                match queue.next_op().await {
-                ExecItem::Op(addr) => {
-                    let op = code_reader.get_op(addr);
-                    log::trace!(target: "vm", "executing #{} for thunk {} (code {}): {}", addr, thunk_ref, code_ref, op);
-                    self.exec_op(op, &code_reader, &thunk_ex, &regs, &queue, &inputs)?;
-                },
-                ExecItem::Ret(h) => return Ok(h),
-                ExecItem::Err(e) => return Err(e)
+    // Called when a frame returns. The result is recorded in the thunk map
+    // and passed on to the waiter (if the result is itself a thunk,
+    // the waiter is moved onto that thunk instead)
+    fn finish<'t>(&'t self, id: FrameID, res: Result<S::Handle<'s>, Error>, tasks: &TaskSet<'t>) {
+        let frame = match self.frames.borrow_mut().remove(&id) {
+            Some(f) => f,
+            None => return
+        };
+        self.in_progress.borrow_mut().remove(&frame.thunk);
+        self.in_flight.land(&frame.thunk, res.as_ref());
+        let waiters = std::mem::take(&mut *frame.waiters.borrow_mut());
+        if let (Some(cache), Some(t)) = (&self.traces, frame.trace.borrow_mut().take()) {
+            cache.insert(match &res {
+                Ok(h) => t.ret(h),
+                Err(e) => t.error(e)
+            })
+        }
+        match res {
+            Ok(h) => {
+                log::trace!(target: "vm", "done with thunk {}", frame.thunk);
+                if frame.volatile.get() {
+                    self.thunk_map.insert_volatile(&frame.thunk, &h);
+                } else {
+                    self.thunk_map.insert(&frame.thunk, &h);
+                }
+                for w in waiters {
+                    if frame.volatile.get() {
+                        self.taint(&w)
+                    }
+                    self.demand(h.clone(), w, tasks)
+                }
+            },
+            Err(e) => {
+                log::trace!(target: "vm", "thunk {} return error {:?}", frame.thunk, e);
+                for w in waiters {
+                    self.resume(w, Err(e.clone()))
                 }
             }
-        }).await;
-        match &res {
-            Err(e) => log::trace!(target: "vm", "thunk {} return error {:?}", thunk_ref, e),
-            _ => ()
         }
-        log::trace!(target: "vm", "done with thunk {}", thunk_ref);
-        res
     }
 
-    fn exec_op<'t, 'r, R: CodeReader<'r, 's, Handle=S::Handle<'s>>>(&'t self, op : Op, code: &'t R, thunk_ex: &LocalExecutor<'t>,
-                    regs: &'t Registers<'s, S>, queue: &'t ExecQueue<'s, S>, inputs: &Vec<S::Handle<'s>>) -> Result<(), Error> {
+    fn exec_op<'t>(&'t self, frame: &Frame<'s, S>, addr: OpAddr, tasks: &TaskSet<'t>) -> Result<(), Error> {
+        let code = frame.code.reader()?.as_code()?;
+        let op = code.get_op(addr);
+        log::trace!(target: "vm", "executing #{} for thunk {} (code {}): {}", addr, frame.thunk, frame.code, op);
+        let (regs, queue) = (&frame.regs, &self.queue);
+        let id = frame.id;
         use Op::*;
         match op {
             Force(dest, arg) => {
                 let entry = regs.consume(arg)?;
-                if entry.reader()?.get_type() == ObjectType::Thunk {
-                    // spawn the force as a background task
-                    // since we might want to move onto other things
-                    thunk_ex.spawn(async move {
-                        let res = self.force(&entry).await;
-                        // we need to get 
-                        scope::complete(code, regs, queue, &dest, res)
-                    }).detach();
+                if let Some(t) = frame.trace.borrow_mut().as_mut() {
+                    t.query(&entry, dest.reg)
+                }
+                let ty = entry.reader()?.get_type();
+                if ty == ObjectType::Thunk || ty == ObjectType::Indirect {
+                    // push the thunk as a new frame, which
+                    // will complete dest when it returns
+                    self.demand(entry, Waiter::Frame(id, dest), tasks)
                 } else {
                     // We are already WHNF
-                    scope::complete(code, regs, queue, &dest, Ok(entry))
+                    frame.complete(queue, &dest, Ok(entry))
                 }
             },
             SetValue(dest, value) => {
                 let h = code.get_value(value).unwrap();
-                scope::complete(code, regs, queue, &dest, Ok(h.borrow().clone()))
+                frame.complete(queue, &dest, Ok(h.borrow().clone()))
             },
             SetInput(dest, input) => {
-                let val = inputs.get(input as usize).cloned()
+                let val = frame.inputs.get(input as usize).cloned()
                         .ok_or(Error::new_const(ErrorKind::Internal, "Input out of bounds"));
-                scope::complete(code, regs, queue, &dest, val)
+                frame.complete(queue, &dest, val)
             },
             Bind(dest, lam, bind_args) => {
                 let lam = regs.consume(lam)?;
@@ -183,16 +692,15 @@ impl<'s, S: Storage> Machine<'s, S> {
                     bind_args.iter().map(|x| regs.consume(*x)).collect();
                 args.extend(new_args?);
                 // construct a new partial with the modified arguments
-                let res = self.store.insert_from(&Value::Partial(code_entry, args))?;
-                scope::complete(code, regs, queue, &dest, Ok(res))
+                let res = self.alloc(&Value::Partial(code_entry, args))?;
+                frame.complete(queue, &dest, Ok(res))
             },
             Invoke(dest, target) => {
                 let target_entry = regs.consume(target)?;
-                let entry = self.store.insert_from(&Value::Thunk(target_entry))?;
-                scope::complete(code, regs, queue, &dest, Ok(entry))
+                let entry = self.alloc(&Value::Thunk(target_entry))?;
+                frame.complete(queue, &dest, Ok(entry))
             },
             Builtin(dest, op, args) => {
-                // builtin::exec_builtin(self, r, code, thunk_ex, regs, queue)?;
                 let args : Result<Vec<S::Handle<'s>>, Error> = args.iter().map(|&x| regs.consume(x)).collect();
                 let mut args = args?;
                 use BuiltinOp::*;
@@ -214,8 +722,8 @@ impl<'s, S: Storage> Machine<'s, S> {
                         if !args.is_empty() { panic!("Expected one argument") }
                         self.numeric_unop(arg, Numeric::neg)
                     },
-                    EmptyRecord => self.store.insert_from(&Value::Record(Vec::new())),
-                    EmptyTuple => self.store.insert_from(&Value::Tuple(Vec::new())),
+                    EmptyRecord => self.alloc(&Value::Record(Vec::new())),
+                    EmptyTuple => self.alloc(&Value::Tuple(Vec::new())),
                     Append => {
                         let item = args.pop().unwrap();
                         let object = args.pop().unwrap();
@@ -232,11 +740,11 @@ impl<'s, S: Storage> Machine<'s, S> {
                         let object = args.pop().unwrap();
                         self.project(object, key)
                     },
-                    Nil => self.store.insert_from(&Value::Nil),
+                    Nil => self.alloc(&Value::Nil),
                     Cons => {
                         let tail = args.pop().unwrap();
                         let head = args.pop().unwrap();
-                        self.store.insert_from(&Value::Cons(head, tail))
+                        self.alloc(&Value::Cons(head, tail))
                     },
                     JoinUrl => {
                         let ext = args.pop().unwrap();
@@ -250,7 +758,7 @@ impl<'s, S: Storage> Machine<'s, S> {
                             .map_err(|_| Error::new("Malformed url"))?;
                         let joined_url = base_url.join(ext_str.deref())
                             .map_err(|_| Error::new("Malformed url"))?;
-                        self.store.insert_from(&Value::String(joined_url.to_string()))
+                        self.alloc(&Value::String(joined_url.to_string()))
                     },
                     DecodeUtf8 => {
                         let bytes = args.pop().unwrap();
@@ -258,45 +766,85 @@ impl<'s, S: Storage> Machine<'s, S> {
                         let buff = buff.as_slice();
                         let str = std::str::from_utf8(buff.deref())
                                 .map_err(|_| Error::new("Invalid utf8"))?;
-                        self.store.insert_from(&Value::String(String::from(str)))
+                        self.alloc(&Value::String(String::from(str)))
                     },
                     EncodeUtf8 => {
                         let str = args.pop().unwrap();
                         let str : _ = str.reader()?.as_string()?;
                         let str = str.as_slice();
-                        self.store.insert_from(&Value::Buffer(Bytes::copy_from_slice(str.deref().as_bytes())))
+                        self.alloc(&Value::Buffer(Bytes::copy_from_slice(str.deref().as_bytes())))
 
                     },
-                    // Fetch, Compile, and Sys are the only async builtins!
+                    Untar | Unzip | Gunzip => {
+                        let archive = args.pop().unwrap();
+                        let archive = archive.reader()?.as_buffer()?.to_bytes();
+                        let limit = self.unpack_limit();
+                        match op {
+                            Untar => archive::untar(&archive, limit)?.store_in(self),
+                            Unzip => archive::unzip(&archive, limit)?.store_in(self),
+                            _ => self.alloc(&Value::Buffer(archive::gunzip(&archive, limit)?.into()))
+                        }
+                    },
+                    // Fetch, Compile, Sys and Tar are the only async builtins!
+                    // These run as background tasks which complete
+                    // the destination register (by frame id) when done
+                    // $fetch(url) or $fetch(url, {sha256: "..."})
                     Fetch => {
+                        let opts = if args.len() > 1 { args.pop() } else { None };
                         let url = args.pop().unwrap();
-                        thunk_ex.spawn(async move {
+                        tasks.spawn(Some(id), self.acting_for(id, async move {
                             let res : Result<S::Handle<'s>, Error> = try {
                                 let url_str : _ = url.reader()?.as_string()?;
                                 let url_str : _ = url_str.as_slice();
                                 let url = Url::parse(url_str.deref())
                                     .map_err(|_| Error::new("Bad url"))?;
-                                self.fetch(&url).await?
+                                if let Some(f) = self.frame(id) {
+                                    if let Some(t) = f.trace.borrow_mut().as_mut() {
+                                        t.use_resource(&url)
+                                    }
+                                }
+                                match self.expected_sha256(opts).await? {
+                                    Some(digest) => self.fetch_verified(&url, &digest).await?,
+                                    None => {
+                                        // unless pinned by a digest, the content can change
+                                        if let Some(f) = self.frame(id) {
+                                            f.volatile.set(true)
+                                        }
+                                        self.fetch(&url).await?
+                                    }
+                                }
                             };
-                            scope::complete(code, regs, queue, &dest, res)
-                        }).detach();
+                            self.complete(id, &dest, res)
+                        }));
                         return Ok(());
                     },
                     Compile => {
                         let text = args.pop().unwrap();
                         let loc = args.pop().unwrap();
-                        thunk_ex.spawn(async move {
+                        tasks.spawn(Some(id), self.acting_for(id, async move {
                             let res : Result<S::Handle<'s>, Error> = try {
                                 let text_str : _ = text.reader()?.as_string()?;
                                 let text_str : _ = text_str.as_slice();
                                 self.compile_module(loc, text_str.deref()).await?
                             };
-                            scope::complete(code, regs, queue, &dest, res)
-                        }).detach();
+                            self.complete(id, &dest, res)
+                        }));
+                        return Ok(());
+                    },
+                    // $tar(dir) forces the whole directory
+                    Tar => {
+                        let dir = args.pop().unwrap();
+                        tasks.spawn(Some(id), self.acting_for(id, async move {
+                            let res = try {
+                                let tar = archive::pack_tar(self, dir).await?;
+                                self.alloc(&Value::Buffer(tar.into()))?
+                            };
+                            self.complete(id, &dest, res)
+                        }));
                         return Ok(());
                     },
                     Sys => {
-                        thunk_ex.spawn(async move {
+                        tasks.spawn(Some(id), self.acting_for(id, async move {
                             let res = try {
                                 let sys_args = args.split_off(1);
                                 let sys_name = args.pop().unwrap();
@@ -304,12 +852,12 @@ impl<'s, S: Storage> Machine<'s, S> {
                                 let sys_str : _ = sys_str.as_slice();
                                 self.sys(sys_str.deref(), sys_args).await?
                             };
-                            scope::complete(code, regs, queue, &dest, res)
-                        }).detach();
+                            self.complete(id, &dest, res)
+                        }));
                         return Ok(());
                     }
                 };
-                scope::complete(code, regs, queue, &dest, res)
+                frame.complete(queue, &dest, res)
             },
             Match(_dest, _scrut, _cases) => {
                 todo!()
@@ -326,12 +874,12 @@ impl<'s, S: Storage> Machine<'s, S> {
 
     pub fn numeric_binop<F: Fn(Numeric, Numeric) -> Numeric>(&self, lhs: S::Handle<'s>, rhs: S::Handle<'s>, func : F) -> Result<S::Handle<'s>, Error> {
         let (l, r) = (lhs.reader()?.as_numeric()?, rhs.reader()?.as_numeric()?);
-        self.store.insert_from(&Value::from_numeric(func(l, r)))
+        self.alloc(&Value::from_numeric(func(l, r)))
     }
 
     pub fn numeric_unop<F: Fn(Numeric) -> Numeric>(&self, arg: S::Handle<'s>, func : F) -> Result<S::Handle<'s>, Error> {
         let arg = arg.reader()?.as_numeric()?;
-        self.store.insert_from(&Value::from_numeric(func(arg)))
+        self.alloc(&Value::from_numeric(func(arg)))
     }
 
     // Assumes the object is forced!
@@ -352,7 +900,7 @@ impl<'s, S: Storage> Machine<'s, S> {
                     }
                 }
                 entries.push((key, val));
-                self.store.insert_from(&Value::Record(entries))
+                self.alloc(&Value::Record(entries))
             },
             _ => Err(Error::new("Expected record"))
         }
@@ -378,7 +926,7 @@ impl<'s, S: Storage> Machine<'s, S> {
             Tuple(t) => {
                 let mut items : Vec<_> =  t.iter().map(|x| x.borrow().clone()).collect();
                 items.push(item);
-                self.store.insert_from(&Value::Tuple(items))
+                self.alloc(&Value::Tuple(items))
             },
             _ => Err(Error::new_const(ErrorKind::BadType, "Bad type, not a tuple"))
         }
@@ -394,31 +942,90 @@ impl<'s, S: Storage> Machine<'s, S> {
         let mut env = Env::new();
         env.insert(String::from("__path__"), loc);
         // Load the prelude
-        {
-            let lexer = crate::parse::Lexer::new(prelude_src);
-            let parser = crate::grammar::ModuleParser::new();
-            let module : crate::parse::ast::Module = parser.parse(lexer).unwrap();
-            let expr = module.transpile();
-            let prelude_compiled = expr.compile(self.store, &env)?.store_in(self.store)?;
-            let prelude_module = self.store.insert_from(&Value::Thunk(prelude_compiled))?;
-            self.env_use(prelude_module, &mut env).await?;
-        }
+        let prelude = self.modules.compile(prelude_src)?;
+        let prelude_module = self.modules.instantiate(&prelude, &env)?;
+        self.env_use(prelude_module, &mut env).await?;
 
-        let lexer = crate::parse::Lexer::new(source);
-        let parser = crate::grammar::ModuleParser::new();
-        let module : crate::parse::ast::Module = parser.parse(lexer).map_err(|e| Error::new(e.to_string()))?;
-        let expr = module.transpile();
-        let code = expr.compile(self.store, &env)?.store_in(self.store)?;
-        self.store.insert_from(&Value::Thunk(code))
+        let module = self.modules.compile(source)?;
+        self.modules.instantiate(&module, &env)
     }
 
     // Do a syscall
     pub async fn sys(&self, sys: &str, args: Vec<S::Handle<'s>>) -> Result<S::Handle<'s>, Error> {
-        let handler = self.syscalls.get(sys).ok_or(Error::new_const(ErrorKind::NotFound, "Syscall not found"))?;
-        handler.call(sys, self, args).await
+        let run = async {
+            let handler = self.syscalls.get(sys).ok_or(Error::new_const(ErrorKind::NotFound, "Syscall not found"))?;
+            handler.call(sys, self, args.clone()).await
+        };
+        match &self.replay {
+            Some(r) => r.effect(Effect::Sys, sys_key::<S>(sys, &args)?, run).await,
+            None => run.await
+        }
+    }
+
+    // The digest given by the sha256 field of fetch options, if any
+    async fn expected_sha256(&self, opts: Option<S::Handle<'s>>) -> Result<Option<Sha256>, Error> {
+        let opts = match opts {
+            Some(opts) => self.force(&opts).await?,
+            None => return Ok(None)
+        };
+        let hash = match opts.reader()?.which() {
+            ReaderWhich::Record(r) => match r.get("sha256") {
+                Ok(h) => h.borrow().clone(),
+                Err(_) => return Ok(None)
+            },
+            // {} is an empty scope rather than an empty record
+            ReaderWhich::Unit => return Ok(None),
+            _ => return Err(Error::new_const(ErrorKind::BadType, "Fetch options must be a record"))
+        };
+        let hash = self.force(&hash).await?;
+        let hash = hash.reader()?.as_string()?;
+        let hash = hash.as_slice();
+        hex::decode(hash.deref()).map(Some)
+            .ok_or(Error::new_const(ErrorKind::BadFormat, "Expected a hex sha256 digest"))
+    }
+
+    // Fetches content which must have the given SHA-256. Content
+    // which has been verified before is not fetched again
+    pub async fn fetch_verified(&self, url: &Url, digest: &Sha256) -> Result<S::Handle<'s>, Error> {
+        if let Some(h) = self.content.get(digest) {
+            return Ok(h)
+        }
+        let h = self.fetch(url).await?;
+        let bytes = match h.reader()?.which() {
+            ReaderWhich::Buffer(b) => b.to_bytes(),
+            ReaderWhich::String(s) => Bytes::copy_from_slice(s.as_slice().deref().as_bytes()),
+            _ => return Err(Error::new_const(ErrorKind::BadType, "Fetched content is not a buffer"))
+        };
+        let actual = sha256(&bytes);
+        if &actual != digest {
+            return Err(Error::new_kind(ErrorKind::Integrity, format!(
+                "{} has sha256 {} (expected {})", url, hex::encode(&actual), hex::encode(digest))))
+        }
+        self.content.put(digest, bytes)
     }
 
     pub async fn fetch(&self, url: &Url) -> Result<S::Handle<'s>, Error> {
-        self.resources.retrieve(url).await
+        match &self.replay {
+            Some(r) => r.effect(Effect::Fetch, url.to_string(), self.resources.retrieve(url)).await,
+            None => self.resources.retrieve(url).await
+        }
+    }
+}
+
+impl<'m, 's, S: Storage + 's> Alloc<'s> for &'m Machine<'s, S> {
+    type Store = S;
+    fn alloc(&self, value: &Value<'s, S::Handle<'s>>) -> Result<S::Handle<'s>, Error> {
+        Machine::alloc(self, value)
+    }
+}
+
+impl<'s, S: Storage> Drop for Machine<'s, S> {
+    // Anything still under evaluation (i.e the force was interrupted)
+    // will never finish, so let other machines waiting on it know
+    fn drop(&mut self) {
+        let err = Error::new_const(ErrorKind::Interrupted, "Thunk evaluation was abandoned");
+        for f in self.frames.borrow().values() {
+            self.in_flight.land(&f.thunk, Err(&err))
+        }
     }
 }
\ No newline at end of file
@@ -1,7 +1,16 @@
 pub mod machine;
 pub mod trace;
 pub mod resource;
+pub mod http;
+pub mod git;
+pub mod archive;
 pub mod scope;
+pub mod replay;
+pub mod lockfile;
+pub mod content;
+
+#[cfg(test)]
+mod test;
 
 pub use machine::Machine;
 pub use resource::{Resources, ResourceProvider};
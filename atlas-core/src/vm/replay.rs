@@ -0,0 +1,261 @@
+// Recording and replaying the external effects of an evaluation.
+// Fetches, syscalls and the REPL's __path__ are the only sources of
+// non-determinism, so an evaluation re-run against a log of their
+// outcomes does exactly what the recorded run did, without needing the
+// network or the sandbox. A log file is:
+//   "ATRP", u32 version, u32 entry count,
+//   the entries, each a u8 kind (0 = fetch, 1 = sys, 2 = path),
+//   a u32 length and key, then either 1 and a u32 length and the
+//   exported result, or 0, a u8 error kind, a u32 length and the message
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::path::Path;
+
+use crate::{Error, ErrorKind};
+use crate::store::Storage;
+use crate::store::hash::sha3;
+use crate::store::serialize::{export, import};
+use crate::util::hex;
+
+pub const MAGIC: &[u8; 4] = b"ATRP";
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Effect {
+    Fetch,
+    Sys,
+    Path
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Record,
+    Replay
+}
+
+// The number of an ErrorKind in the log. New kinds get new numbers,
+// and the match is exhaustive so that a kind can't be left out
+fn kind_code(kind: ErrorKind) -> u8 {
+    use ErrorKind::*;
+    match kind {
+        BadFormat => 0, BadType => 1, BadPointer => 2, IO => 3,
+        Interrupted => 4, NotFound => 5, Filesystem => 6, Compile => 7,
+        Internal => 8, IncorrectType => 9, InfiniteLoop => 10,
+        BudgetExceeded => 11, Custom => 12, Integrity => 13,
+        UnsupportedScheme => 14
+    }
+}
+
+fn kind_from_code(code: u8) -> Option<ErrorKind> {
+    use ErrorKind::*;
+    Some(match code {
+        0 => BadFormat, 1 => BadType, 2 => BadPointer, 3 => IO,
+        4 => Interrupted, 5 => NotFound, 6 => Filesystem, 7 => Compile,
+        8 => Internal, 9 => IncorrectType, 10 => InfiniteLoop,
+        11 => BudgetExceeded, 12 => Custom, 13 => Integrity,
+        14 => UnsupportedScheme,
+        _ => return None
+    })
+}
+
+type Outcome = Result<Vec<u8>, (ErrorKind, String)>;
+
+struct Entry {
+    effect: Effect,
+    key: String,
+    outcome: Outcome
+}
+
+pub struct ReplayLog<'s, S: Storage + 's> {
+    store: &'s S,
+    mode: Mode,
+    // Every effect, in the order it completed
+    entries: RefCell<Vec<Entry>>,
+    // When replaying, (effect, key) --> the indices of the outcomes
+    // not yet replayed, so an effect repeated with the same
+    // key gets its results in the recorded order
+    pending: RefCell<HashMap<(Effect, String), VecDeque<usize>>>
+}
+
+fn bad_format(msg: &'static str) -> Error {
+    Error::new_const(ErrorKind::BadFormat, msg)
+}
+
+fn take<'b>(bytes: &mut &'b [u8], n: usize) -> Result<&'b [u8], Error> {
+    if bytes.len() < n {
+        return Err(bad_format("Truncated replay log"))
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+fn take_str(bytes: &mut &[u8]) -> Result<String, Error> {
+    let len = take_u32(bytes)? as usize;
+    String::from_utf8(take(bytes, len)?.to_vec())
+        .map_err(|_| bad_format("Bad string in replay log"))
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// The key for a syscall: its name and a digest of its arguments
+pub fn sys_key<'s, S: Storage + 's>(sys: &str, args: &[S::Handle<'s>]) -> Result<String, Error> {
+    let mut bytes = Vec::new();
+    for a in args {
+        put_bytes(&mut bytes, &export(a)?);
+    }
+    let digest = sha3(&bytes);
+    Ok(format!("{}:{}", sys, hex::encode(&digest[..16])))
+}
+
+impl<'s, S: Storage + 's> ReplayLog<'s, S> {
+    // An empty log which records the effects it is given
+    pub fn record(store: &'s S) -> Self {
+        Self { store, mode: Mode::Record, entries: RefCell::new(Vec::new()),
+               pending: RefCell::new(HashMap::new()) }
+    }
+
+    // A log which replays the effects of a recording
+    pub fn replay(store: &'s S, mut bytes: &[u8]) -> Result<Self, Error> {
+        if take(&mut bytes, 4)? != MAGIC {
+            return Err(bad_format("Not a replay log"))
+        }
+        let version = take_u32(&mut bytes)?;
+        if version != VERSION {
+            return Err(Error::new_kind(ErrorKind::BadFormat,
+                format!("Unsupported replay log version {} (expected {})", version, VERSION)))
+        }
+        let count = take_u32(&mut bytes)?;
+        let mut entries = Vec::new();
+        let mut pending : HashMap<_, VecDeque<_>> = HashMap::new();
+        for i in 0..count as usize {
+            let effect = match take(&mut bytes, 1)?[0] {
+                0 => Effect::Fetch, 1 => Effect::Sys, 2 => Effect::Path,
+                _ => return Err(bad_format("Bad effect in replay log"))
+            };
+            let key = take_str(&mut bytes)?;
+            let outcome = match take(&mut bytes, 1)?[0] {
+                1 => {
+                    let len = take_u32(&mut bytes)? as usize;
+                    Ok(take(&mut bytes, len)?.to_vec())
+                },
+                0 => {
+                    let kind = kind_from_code(take(&mut bytes, 1)?[0])
+                        .ok_or_else(|| bad_format("Bad error kind in replay log"))?;
+                    Err((kind, take_str(&mut bytes)?))
+                },
+                _ => return Err(bad_format("Bad outcome in replay log"))
+            };
+            pending.entry((effect, key.clone())).or_default().push_back(i);
+            entries.push(Entry { effect, key, outcome });
+        }
+        if !bytes.is_empty() {
+            return Err(bad_format("Trailing bytes in replay log"))
+        }
+        Ok(Self { store, mode: Mode::Replay, entries: RefCell::new(entries),
+                  pending: RefCell::new(pending) })
+    }
+
+    pub fn load<P: AsRef<Path>>(store: &'s S, path: P) -> Result<Self, Error> {
+        Self::replay(store, &std::fs::read(path)?)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let entries = self.entries.borrow();
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        for e in entries.iter() {
+            buf.push(match e.effect { Effect::Fetch => 0, Effect::Sys => 1, Effect::Path => 2 });
+            put_bytes(&mut buf, e.key.as_bytes());
+            match &e.outcome {
+                Ok(bytes) => {
+                    buf.push(1);
+                    put_bytes(&mut buf, bytes);
+                },
+                Err((kind, msg)) => {
+                    buf.push(0);
+                    buf.push(kind_code(*kind));
+                    put_bytes(&mut buf, msg.as_bytes());
+                }
+            }
+        }
+        buf
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    // When recording, runs the effect and logs its result.
+    // When replaying, the effect is dropped without being run
+    // and the next recorded result for the same key is returned
+    pub async fn effect<F>(&self, effect: Effect, key: String, run: F) -> Result<S::Handle<'s>, Error>
+            where F: Future<Output=Result<S::Handle<'s>, Error>> {
+        match self.mode {
+            Mode::Record => {
+                let res = run.await;
+                let outcome = match &res {
+                    Ok(h) => Ok(export(h)?),
+                    Err(e) => Err((e.kind(), e.to_string()))
+                };
+                self.entries.borrow_mut().push(Entry { effect, key, outcome });
+                res
+            },
+            Mode::Replay => {
+                let next = self.pending.borrow_mut().get_mut(&(effect, key.clone()))
+                                .and_then(|q| q.pop_front());
+                let i = next.ok_or_else(|| Error::new_kind(ErrorKind::NotFound,
+                            format!("No recorded result for {:?} {}", effect, key)))?;
+                match &self.entries.borrow()[i].outcome {
+                    Ok(bytes) => import(self.store, bytes),
+                    Err((kind, msg)) => Err(Error::new_kind(*kind, msg.clone()))
+                }
+            }
+        }
+    }
+
+    // The REPL's __path__, which depends on where it was started
+    pub fn path(&self, current: String) -> Result<String, Error> {
+        match self.mode {
+            Mode::Record => {
+                let bytes = current.as_bytes().to_vec();
+                self.entries.borrow_mut().push(Entry {
+                    effect: Effect::Path, key: String::new(), outcome: Ok(bytes)
+                });
+                Ok(current)
+            },
+            Mode::Replay => {
+                let next = self.pending.borrow_mut().get_mut(&(Effect::Path, String::new()))
+                                .and_then(|q| q.pop_front());
+                let i = next.ok_or_else(|| Error::new_const(ErrorKind::NotFound, "No recorded __path__"))?;
+                match &self.entries.borrow()[i].outcome {
+                    Ok(bytes) => String::from_utf8(bytes.clone())
+                        .map_err(|_| bad_format("Bad __path__ in replay log")),
+                    Err((kind, msg)) => Err(Error::new_kind(*kind, msg.clone()))
+                }
+            }
+        }
+    }
+}
@@ -1,33 +1,58 @@
-use crate::store::Storage;
+use crate::store::{Storage, Storable};
 use crate::{Error, ErrorKind};
 use crate::store::value::Value;
+use crate::core::{Expr, Builtin, Symbol};
+use crate::compile::Compile;
+use crate::util::mmap::snapshot_file;
+use super::lockfile::{Lockfile, LockEntry};
 
 use std::ops::Deref;
 use std::rc::Rc;
 use url::Url;
-use std::collections::HashMap;
-use std::cell::RefCell;
-use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::PermissionsExt;
 
 use async_trait::async_trait;
-
-use curl::easy::Easy;
+use base64::{alphabet, Engine};
+use base64::engine::{GeneralPurpose, GeneralPurposeConfig, DecodePaddingMode};
 
 #[async_trait(?Send)]
 pub trait ResourceProvider<'s, S: Storage> {
+    // The url schemes this provider handles. A provider with none
+    // is tried for every scheme which has no provider of its own
+    fn schemes(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
     async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error>;
 }
 
+pub(crate) fn unsupported(res: &Url) -> Error {
+    Error::new_kind(ErrorKind::UnsupportedScheme,
+        format!("No resource provider for {}:// urls ({})", res.scheme(), res))
+}
+
 pub struct Resources<'s, S : Storage + 's> {
-    providers: Vec<Rc<dyn ResourceProvider<'s, S> + 's>>
+    // scheme --> the providers for it, in the order they were added
+    schemes: HashMap<String, Vec<Rc<dyn ResourceProvider<'s, S> + 's>>>,
+    // providers for any other scheme
+    fallback: Vec<Rc<dyn ResourceProvider<'s, S> + 's>>
 }
 
 impl<'s, S: Storage + 's> Resources<'s, S> {
     pub fn new() -> Resources<'s, S> {
-        Self { providers: Default::default() }
+        Self { schemes: Default::default(), fallback: Default::default() }
     }
     pub fn add_provider(&mut self, prov: Rc<dyn ResourceProvider<'s, S> + 's>) {
-        self.providers.push(prov);
+        let schemes = prov.schemes();
+        if schemes.is_empty() {
+            self.fallback.push(prov);
+            return
+        }
+        for s in schemes {
+            self.schemes.entry(s.to_string()).or_default().push(prov.clone());
+        }
     }
 }
 
@@ -35,15 +60,30 @@ impl<'s, S: Storage + 's> Resources<'s, S> {
 impl<'s, S : Storage> ResourceProvider<'s, S> for Resources<'s, S> {
     async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
         log::trace!(target: "resource", "fetching {}", res);
-        let result = {
-            for p in self.providers.iter() {
+        let result = async {
+            let providers = self.schemes.get(res.scheme()).unwrap_or(&self.fallback);
+            let mut errors = Vec::new();
+            for p in providers.iter() {
                 match p.retrieve(res).await {
                     Ok(h) => return Ok(h),
-                    _ => ()
+                    Err(e) => errors.push(e)
                 }
             }
-            Err(Error::new(format!("Resource not found {}", res)))
-        };
+            // Report the first real failure, if there is one,
+            // rather than a provider not having the resource
+            let is_real = |e: &Error| !matches!(e.kind(), ErrorKind::NotFound | ErrorKind::UnsupportedScheme);
+            let primary = match errors.iter().position(is_real) {
+                Some(i) => errors.remove(i),
+                None if errors.is_empty() => return Err(unsupported(res)),
+                None => errors.remove(0)
+            };
+            let mut context = format!("Unable to retrieve {}", res);
+            if !errors.is_empty() {
+                let others : Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+                context.push_str(&format!(" (other providers: {})", others.join("; ")));
+            }
+            Err(primary.context(context))
+        }.await;
         match &result {
         Err(e) => log::trace!(target: "resource", "resource {} gave error: {:?}", res, e),
         Ok(_) => ()
@@ -54,6 +94,11 @@ impl<'s, S : Storage> ResourceProvider<'s, S> for Resources<'s, S> {
 
 pub struct Snapshot<'s, S : Storage + 's> {
     snapshot : RefCell<HashMap<Url, S::Handle<'s>>>,
+    // What each url fetched (now or by an earlier run) is pinned to
+    lock: RefCell<Lockfile>,
+    lock_path: Option<PathBuf>,
+    // Whether the lock has changed since it was last saved
+    dirty: Cell<bool>,
     resources: Rc<dyn ResourceProvider<'s, S> + 's>
 }
 
@@ -61,99 +106,268 @@ impl<'s, S> Snapshot<'s, S>
         where S: Storage + 's {
     
     pub fn new(resources: Rc<dyn ResourceProvider<'s, S> + 's>) -> Self {
-        Self { snapshot: RefCell::new(HashMap::new()), resources }
+        Self { snapshot: RefCell::new(HashMap::new()), lock: RefCell::new(Lockfile::new()),
+               lock_path: None, dirty: Cell::new(false), resources }
+    }
+
+    // A snapshot pinned to the lockfile at the given path (if it exists),
+    // which is where the lock is saved
+    pub fn with_lockfile<P: Into<PathBuf>>(resources: Rc<dyn ResourceProvider<'s, S> + 's>, path: P)
+                -> Result<Self, Error> {
+        let path = path.into();
+        let lock = Lockfile::load(&path)?;
+        Ok(Self { snapshot: RefCell::new(HashMap::new()), lock: RefCell::new(lock),
+                  lock_path: Some(path), dirty: Cell::new(false), resources })
+    }
+
+    pub fn lockfile(&self) -> Lockfile {
+        self.lock.borrow().clone()
+    }
+
+    // Writes the lockfile, if it has changed
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(path) = &self.lock_path {
+            if self.dirty.get() {
+                self.lock.borrow().save(path)?;
+                self.dirty.set(false);
+            }
+        }
+        Ok(())
+    }
+
+    // Re-retrieves everything in the snapshot (or its lockfile), returning
+    // the new snapshot along with the urls whose contents changed
+    // (or which can no longer be retrieved). The new snapshot is
+    // pinned to whatever was retrieved
+    pub async fn update(&self) -> (Self, HashSet<Url>) {
+        let old : Vec<_> = self.snapshot.borrow().iter()
+            .map(|(u, h)| (u.clone(), h.clone())).collect();
+        let old_lock = self.lockfile();
+        let mut urls : Vec<_> = old.iter().map(|(u, _)| u.clone()).collect();
+        urls.extend(old_lock.urls().filter(|u| !self.snapshot.borrow().contains_key(u)).cloned());
+        let old : HashMap<_, _> = old.into_iter().collect();
+
+        let mut snapshot = HashMap::new();
+        let mut lock = Lockfile::new();
+        let mut changed = HashSet::new();
+        for url in urls {
+            let res = match self.resources.retrieve(&url).await {
+                Ok(h) => LockEntry::of(&h).map(|e| (h, e)),
+                Err(e) => Err(e)
+            };
+            match res {
+                Ok((h, entry)) => {
+                    // compare content digests, since re-retrieving
+                    // a record (i.e a directory) builds a new one
+                    let differs = match (old_lock.get(&url), old.get(&url)) {
+                        (Some(pinned), _) => *pinned != entry,
+                        (None, Some(old_handle)) => LockEntry::of(old_handle).ok() != Some(entry),
+                        (None, None) => true
+                    };
+                    if differs {
+                        changed.insert(url.clone());
+                    }
+                    lock.insert(url.clone(), entry);
+                    snapshot.insert(url, h);
+                },
+                Err(_) => { changed.insert(url); }
+            }
+        }
+        (Self { snapshot: RefCell::new(snapshot), lock: RefCell::new(lock),
+                lock_path: self.lock_path.clone(), dirty: Cell::new(true),
+                resources: self.resources.clone() }, changed)
     }
 }
 
 #[async_trait(?Send)]
 impl<'s, S: Storage + 's> ResourceProvider<'s, S> for Snapshot<'s, S> {
     async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
-        let mut snapshot = self.snapshot.borrow_mut();
-        match snapshot.get(&res) {
-            Some(h) => Ok(h.clone()),
+        if let Some(h) = self.snapshot.borrow().get(res) {
+            return Ok(h.clone())
+        }
+        let h = self.resources.retrieve(res).await?;
+        let entry = LockEntry::of(&h)?;
+        let mut lock = self.lock.borrow_mut();
+        match lock.get(res) {
+            Some(pinned) if *pinned != entry => {
+                return Err(Error::new_kind(ErrorKind::Integrity, format!(
+                    "{} does not match the lockfile (expected {}, got {}), \
+                     use %update_snapshot to accept the change", res, pinned, entry)))
+            },
+            Some(_) => (),
             None => {
-                match self.resources.retrieve(res).await {
-                Ok(h) => {
-                    // Insert into the snapshot table
-                    snapshot.insert(res.clone(), h.clone());
-                    Ok(h)
-                },
-                Err(e) => Err(e)
-                }
+                lock.insert(res.clone(), entry);
+                self.dirty.set(true);
             }
         }
+        // Insert into the snapshot table
+        Ok(self.snapshot.borrow_mut().entry(res.clone()).or_insert(h).clone())
     }
 }
 
-pub struct FileProvider<'s, S: Storage> {
-    store: &'s S
+// Builds thunks which fetch a url, for providers whose
+// resources should only be read when they are used
+pub(crate) struct LazyFetch<'s, S: Storage> {
+    store: &'s S,
+    // The code for $fetch(url)
+    code: RefCell<Option<S::Handle<'s>>>
 }
 
-impl<'s, S: Storage> FileProvider<'s, S> {
+impl<'s, S: Storage> LazyFetch<'s, S> {
     pub fn new(store: &'s S) -> Self {
-        Self { store }
+        Self { store, code: RefCell::new(None) }
+    }
+
+    pub fn thunk(&self, url: &Url) -> Result<S::Handle<'s>, Error> {
+        let code = self.code.borrow().clone();
+        let code = match code {
+            Some(c) => c,
+            None => {
+                let fetch = Expr::Builtin(Builtin {
+                    op: "fetch".to_string(),
+                    args: vec![Expr::Var(Symbol { name: "url".to_string() })]
+                });
+                let (graph, _) = fetch.compile_open(self.store)?;
+                let code = graph.store_in(self.store)?;
+                *self.code.borrow_mut() = Some(code.clone());
+                code
+            }
+        };
+        let url = self.store.insert_from(&Value::String(url.to_string()))?;
+        let partial = self.store.insert_from(&Value::Partial(code, vec![url]))?;
+        self.store.insert_from(&Value::Thunk(partial))
     }
 }
 
-#[async_trait(?Send)]
-impl<'s, S: Storage + 's> ResourceProvider<'s, S> for FileProvider<'s, S> {
-    async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
-        if res.scheme() != "file" {
-            return Err(Error::new_const(ErrorKind::NotFound, "Only supports file:// scheme"))
-        }
-        let res = std::fs::read(res.path())
-            .map_err(|_| Error::new_const(ErrorKind::IO, "Couldn't read file"))?;
-        let val = Value::Buffer(Bytes::from(res));
-        self.store.insert_from(&val)
+// Where the entry helpers below insert objects: straight into a store,
+// or through a machine, which counts them against its budget
+pub(crate) trait Alloc<'s> {
+    type Store: Storage + 's;
+    fn alloc(&self, value: &Value<'s, <Self::Store as Storage>::Handle<'s>>)
+        -> Result<<Self::Store as Storage>::Handle<'s>, Error>;
+}
+
+impl<'s, S: Storage + 's> Alloc<'s> for &'s S {
+    type Store = S;
+    fn alloc(&self, value: &Value<'s, S::Handle<'s>>) -> Result<S::Handle<'s>, Error> {
+        self.insert_from(value)
+    }
+}
+
+// A file entry of a directory record
+pub(crate) fn file_entry<'s, S: Storage + 's, A: Alloc<'s, Store=S>>(store: A, content: S::Handle<'s>, executable: bool)
+            -> Result<S::Handle<'s>, Error> {
+    store.alloc(&Value::Record(vec![
+        (store.alloc(&Value::String("content".to_string()))?, content),
+        (store.alloc(&Value::String("executable".to_string()))?,
+            store.alloc(&Value::Bool(executable))?)
+    ]))
+}
+
+// A symlink entry of a directory record, {symlink: target}
+pub(crate) fn symlink_entry<'s, S: Storage + 's, A: Alloc<'s, Store=S>>(store: A, target: String) -> Result<S::Handle<'s>, Error> {
+    store.alloc(&Value::Record(vec![
+        (store.alloc(&Value::String("symlink".to_string()))?, store.alloc(&Value::String(target))?)
+    ]))
+}
+
+// {entries: {name: entry}}, with the entries sorted by name,
+// along with any other fields given
+pub(crate) fn dir_record<'s, S: Storage + 's, A: Alloc<'s, Store=S>>(store: A, mut entries: Vec<(String, S::Handle<'s>)>,
+                                         fields: Vec<(&str, S::Handle<'s>)>) -> Result<S::Handle<'s>, Error> {
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let entries = entries.into_iter().map(|(k, v)| {
+        Ok((store.alloc(&Value::String(k))?, v))
+    }).collect::<Result<Vec<_>, Error>>()?;
+    let entries = store.alloc(&Value::Record(entries))?;
+    let mut record = vec![(store.alloc(&Value::String("entries".to_string()))?, entries)];
+    for (k, v) in fields {
+        record.push((store.alloc(&Value::String(k.to_string()))?, v));
     }
+    store.alloc(&Value::Record(record))
 }
 
-pub struct HttpProvider<'s, S: Storage> {
+// Reads file:// urls. A file is read as a buffer, and a directory as
+// {entries: {name: entry}}, where a file entry is {content, executable}
+// and a subdirectory entry is itself a directory. Entries are thunks
+// which fetch their own url, so only what is used gets read
+pub struct FileProvider<'s, S: Storage> {
     store: &'s S,
-    cache: RefCell<HashMap<Url, S::Handle<'s>>>
+    lazy: LazyFetch<'s, S>,
+    // Where large files are copied to be mapped
+    snapshot_dir: PathBuf
 }
 
-impl<'s, S: Storage> HttpProvider<'s, S> {
+impl<'s, S: Storage> FileProvider<'s, S> {
     pub fn new(store: &'s S) -> Self {
-        Self { store, cache: RefCell::new(HashMap::new()) }
+        Self::with_snapshot_dir(store, std::env::temp_dir())
+    }
+
+    pub fn with_snapshot_dir<P: Into<PathBuf>>(store: &'s S, dir: P) -> Self {
+        Self { store, lazy: LazyFetch::new(store), snapshot_dir: dir.into() }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<S::Handle<'s>, Error> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(n) => {
+                    log::warn!(target: "resource", "Skipping non-utf8 file name {:?}", n);
+                    continue
+                }
+            };
+            let child = entry.path();
+            let url = Url::from_file_path(&child)
+                .map_err(|_| Error::new_const(ErrorKind::BadFormat, "Bad file path"))?;
+            // follows symlinks
+            let meta = std::fs::metadata(&child)?;
+            let value = if meta.is_dir() {
+                self.lazy.thunk(&url)?
+            } else if meta.is_file() {
+                let executable = meta.permissions().mode() & 0o111 != 0;
+                file_entry(self.store, self.lazy.thunk(&url)?, executable)?
+            } else {
+                continue
+            };
+            entries.push((name, value));
+        }
+        dir_record(self.store, entries, Vec::new())
     }
 }
 
 #[async_trait(?Send)]
-impl<'s, S: Storage + 's> ResourceProvider<'s, S> for HttpProvider<'s, S> {
+impl<'s, S: Storage + 's> ResourceProvider<'s, S> for FileProvider<'s, S> {
+    fn schemes(&self) -> Vec<&'static str> {
+        vec!["file"]
+    }
     async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
-        if res.scheme() != "http" && res.scheme() != "https" {
-            return Err(Error::new_const(ErrorKind::NotFound, "Only supports file:// scheme"))
+        if res.scheme() != "file" {
+            return Err(unsupported(res))
         }
-        {
-            let cache = self.cache.borrow_mut();
-            if let Some(h) = cache.get(res) {
-                return Ok(h.clone())
-            }
+        let path = res.to_file_path()
+            .map_err(|_| Error::new_const(ErrorKind::BadFormat, "Not a file path"))?;
+        let meta = std::fs::metadata(&path).map_err(|e| file_error(e, &path))?;
+        if meta.is_dir() {
+            return self.read_dir(&path)
         }
-        log::info!(target: "resource", "Fetching remote resource {}", res);
-        let res_url = res.clone();
-        let bytes : Result<Vec<u8>, Error> = blocking::unblock(move | | {
-            let mut handle = Easy::new();
-            let mut data = Vec::new();
-            handle.url(res_url.as_str()).unwrap();
-            {
-                let mut transfer = handle.transfer();
-                transfer.write_function(|new_data| {
-                    data.extend_from_slice(new_data);
-                    Ok(new_data.len())
-                }).map_err(|_| Error::new("Unable to fetch"))?;
-                transfer.perform().map_err(|_| Error::new("Unable to fetch"))?;
-            }
-            Ok(data)
-        }).await;
-        let val = Value::Buffer(Bytes::from(bytes?));
-        let handle = self.store.insert_from(&val)?;
-        self.cache.borrow_mut().insert(res.clone(), handle.clone());
-        Ok(handle)
+        // editing the file afterwards can't change a buffer already in use
+        let res = snapshot_file(&path, &self.snapshot_dir).map_err(|e| file_error(e, &path))?;
+        let val = Value::Buffer(res);
+        self.store.insert_from(&val)
     }
 }
 
+// Keeps the io error as the source, with a missing file being NotFound
+fn file_error(e: std::io::Error, path: &Path) -> Error {
+    let kind = match e.kind() {
+        std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+        _ => ErrorKind::IO
+    };
+    Error::new_kind(kind, e).context(format!("Couldn't read {}", path.display()))
+}
+
 pub struct BuiltinsProvider<'s, S: Storage + 's> {
     store: &'s S,
     handle: RefCell<Option<S::Handle<'s>>>
@@ -182,9 +396,12 @@ impl<'s, S: Storage + 's> BuiltinsProvider<'s, S> {
 
 #[async_trait(?Send)]
 impl<'s, S: Storage + 's> ResourceProvider<'s, S> for BuiltinsProvider<'s, S> {
+    fn schemes(&self) -> Vec<&'static str> {
+        vec!["builtin"]
+    }
     async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
         if res.scheme() != "builtin" {
-            return Err(Error::new_const(ErrorKind::NotFound, "Only supports builtin:// scheme"))
+            return Err(unsupported(res))
         }
         if res.host_str() == Some("prelude") {
             return self.prelude().await
@@ -192,4 +409,104 @@ impl<'s, S: Storage + 's> ResourceProvider<'s, S> for BuiltinsProvider<'s, S> {
             return Err(Error::new_const(ErrorKind::NotFound, "Builtin not found"))
         }
     }
-}
\ No newline at end of file
+}
+// Reads RFC 2397 data: urls, data:[<mediatype>][;base64],<data>,
+// as buffers. The media type is not checked
+pub struct DataProvider<'s, S: Storage + 's> {
+    store: &'s S
+}
+
+impl<'s, S: Storage + 's> DataProvider<'s, S> {
+    pub fn new(store: &'s S) -> Self {
+        Self { store }
+    }
+}
+
+// data: urls may use either base64 alphabet, with or without padding
+const LENIENT: GeneralPurposeConfig = GeneralPurposeConfig::new()
+    .with_decode_padding_mode(DecodePaddingMode::Indifferent)
+    .with_decode_allow_trailing_bits(true);
+const BASE64: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, LENIENT);
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(&alphabet::URL_SAFE, LENIENT);
+
+fn decode_data_url(res: &Url) -> Result<Vec<u8>, Error> {
+    let bad = |m: &'static str| Error::new_const(ErrorKind::BadFormat, m);
+    // everything but the fragment belongs to the data
+    let body = &res[url::Position::BeforePath..url::Position::AfterQuery];
+    let (header, data) = body.split_once(',').ok_or_else(|| bad("data: url has no ','"))?;
+    let data : Vec<u8> = percent_encoding::percent_decode_str(data).collect();
+    if header.rsplit(';').next().map(|p| p.eq_ignore_ascii_case("base64")) == Some(true) {
+        let data : Vec<u8> = data.into_iter().filter(|c| !c.is_ascii_whitespace()).collect();
+        BASE64.decode(&data).or_else(|_| BASE64_URL.decode(&data))
+            .map_err(|e| Error::new_kind(ErrorKind::BadFormat, e).context("data: url has malformed base64"))
+    } else {
+        Ok(data)
+    }
+}
+
+#[async_trait(?Send)]
+impl<'s, S: Storage + 's> ResourceProvider<'s, S> for DataProvider<'s, S> {
+    fn schemes(&self) -> Vec<&'static str> {
+        vec!["data"]
+    }
+    async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
+        if res.scheme() != "data" {
+            return Err(unsupported(res))
+        }
+        let data = decode_data_url(res)?;
+        self.store.insert_from(&Value::Buffer(data.into()))
+    }
+}
+
+// Reads environment variables as strings, from env://VAR urls.
+// Nothing registers this by default, since it makes builds depend on
+// the environment. Under a snapshot the values read are pinned like any
+// other resource, so a variable changing is noticed rather than ignored
+pub struct EnvProvider<'s, S: Storage + 's> {
+    store: &'s S,
+    // If set, the only variables which may be read
+    allowed: Option<HashSet<String>>
+}
+
+impl<'s, S: Storage + 's> EnvProvider<'s, S> {
+    pub fn new(store: &'s S) -> Self {
+        Self { store, allowed: None }
+    }
+
+    pub fn only<I: IntoIterator<Item=String>>(store: &'s S, vars: I) -> Self {
+        Self { store, allowed: Some(vars.into_iter().collect()) }
+    }
+}
+
+#[async_trait(?Send)]
+impl<'s, S: Storage + 's> ResourceProvider<'s, S> for EnvProvider<'s, S> {
+    fn schemes(&self) -> Vec<&'static str> {
+        vec!["env"]
+    }
+    async fn retrieve(&self, res: &Url) -> Result<S::Handle<'s>, Error> {
+        if res.scheme() != "env" {
+            return Err(unsupported(res))
+        }
+        let var = match res.host_str() {
+            Some(h) if !h.is_empty() => h,
+            _ => res.path().trim_start_matches('/')
+        };
+        if var.is_empty() {
+            return Err(Error::new_const(ErrorKind::BadFormat, "env: url has no variable"))
+        }
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(var) {
+                return Err(Error::new_kind(ErrorKind::NotFound,
+                    format!("Environment variable {} may not be read", var)))
+            }
+        }
+        let value = match std::env::var(var) {
+            Ok(v) => v,
+            Err(std::env::VarError::NotPresent) => return Err(Error::new_kind(
+                ErrorKind::NotFound, format!("Environment variable {} is not set", var))),
+            Err(e) => return Err(Error::new_kind(ErrorKind::BadFormat, e))
+        };
+        log::debug!(target: "resource", "read environment variable {}", var);
+        self.store.insert_from(&Value::String(value))
+    }
+}
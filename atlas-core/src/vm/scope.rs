@@ -1,60 +1,202 @@
-use crate::{Error};
+use crate::{Error, ErrorKind};
 use crate::store::op::{OpAddr, OpCount, RegID, Dest};
-use crate::store::{CodeReader, Storage, IndirectBuilder};
+use crate::store::{Storage, Handle, ObjectReader, CodeReader, IndirectBuilder};
+
+use super::trace::TraceContext;
 
 use deadqueue::unlimited::Queue;
 use std::collections::HashMap;
 use slab::Slab;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+
+// Frames are identified by a monotonically increasing counter
+// (rather than a slab key) so that stale queue items for a frame
+// that has already finished can never be delivered to a new frame
+pub type FrameID = u64;
+
+// Each call to Machine::force() is numbered the same way, and every
+// frame belongs to the force it was started for, so that abandoning
+// a force only fails the frames it started
+pub type RootID = u64;
 
 pub enum ExecItem<'s, S: Storage + 's> {
-    Op(OpAddr),
-    Ret(S::Handle<'s>),
-    Err(Error)
+    Op(FrameID, OpAddr),
+    Ret(FrameID, S::Handle<'s>),
+    Err(FrameID, Error),
+    // The result of a thunk evaluated by another machine
+    Resume(Waiter<'s, S>, Result<S::Handle<'s>, Error>)
 }
 
-// An execqueue manages the execution of a particular
-// code block by tracking dependencies
+// An execqueue holds the operations (across all of the
+// frames being evaluated by a machine) which are ready to execute.
 // It needs to be shared among all ongoing coroutines
-// being executed
+// driving the machine
 pub struct ExecQueue<'s, S: Storage + 's> {
-    // The queue of operations that are
-    // ready to execute
-    queue : Queue<ExecItem<'s, S>>,
-    // map from op to number of dependencies
-    // left to be satisfied.
-    waiting : RefCell<HashMap<OpAddr, OpCount>>
+    queue : Queue<ExecItem<'s, S>>
 }
 
 impl<'s, S: Storage> ExecQueue<'s, S> {
     pub fn new() -> Self {
-        Self { 
-            queue: Queue::new(), 
-            waiting: RefCell::new(HashMap::new())
-        }
+        Self { queue: Queue::new() }
     }
 
-    pub fn push(&self, addr: OpAddr) {
-        self.queue.push(ExecItem::Op(addr))
+    pub fn push(&self, frame: FrameID, addr: OpAddr) {
+        self.queue.push(ExecItem::Op(frame, addr))
     }
 
-    pub async fn next_op(&self) -> ExecItem<'s, S> {
+    pub async fn next(&self) -> ExecItem<'s, S> {
         self.queue.pop().await
     }
 
-    pub fn notify_return(&self, h: S::Handle<'s>) {
-        self.queue.push(ExecItem::Ret(h))
+    pub fn try_next(&self) -> Option<ExecItem<'s, S>> {
+        self.queue.try_pop()
     }
 
-    pub fn notify_error(&self, e: Error) {
-        self.queue.push(ExecItem::Err(e))
+    pub fn notify_return(&self, frame: FrameID, h: S::Handle<'s>) {
+        self.queue.push(ExecItem::Ret(frame, h))
     }
 
-    // Will complete a particular operation, getting each of the
-    // dependents and notifying them that a dependency has been completed
-    pub fn complete<'p, R: CodeReader<'p, 's>>(&self, dest: &Dest, code: &R) {
-        for d in &dest.uses {
-            self.dep_complete_for(*d, code);
+    pub fn notify_error(&self, frame: FrameID, e: Error) {
+        self.queue.push(ExecItem::Err(frame, e))
+    }
+
+    pub fn notify_resume(&self, waiter: Waiter<'s, S>, res: Result<S::Handle<'s>, Error>) {
+        self.queue.push(ExecItem::Resume(waiter, res))
+    }
+}
+
+type Landing<'s, S> = Result<<S as Storage>::Handle<'s>, Error>;
+
+// The thunks being evaluated by any of the machines sharing
+// this table (usually the machines sharing a thunk map), so that
+// a machine forcing a thunk which another machine is already
+// evaluating waits on that result instead of running it again
+pub struct InFlight<'s, S: Storage + 's> {
+    map: RefCell<HashMap<S::Handle<'s>,
+        (async_broadcast::Sender<Landing<'s, S>>, async_broadcast::Receiver<Landing<'s, S>>)>>
+}
+
+impl<'s, S: Storage> Default for InFlight<'s, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'s, S: Storage> InFlight<'s, S> {
+    pub fn new() -> Self {
+        Self { map: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn begin(&self, thunk: &S::Handle<'s>) {
+        let (s, r) = async_broadcast::broadcast(1);
+        self.map.borrow_mut().insert(thunk.clone(), (s, r));
+    }
+
+    // Returns a receiver for the result of the thunk
+    // if it is currently being evaluated
+    pub fn wait(&self, thunk: &S::Handle<'s>) -> Option<async_broadcast::Receiver<Landing<'s, S>>> {
+        self.map.borrow().get(thunk).map(|(_, r)| r.clone())
+    }
+
+    // Removes the thunk from the table, passing
+    // the result to everyone waiting on it
+    pub fn land(&self, thunk: &S::Handle<'s>, res: Result<&S::Handle<'s>, &Error>) {
+        let entry = self.map.borrow_mut().remove(thunk);
+        if let Some((s, _r)) = entry {
+            let res = res.map(|h| h.clone()).map_err(|e| e.clone());
+            // we still hold _r, so the channel cannot be closed
+            s.try_broadcast(res).ok();
+        }
+    }
+
+    pub async fn landed(recv: &mut async_broadcast::Receiver<Landing<'s, S>>) -> Result<S::Handle<'s>, Error> {
+        match recv.recv().await {
+            Ok(res) => res,
+            Err(_) => Err(Error::new_const(ErrorKind::Internal, "Thunk evaluation was abandoned"))
+        }
+    }
+}
+
+// Where the result of a frame should be delivered
+pub enum Waiter<'s, S: Storage + 's> {
+    // A register in another frame
+    Frame(FrameID, Dest),
+    // A call to Machine::force(), made by a task
+    // acting for the given frame (if any)
+    Root(smol::channel::Sender<Result<S::Handle<'s>, Error>>, Option<FrameID>, RootID)
+}
+
+impl<'s, S: Storage> Waiter<'s, S> {
+    // The frame which is waiting, if any
+    pub fn frame(&self) -> Option<FrameID> {
+        match self {
+            Waiter::Frame(id, _) => Some(*id),
+            Waiter::Root(_, id, _) => *id
+        }
+    }
+}
+
+// A frame is a single thunk under evaluation. Frames
+// do not reference each other directly, so forcing a thunk
+// from inside another thunk never recurses on the native stack
+pub struct Frame<'s, S: Storage + 's> {
+    pub id: FrameID,
+    // The force this frame was started for
+    pub root: Cell<RootID>,
+    pub thunk: S::Handle<'s>,
+    pub code: S::Handle<'s>,
+    pub inputs: Vec<S::Handle<'s>>,
+    pub regs: Registers<'s, S>,
+    // Everyone waiting on the result of this thunk
+    pub waiters: RefCell<Vec<Waiter<'s, S>>>,
+    // Set if the machine is recording traces
+    pub trace: RefCell<Option<TraceContext<'s, S>>>,
+    // Whether the result depends on a resource, read
+    // by the frame or by a volatile thunk it forced
+    pub volatile: Cell<bool>,
+    // map from op to number of dependencies
+    // left to be satisfied.
+    waiting : RefCell<HashMap<OpAddr, OpCount>>
+}
+
+impl<'s, S: Storage> Frame<'s, S> {
+    pub fn new(id: FrameID, root: RootID, thunk: S::Handle<'s>, code: S::Handle<'s>,
+                inputs: Vec<S::Handle<'s>>, regs: Registers<'s, S>) -> Self {
+        Self {
+            id, thunk, code, inputs, regs,
+            root: Cell::new(root),
+            waiters: RefCell::new(Vec::new()),
+            trace: RefCell::new(None),
+            volatile: Cell::new(false),
+            waiting: RefCell::new(HashMap::new())
+        }
+    }
+
+    // Sets the destination register, releasing any dependents
+    // into the queue, or returns from the frame if the destination
+    // is the return register
+    pub fn complete(&self, queue: &ExecQueue<'s, S>, d: &Dest, res: Result<S::Handle<'s>, Error>) {
+        match res {
+            Err(e) => queue.notify_error(self.id, e),
+            Ok(h) => {
+                if let Some(t) = self.trace.borrow_mut().as_mut() {
+                    t.complete(d.reg, &h)
+                }
+                if self.regs.return_reg() == d.reg {
+                    queue.notify_return(self.id, h)
+                } else {
+                    let res : Result<(), Error> = try {
+                        let code = self.code.reader()?.as_code()?;
+                        for u in &d.uses {
+                            self.dep_complete_for(*u, &code, queue);
+                        }
+                    };
+                    match res {
+                        Ok(()) => self.regs.set_object(d, h),
+                        Err(e) => queue.notify_error(self.id, e)
+                    }
+                }
+            }
         }
     }
 
@@ -64,8 +206,7 @@ impl<'s, S: Storage> ExecQueue<'s, S> {
     // completed. If this is the first time the given operation
     // has a dependency complete, we read the operation and determine
     // the number of dependencies it has.
-    fn dep_complete_for<'p, R: CodeReader<'p, 's>>(&self, op: OpAddr, code: &R) {
-        let opr = code.get_op(op);
+    fn dep_complete_for<'p, R: CodeReader<'p, 's>>(&self, op: OpAddr, code: &R, queue: &ExecQueue<'s, S>) {
         let mut w = self.waiting.borrow_mut();
         match w.get_mut(&op) {
             Some(r) => {
@@ -74,18 +215,18 @@ impl<'s, S: Storage> ExecQueue<'s, S> {
                 if *r == 0 {
                     // release into the queue
                     w.remove(&op);
-                    self.queue.push(ExecItem::Op(op));
+                    queue.push(self.id, op);
                 }
             },
             None => {
                 // this is the first time this op
                 // is being listed as dependency complete, find the number of dependents
-                let deps = opr.num_deps();
+                let deps = code.get_op(op).num_deps();
                 log::trace!(target: "queue", "populating {} requirements for #{}", deps, op);
                 if deps > 1 {
                     w.insert(op, deps - 1);
                 } else {
-                    self.queue.push(ExecItem::Op(op));
+                    queue.push(self.id, op);
                 }
             }
         }
@@ -125,6 +266,9 @@ pub struct Registers<'s, S: Storage> {
     // map from ObjectID to the slab register key
     reg_map: RefCell<HashMap<RegID, usize>>,
     return_reg: RegID,
+    // The number of indirects created since the machine
+    // last counted them against its allocation budget
+    indirects: Cell<u64>,
     store: &'s S
 }
 
@@ -134,6 +278,7 @@ impl<'s, S: Storage> Registers<'s, S> {
             regs: RefCell::new(Slab::new()),
             reg_map: RefCell::new(HashMap::new()),
             return_reg,
+            indirects: Cell::new(0),
             store
         }
     }
@@ -142,6 +287,10 @@ impl<'s, S: Storage> Registers<'s, S> {
         self.return_reg
     }
 
+    pub fn take_indirects(&self) -> u64 {
+        self.indirects.replace(0)
+    }
+
     // Will set a particular ObjectID to a given entry value, as well as
     // a number of uses for this data until the register should be discarded
     pub fn set_object(&self, dest: &Dest, e: S::Handle<'s>) {
@@ -178,6 +327,7 @@ impl<'s, S: Storage> Registers<'s, S> {
         None => {
             // Insert a bot value. This will be replaced when the value is actually populated
             let tmp = self.store.indirect()?;
+            self.indirects.set(self.indirects.get() + 1);
             let handle = tmp.handle();
             let key = regs.insert(Reg::Temp(tmp));
             reg_map.insert(d, key);
@@ -205,18 +355,3 @@ impl<'s, S: Storage> Registers<'s, S> {
         }
     }
 }
-
-pub fn complete<'s, 'p, S: Storage, R: CodeReader<'p, 's>>(code: &R, regs: &Registers<'s, S>, queue: &ExecQueue<'s, S>, 
-                        d: &Dest, res: Result<S::Handle<'s>, Error>) {
-    match res {
-        Err(e) => queue.notify_error(e),
-        Ok(h) => {
-            if regs.return_reg() == d.reg {
-                queue.notify_return(h)
-            } else {
-                queue.complete(d, code);
-                regs.set_object(d, h)
-            }
-        }
-    }
-}
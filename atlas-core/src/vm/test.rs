@@ -1,57 +1,1153 @@
 use crate::core::{Expr, Builtin, Literal};
-use crate::value::{mem::MemoryStorage, Numeric, Env};
-use crate::compile::Compile;
+use crate::store::{Storage, Storable, Handle, ObjectReader, Numeric, IndirectBuilder, ThunkMap, StringReader, BufferReader};
+use crate::store::heap::{HeapStorage, ItemHandle};
+use crate::store::file::FileStorage;
+use crate::store::value::Value;
+use crate::compile::{Compile, Env};
 use crate::parse::ast::Module;
 use crate::parse::lexer::Lexer;
 use crate::grammar;
+use crate::ErrorKind;
 
-use super::tracer::ForceCache;
-use super::machine::Machine;
-
+use super::machine::{Machine, SyscallHandler, Budget};
+use super::resource::Resources;
+use super::scope::InFlight;
+use super::trace::Cache;
+use super::resource::{ResourceProvider, Snapshot};
+use url::Url;
 
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
 use smol::LocalExecutor;
 use futures_lite::future;
 
-use test_log::test;
-
 #[test]
 fn test_core_add() {
-    let add = 
-        Expr::Builtin(Builtin { op :"add".to_string(), 
+    let add =
+        Expr::Builtin(Builtin { op :"add".to_string(),
         args: vec![
             Expr::Literal(Literal::Int(42)),
             Expr::Literal(Literal::Int(24))
         ]});
-    let alloc = MemoryStorage::new();
+    let storage = HeapStorage::new();
     let env = Env::new();
-    let thunk = add.compile(&alloc, &env).unwrap();
+    let code = add.compile(&storage, &env).unwrap().store_in(&storage).unwrap();
+    let thunk = storage.insert_from(&Value::Thunk(code)).unwrap();
 
-    let cache = ForceCache::new();
-    let machine = Machine::new(&alloc, &cache);
+    let machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
     let exec = LocalExecutor::new();
     future::block_on(exec.run(async {
-        let res = machine.force(thunk.clone()).await.unwrap();
-        let val = res.as_numeric().unwrap();
+        let res = machine.force(&thunk).await.unwrap();
+        let val = res.reader().unwrap().as_numeric().unwrap();
         assert_eq!(val, Numeric::Int(66))
     }));
 }
 
 #[test]
 fn test_prelude_end_to_end() {
-    let alloc = MemoryStorage::new();
+    let storage = HeapStorage::new();
+    let mut env = Env::new();
+    let path = storage.insert_from(&Value::String("file:///test/".to_string())).unwrap();
+    env.insert(String::from("__path__"), path);
 
     let prelude_lexed = Lexer::new(crate::core::prelude::PRELUDE);
     let prelude : Module = grammar::ModuleParser::new().parse(prelude_lexed).unwrap();
-    // transpile the prelude
     let expr = prelude.transpile();
-    let handle = expr.compile(&alloc, &Env::new()).unwrap();
+    let code = expr.compile(&storage, &env).unwrap().store_in(&storage).unwrap();
+    let thunk = storage.insert_from(&Value::Thunk(code)).unwrap();
+
+    let machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let mut module_env = Env::new();
+        machine.env_use(thunk, &mut module_env).await.unwrap();
+        assert_eq!(module_env.len(), prelude.globals().len());
+    }));
+}
+
+// Applying `tenfold` to itself 6 times makes a step which runs a
+// million times, building a chain of accumulator thunks, each of
+// which forces the previous one. This used to overflow the native
+// stack, before evaluation was driven from the machine's run queue
+#[test]
+fn test_fold_million() {
+    const N : usize = 6;
+    let storage = HeapStorage::new();
+    let src = format!("{{ let step = |acc| $add($force(acc), 1); let tenfold = |f| |x| {}x{}; {}step{}(0) }}",
+                      "f(".repeat(10), ")".repeat(10), "tenfold(".repeat(N), ")".repeat(N));
+    let fold = compile_expr(&storage, &src);
+
+    let machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+    let exec = LocalExecutor::new();
+    let res = future::block_on(exec.run(machine.force(&fold))).unwrap();
+    assert_eq!(res.reader().unwrap().as_numeric().unwrap(), Numeric::Int(10i64.pow(N as u32)));
+}
+
+// Compiles an expression and forces it to get a lambda
+fn compile_expr<'s, S: Storage>(storage: &'s S, src: &str) -> S::Handle<'s> {
+    let expr = grammar::ExprParser::new().parse(Lexer::new(src)).unwrap().transpile();
+    let code = expr.compile(storage, &Env::new()).unwrap().store_in(storage).unwrap();
+    storage.insert_from(&Value::Thunk(code)).unwrap()
+}
+
+// A thunk which forces itself (as produced by a rec binding,
+// which refers back to itself through an indirect)
+#[test]
+fn test_blackhole() {
+    let storage = HeapStorage::new();
+    let machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+    let exec = LocalExecutor::new();
+    let res = future::block_on(exec.run(async {
+        let lam = machine.force(&compile_expr(&storage, "|x| $add($force(x), 1)")).await.unwrap();
+        let tmp = storage.indirect().unwrap();
+        let partial = storage.insert_from(&Value::Partial(lam, vec![tmp.handle()])).unwrap();
+        let x = storage.insert_from(&Value::Thunk(partial)).unwrap();
+        tmp.build(x.clone());
+        machine.force(&x).await
+    }));
+    let err = res.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InfiniteLoop);
+    assert!(err.to_string().contains("infinite loop detected"));
+}
+
+struct ForceArg;
+
+#[async_trait(?Send)]
+impl<'s> SyscallHandler<'s, HeapStorage> for ForceArg {
+    async fn call(&self, _: &str, mach: &Machine<'s, HeapStorage>, args: Vec<ItemHandle<'s>>)
+            -> Result<ItemHandle<'s>, crate::Error> {
+        mach.force(&args[0]).await
+    }
+}
+
+// A thunk which forces itself from inside a syscall
+#[test]
+fn test_blackhole_syscall() {
+    let storage = HeapStorage::new();
+    let mut machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+    machine.add_syscall("force", Rc::new(ForceArg));
+    let exec = LocalExecutor::new();
+    let res = future::block_on(exec.run(async {
+        let lam = machine.force(&compile_expr(&storage, "|x| $sys(\"force\", x)")).await.unwrap();
+        let tmp = storage.indirect().unwrap();
+        let partial = storage.insert_from(&Value::Partial(lam, vec![tmp.handle()])).unwrap();
+        let x = storage.insert_from(&Value::Thunk(partial)).unwrap();
+        tmp.build(x.clone());
+        machine.force(&x).await
+    }));
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::InfiniteLoop);
+}
+
+// Every waiter on a failed thunk gets the whole error
+#[test]
+fn test_shared_error() {
+    use super::resource::FileProvider;
+    let storage = HeapStorage::new();
+    let mut resources = Resources::new();
+    resources.add_provider(Rc::new(FileProvider::new(&storage)));
+    let machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(resources));
+    let missing = std::env::temp_dir().join(format!("atlas-vm-{}-gone", std::process::id()));
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let f = compile_expr(&storage, &format!("$fetch(\"{}\")", Url::from_file_path(&missing).unwrap()));
+        let lam = machine.force(&compile_expr(&storage, "|x| $add($force(x), 1)")).await.unwrap();
+        let partial = storage.insert_from(&Value::Partial(lam, vec![f.clone()])).unwrap();
+        let g = storage.insert_from(&Value::Thunk(partial)).unwrap();
+        let (a, b) = future::zip(machine.force(&f), machine.force(&g)).await;
+        for err in [a.unwrap_err(), b.unwrap_err()] {
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+            assert!(err.chain().iter().any(|e| e.downcast_ref::<std::io::Error>().is_some()));
+        }
+    }));
+}
+
+struct Tick(Cell<i64>);
+
+#[async_trait(?Send)]
+impl<'s, S: Storage + 's> SyscallHandler<'s, S> for Tick {
+    async fn call(&self, _: &str, mach: &Machine<'s, S>, _: Vec<S::Handle<'s>>)
+            -> Result<S::Handle<'s>, crate::Error> {
+        self.0.set(self.0.get() + 1);
+        mach.store().insert_from(&Value::Int(self.0.get()))
+    }
+}
+
+// Both forces of x happen while x is still waiting on
+// the (async) syscall, so the second must wait on the first
+#[test]
+fn test_shared_force() {
+    let storage = HeapStorage::new();
+    let tick = Rc::new(Tick(Cell::new(0)));
+    let mut machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+    machine.add_syscall("tick", tick.clone());
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let lam = machine.force(&compile_expr(&storage, "|x| $add($force(x), $force(x))")).await.unwrap();
+        let x = compile_expr(&storage, "$sys(\"tick\")");
+        let partial = storage.insert_from(&Value::Partial(lam, vec![x])).unwrap();
+        let y = storage.insert_from(&Value::Thunk(partial)).unwrap();
+        let res = machine.force(&y).await.unwrap();
+        assert_eq!(res.reader().unwrap().as_numeric().unwrap(), Numeric::Int(2));
+    }));
+    assert_eq!(tick.0.get(), 1);
+}
+
+// Two machines sharing a thunk map force the same
+// thunk at the same time
+#[test]
+fn test_shared_in_flight() {
+    let storage = HeapStorage::new();
+    let tick = Rc::new(Tick(Cell::new(0)));
+    let thunk_map = Rc::new(storage.create_thunk_map());
+    let in_flight = Rc::new(InFlight::new());
+    let machines : Vec<_> = (0..2).map(|_| {
+        let mut m = Machine::new(&storage, thunk_map.clone(), Rc::new(Resources::new()));
+        m.add_syscall("tick", tick.clone());
+        m.set_in_flight(in_flight.clone());
+        m
+    }).collect();
+    let x = compile_expr(&storage, "$sys(\"tick\")");
+    let exec = LocalExecutor::new();
+    let (a, b) = future::block_on(exec.run(
+        future::zip(machines[0].force(&x), machines[1].force(&x))
+    ));
+    assert_eq!(a.unwrap().reader().unwrap().as_numeric().unwrap(), Numeric::Int(1));
+    assert_eq!(b.unwrap().reader().unwrap().as_numeric().unwrap(), Numeric::Int(1));
+    assert_eq!(tick.0.get(), 1);
+}
+
+struct Hang;
+
+#[async_trait(?Send)]
+impl<'s> SyscallHandler<'s, HeapStorage> for Hang {
+    async fn call(&self, _: &str, _: &Machine<'s, HeapStorage>, _: Vec<ItemHandle<'s>>)
+            -> Result<ItemHandle<'s>, crate::Error> {
+        future::pending().await
+    }
+}
+
+struct Sleep;
+
+#[async_trait(?Send)]
+impl<'s> SyscallHandler<'s, HeapStorage> for Sleep {
+    async fn call(&self, _: &str, _: &Machine<'s, HeapStorage>, args: Vec<ItemHandle<'s>>)
+            -> Result<ItemHandle<'s>, crate::Error> {
+        smol::Timer::after(Duration::from_millis(100)).await;
+        Ok(args[0].clone())
+    }
+}
+
+#[test]
+fn test_budget() {
+    let storage = HeapStorage::new();
+    let sum = compile_expr(&storage, "$add($add(1, 2), $add(3, 4))");
+    let hang = compile_expr(&storage, "$sys(\"hang\")");
+    let exec = LocalExecutor::new();
+    let limited = |budget: Budget| {
+        let mut m = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+        m.add_syscall("hang", Rc::new(Hang));
+        m.set_budget(budget);
+        m
+    };
+    future::block_on(exec.run(async {
+        let m = limited(Budget { ops: Some(100), ..Budget::default() });
+        assert!(m.force(&sum).await.is_ok());
+        let m = limited(Budget { ops: Some(3), ..Budget::default() });
+        assert_eq!(m.force(&sum).await.unwrap_err().kind(), ErrorKind::BudgetExceeded);
+        let m = limited(Budget { allocations: Some(2), ..Budget::default() });
+        assert_eq!(m.force(&sum).await.unwrap_err().kind(), ErrorKind::BudgetExceeded);
+        let m = limited(Budget { deadline: Some(Instant::now() + Duration::from_millis(50)), ..Budget::default() });
+        assert_eq!(m.force(&hang).await.unwrap_err().kind(), ErrorKind::BudgetExceeded);
+        // the thunk cut off by the deadline can be forced again
+        let slow = compile_expr(&storage, "$sys(\"sleep\", 7)");
+        let mut m = limited(Budget { deadline: Some(Instant::now() + Duration::from_millis(10)), ..Budget::default() });
+        m.add_syscall("sleep", Rc::new(Sleep));
+        assert_eq!(m.force(&slow).await.unwrap_err().kind(), ErrorKind::BudgetExceeded);
+        m.set_budget(Budget::default());
+        let res = future::or(async { Some(m.force(&slow).await) },
+                             async { smol::Timer::after(Duration::from_secs(5)).await; None }).await;
+        assert_eq!(res.expect("force hung").unwrap().reader().unwrap().as_int().unwrap(), 7);
+    }));
+}
+
+// Dropping a force only fails the frames it started,
+// not those of a force made after it on the same machine
+#[test]
+fn test_abandon_concurrent() {
+    let storage = HeapStorage::new();
+    let first = compile_expr(&storage, "$sys(\"sleep\", 1)");
+    let second = compile_expr(&storage, "$add($sys(\"sleep\", 2), 1)");
+    let mut m = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+    m.add_syscall("sleep", Rc::new(Sleep));
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let mut dropped = Box::pin(m.force(&first));
+        assert!(future::poll_once(&mut dropped).await.is_none());
+        let (_, res) = future::zip(async {
+            smol::Timer::after(Duration::from_millis(10)).await;
+            drop(dropped)
+        }, m.force(&second)).await;
+        assert_eq!(res.unwrap().reader().unwrap().as_int().unwrap(), 3);
+        // the abandoned thunk can be forced again
+        assert_eq!(m.force(&first).await.unwrap().reader().unwrap().as_int().unwrap(), 1);
+    }));
+}
+
+struct NoResources;
+
+#[async_trait(?Send)]
+impl<'s> ResourceProvider<'s, HeapStorage> for NoResources {
+    async fn retrieve(&self, _: &Url) -> Result<ItemHandle<'s>, crate::Error> {
+        Err(crate::Error::new_const(crate::ErrorKind::NotFound, "No resources"))
+    }
+}
+
+#[test]
+fn test_trace() {
+    let storage = HeapStorage::new();
+    let traces = Rc::new(Cache::new());
+    let mut machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(NoResources));
+    machine.set_trace_cache(traces.clone());
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let lam = machine.force(&compile_expr(&storage, "|x| $add($force(x), 1)")).await.unwrap();
+        let x = storage.insert_from(&Value::Int(41)).unwrap();
+        let partial = storage.insert_from(&Value::Partial(lam, vec![x])).unwrap();
+        let y = storage.insert_from(&Value::Thunk(partial)).unwrap();
+        let res = machine.force(&y).await.unwrap();
+
+        let trace = traces.get(&y).unwrap();
+        assert_eq!(trace.ret(), Some(&res));
+        let actions : Vec<_> = trace.events.iter().map(|e| e.action.to_string()).collect();
+        assert_eq!(actions[0], "@0 <- input 0");
+        assert!(actions.contains(&"query @0".to_string()));
 
-    let cache = ForceCache::new();
-    let machine = Machine::new(&alloc, &cache);
+        let fetch = compile_expr(&storage, "$fetch(\"test://a\")");
+        assert!(machine.force(&fetch).await.is_err());
+        let trace = traces.get(&fetch).unwrap();
+        assert_eq!(trace.resources(), vec![&Url::parse("test://a").unwrap()]);
+        assert_eq!(trace.ret(), None);
+    }));
+}
+
+// As %gc does in the repl: each line is freed along with its trace,
+// while a binding which is still live keeps its trace and result
+#[test]
+fn test_gc_traces() {
+    let storage = HeapStorage::new();
+    let thunk_map = Rc::new(storage.create_thunk_map());
+    let traces = Rc::new(Cache::new());
+    // each line is compiled and run by a machine of its own
+    let run = |src: &str| {
+        let thunk = compile_expr(&storage, src);
+        let mut m = Machine::new(&storage, thunk_map.clone(), Rc::new(NoResources));
+        m.set_trace_cache(traces.clone());
+        future::block_on(LocalExecutor::new().run(m.force(&thunk))).unwrap();
+        thunk
+    };
+    let kept = run("$add($add(1, 2), $add(3, 4))");
+    traces.gc(&storage);
+    let (objects, recorded) = (storage.stats().objects, traces.len());
+    for i in 0..10 {
+        run(&format!("$add($add({}, 2), $add(3, 4))", i));
+    }
+    assert!(traces.len() > recorded);
+    assert!(traces.gc(&storage) > 0);
+    assert_eq!(storage.stats().objects, objects);
+    assert_eq!(traces.len(), recorded);
+    assert!(traces.get(&kept).unwrap().ret().is_some());
+    assert_eq!(thunk_map.get(&kept).unwrap().reader().unwrap().as_int().unwrap(), 10);
+    assert_eq!(traces.gc(&storage), 0);
+}
+
+struct Files<'s>(&'s HeapStorage, RefCell<HashMap<String, String>>);
+
+#[async_trait(?Send)]
+impl<'s> ResourceProvider<'s, HeapStorage> for Files<'s> {
+    async fn retrieve(&self, url: &Url) -> Result<ItemHandle<'s>, crate::Error> {
+        let content = self.1.borrow().get(url.as_str()).cloned()
+            .ok_or(crate::Error::new_const(ErrorKind::NotFound, "No such file"))?;
+        self.0.insert_from(&Value::String(content))
+    }
+}
+
+#[test]
+fn test_invalidate() {
+    let storage = HeapStorage::new();
+    let files = Rc::new(Files(&storage, RefCell::new(HashMap::from([
+        ("test://a".to_string(), "a".to_string()),
+        ("test://b".to_string(), "b".to_string())
+    ]))));
+    let thunk_map = Rc::new(storage.create_thunk_map());
+    let traces = Rc::new(Cache::new());
+    let a = compile_expr(&storage, "$fetch(\"test://a\")");
+    let b = compile_expr(&storage, "$fetch(\"test://b\")");
     let exec = LocalExecutor::new();
     future::block_on(exec.run(async {
-        let res = machine.force(handle).await.unwrap();
-        let val = res.as_record().unwrap();
-        assert_eq!(val.len(), 12);
+        let snapshot = Rc::new(Snapshot::new(files.clone()));
+        let mut m = Machine::new(&storage, thunk_map.clone(), snapshot.clone());
+        m.set_trace_cache(traces.clone());
+        m.add_syscall("force", Rc::new(ForceArg));
+        // c forces a
+        let lam = m.force(&compile_expr(&storage, "|x| $force(x)")).await.unwrap();
+        let partial = storage.insert_from(&Value::Partial(lam, vec![a.clone()])).unwrap();
+        let c = storage.insert_from(&Value::Thunk(partial)).unwrap();
+        m.force(&c).await.unwrap();
+        m.force(&b).await.unwrap();
+        // d forces a from inside a syscall
+        let lam = m.force(&compile_expr(&storage, "|x| $sys(\"force\", x)")).await.unwrap();
+        let partial = storage.insert_from(&Value::Partial(lam, vec![a.clone()])).unwrap();
+        let d = storage.insert_from(&Value::Thunk(partial)).unwrap();
+        m.force(&d).await.unwrap();
+
+        files.1.borrow_mut().insert("test://a".to_string(), "a2".to_string());
+        let (snapshot, changed) = snapshot.update().await;
+        assert_eq!(changed, HashSet::from([Url::parse("test://a").unwrap()]));
+        assert_eq!(traces.invalidate(&changed, &*thunk_map), 3);
+        assert!(thunk_map.get(&a).is_none());
+        assert!(thunk_map.get(&c).is_none());
+        assert!(thunk_map.get(&d).is_none());
+        assert!(thunk_map.get(&b).is_some());
+
+        let m = Machine::new(&storage, thunk_map.clone(), Rc::new(snapshot));
+        let res = m.force(&c).await.unwrap();
+        let res = res.reader().unwrap().as_string().unwrap();
+        assert_eq!(res.as_slice().deref(), "a2");
     }));
-}
\ No newline at end of file
+}
+
+// Results forced by one process are reused by
+// the next process to open the same store
+#[test]
+fn test_persistent_thunk_map() {
+    let dir = std::env::temp_dir().join(format!("atlas-vm-{}-thunks", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let tick = Rc::new(Tick(Cell::new(0)));
+    let file = std::env::temp_dir().join(format!("atlas-vm-{}-thunks.txt", std::process::id()));
+    let fetch = format!("$fetch(\"{}\")", Url::from_file_path(&file).unwrap());
+    for content in ["one", "two"] {
+        std::fs::write(&file, content).unwrap();
+        let storage = FileStorage::open(&dir).unwrap();
+        let mut resources = Resources::new();
+        resources.add_provider(Rc::new(super::resource::FileProvider::new(&storage)));
+        let mut machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(resources));
+        machine.add_syscall("tick", tick.clone());
+        let x = compile_expr(&storage, "$add($sys(\"tick\"), 1)");
+        let exec = LocalExecutor::new();
+        let res = future::block_on(exec.run(machine.force(&x))).unwrap();
+        assert_eq!(res.reader().unwrap().as_numeric().unwrap(), Numeric::Int(2));
+        // results which read a resource are not reused
+        let y = compile_expr(&storage, &fetch);
+        let res = future::block_on(exec.run(machine.force(&y))).unwrap();
+        assert_eq!(res.reader().unwrap().as_buffer().unwrap().as_slice().to_vec(), content.as_bytes());
+    }
+    assert_eq!(tick.0.get(), 1);
+    std::fs::remove_file(&file).ok();
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+// A replayed evaluation gets the recorded fetches and syscalls,
+// without any resources or syscall handlers
+#[test]
+fn test_record_replay() {
+    use super::replay::{ReplayLog, Mode};
+    let storage = HeapStorage::new();
+    let files = Rc::new(Files(&storage, RefCell::new(HashMap::from([
+        ("test://a".to_string(), "a".to_string())
+    ]))));
+    let srcs = ["$fetch(\"test://a\")", "$add($sys(\"tick\"), 1)", "$fetch(\"test://b\")"];
+    let exec = LocalExecutor::new();
+
+    let log = Rc::new(ReplayLog::record(&storage));
+    let mut machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), files);
+    machine.add_syscall("tick", Rc::new(Tick(Cell::new(41))));
+    machine.set_replay_log(log.clone());
+    let x = compile_expr(&storage, srcs[0]);
+    let res = future::block_on(exec.run(machine.force(&x))).unwrap();
+    assert_eq!(res.reader().unwrap().as_string().unwrap().as_slice().deref(), "a");
+    let y = compile_expr(&storage, srcs[1]);
+    let res = future::block_on(exec.run(machine.force(&y))).unwrap();
+    assert_eq!(res.reader().unwrap().as_numeric().unwrap(), Numeric::Int(43));
+    let z = compile_expr(&storage, srcs[2]);
+    assert!(future::block_on(exec.run(machine.force(&z))).is_err());
+    assert_eq!(log.path("file:///here/".to_string()).unwrap(), "file:///here/");
+    let bytes = log.to_bytes();
+
+    let storage = HeapStorage::new();
+    let log = Rc::new(ReplayLog::replay(&storage, &bytes).unwrap());
+    assert_eq!(log.mode(), Mode::Replay);
+    assert_eq!(log.path("file:///elsewhere/".to_string()).unwrap(), "file:///here/");
+    let mut machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+    machine.set_replay_log(log.clone());
+    let x = compile_expr(&storage, srcs[0]);
+    let res = future::block_on(exec.run(machine.force(&x))).unwrap();
+    assert_eq!(res.reader().unwrap().as_string().unwrap().as_slice().deref(), "a");
+    let y = compile_expr(&storage, srcs[1]);
+    let res = future::block_on(exec.run(machine.force(&y))).unwrap();
+    assert_eq!(res.reader().unwrap().as_numeric().unwrap(), Numeric::Int(43));
+    let z = compile_expr(&storage, srcs[2]);
+    let err = future::block_on(exec.run(machine.force(&z))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    // every recorded effect has been used up
+    let again = compile_expr(&storage, "$fetch(\"test://a\")");
+    assert!(future::block_on(exec.run(machine.force(&again))).is_err());
+}
+
+#[test]
+fn test_lockfile() {
+    use super::lockfile::{Lockfile, LockChange};
+    let path = std::env::temp_dir().join(format!("atlas-vm-{}.lock", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let storage = HeapStorage::new();
+    let files = Rc::new(Files(&storage, RefCell::new(HashMap::from([
+        ("test://a".to_string(), "a".to_string()),
+        ("test://b".to_string(), "b".to_string())
+    ]))));
+    let a = Url::parse("test://a").unwrap();
+    let b = Url::parse("test://b").unwrap();
+    future::block_on(async {
+        let snapshot = Snapshot::with_lockfile(files.clone(), &path).unwrap();
+        snapshot.retrieve(&a).await.unwrap();
+        snapshot.retrieve(&b).await.unwrap();
+        snapshot.save().unwrap();
+        let lock = Lockfile::load(&path).unwrap();
+        assert_eq!(lock.len(), 2);
+        assert_eq!(Lockfile::parse(&lock.to_string()).unwrap(), lock);
+
+        // a new run refuses content which has changed
+        files.1.borrow_mut().insert("test://a".to_string(), "a2".to_string());
+        let snapshot = Snapshot::with_lockfile(files.clone(), &path).unwrap();
+        let err = snapshot.retrieve(&a).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Integrity);
+        snapshot.retrieve(&b).await.unwrap();
+
+        // until the snapshot is updated
+        let (new, changed) = snapshot.update().await;
+        assert_eq!(changed, HashSet::from([a.clone()]));
+        let diff = snapshot.lockfile().diff(&new.lockfile());
+        assert!(matches!(&diff[..], [LockChange::Changed(u, _, _)] if u == &a));
+        new.save().unwrap();
+        let snapshot = Snapshot::with_lockfile(files.clone(), &path).unwrap();
+        let res = snapshot.retrieve(&a).await.unwrap();
+        assert_eq!(res.reader().unwrap().as_string().unwrap().as_slice().deref(), "a2");
+    });
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn test_fetch_verified() {
+    use super::content::ContentCache;
+    const ABC : &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let dir = std::env::temp_dir().join(format!("atlas-vm-{}-content", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let storage = HeapStorage::new();
+    let files = Rc::new(Files(&storage, RefCell::new(HashMap::from([
+        ("test://a".to_string(), "abc".to_string())
+    ]))));
+    let exec = LocalExecutor::new();
+    let mut machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), files.clone());
+    machine.set_content_cache(Rc::new(ContentCache::with_dir(&storage, &dir)));
+    let x = compile_expr(&storage, &format!("$fetch(\"test://a\", {{\"sha256\": \"{}\"}})", ABC));
+    let res = future::block_on(exec.run(machine.force(&x))).unwrap();
+    assert_eq!(res.reader().unwrap().as_buffer().unwrap().as_slice().deref(), b"abc");
+
+    let bad = compile_expr(&storage, &format!("$fetch(\"test://a\", {{\"sha256\": \"{}\"}})", "00".repeat(32)));
+    let err = future::block_on(exec.run(machine.force(&bad))).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Integrity);
+
+    // options without a digest leave the content unpinned
+    let plain = compile_expr(&storage, "$fetch(\"test://a\", {})");
+    let res = future::block_on(exec.run(machine.force(&plain))).unwrap();
+    assert_eq!(res.reader().unwrap().as_string().unwrap().as_slice(), "abc");
+
+    // served from the cache once the url is gone
+    files.1.borrow_mut().clear();
+    let storage = HeapStorage::new();
+    let mut machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), files.clone());
+    machine.set_content_cache(Rc::new(ContentCache::with_dir(&storage, &dir)));
+    let digest = crate::util::hex::decode(ABC).unwrap();
+    let url = Url::parse("test://moved").unwrap();
+    let res = future::block_on(exec.run(machine.fetch_verified(&url, &digest))).unwrap();
+    assert_eq!(res.reader().unwrap().as_buffer().unwrap().as_slice().deref(), b"abc");
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+// A stand-in HTTP server on localhost, which answers every request with
+// whatever the handler returns for it (given the request line and
+// lowercased headers) and remembers the requests it was sent
+struct TestServer {
+    url: Url,
+    requests: std::sync::Arc<std::sync::Mutex<Vec<(String, HashMap<String, String>)>>>
+}
+
+type Reply = (u32, Vec<(&'static str, String)>, Vec<u8>);
+
+impl TestServer {
+    fn new<F>(handler: F) -> Self
+            where F: Fn(&str, &HashMap<String, String>) -> Reply + Send + 'static {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream { Ok(s) => s, Err(_) => return };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).ok();
+                let line = line.trim().to_string();
+                let mut headers = HashMap::new();
+                loop {
+                    let mut h = String::new();
+                    if reader.read_line(&mut h).unwrap_or(0) == 0 || h.trim().is_empty() { break }
+                    if let Some((k, v)) = h.split_once(':') {
+                        headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_string());
+                    }
+                }
+                let (status, reply_headers, body) = handler(&line, &headers);
+                log.lock().unwrap().push((line, headers));
+                let mut reply = format!("HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n",
+                                        status, body.len());
+                for (k, v) in reply_headers {
+                    reply.push_str(&format!("{}: {}\r\n", k, v));
+                }
+                reply.push_str("\r\n");
+                stream.write_all(reply.as_bytes()).ok();
+                stream.write_all(&body).ok();
+            }
+        });
+        Self { url, requests }
+    }
+
+    fn requests(&self) -> Vec<(String, HashMap<String, String>)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_http_cache() {
+    use super::http::HttpProvider;
+    let dir = std::env::temp_dir().join(format!("atlas-vm-{}-downloads", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let server = TestServer::new(|_, headers| {
+        if headers.get("if-none-match").map(|e| e.as_str()) == Some("\"v1\"") {
+            (304, Vec::new(), Vec::new())
+        } else {
+            (200, vec![("ETag", "\"v1\"".to_string())], b"hello".to_vec())
+        }
+    });
+    let url = server.url.join("hello.txt").unwrap();
+    let storage = HeapStorage::new();
+    future::block_on(async {
+        let http = HttpProvider::with_cache_dir(&storage, &dir);
+        let res = http.retrieve(&url).await.unwrap();
+        assert_eq!(res.reader().unwrap().as_buffer().unwrap().as_slice().deref(), b"hello");
+        // kept in memory
+        http.retrieve(&url).await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+        // and on disk, where the body was streamed to and moved into place
+        let mut files : Vec<_> = std::fs::read_dir(&dir).unwrap()
+            .map(|e| e.unwrap().path().extension().map(|e| e.to_string_lossy().to_string())).collect();
+        files.sort();
+        assert_eq!(files, vec![None, Some("meta".to_string())]);
+
+        // a new provider revalidates what is on disk
+        let http = HttpProvider::with_cache_dir(&storage, &dir);
+        let res = http.retrieve(&url).await.unwrap();
+        assert_eq!(res.reader().unwrap().as_buffer().unwrap().as_slice().deref(), b"hello");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].1.get("if-none-match").map(|e| e.as_str()), Some("\"v1\""));
+
+        // and offline only what is on disk is served
+        let http = HttpProvider::with_cache_dir(&storage, &dir);
+        http.set_offline(true);
+        let res = http.retrieve(&url).await.unwrap();
+        assert_eq!(res.reader().unwrap().as_buffer().unwrap().as_slice().deref(), b"hello");
+        let err = http.retrieve(&server.url.join("other.txt").unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(server.requests().len(), 2);
+    });
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_http_errors() {
+    use super::http::{HttpProvider, HttpConfig};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let flaky = Arc::new(AtomicUsize::new(0));
+    let count = flaky.clone();
+    let server = TestServer::new(move |line, headers| {
+        let path = line.split(' ').nth(1).unwrap_or("").to_string();
+        match path.as_str() {
+            "/missing" => (404, Vec::new(), b"not here".to_vec()),
+            "/moved" => (302, vec![("Location", "/final".to_string())], Vec::new()),
+            "/final" => (200, Vec::new(), b"final".to_vec()),
+            // fails twice, then works
+            "/flaky" if count.fetch_add(1, Ordering::SeqCst) < 2 => (503, Vec::new(), Vec::new()),
+            "/flaky" => (200, Vec::new(), b"flaky".to_vec()),
+            "/private" => match headers.get("authorization") {
+                Some(a) if a == "token sekrit" => (200, Vec::new(), b"private".to_vec()),
+                _ => (401, Vec::new(), Vec::new())
+            },
+            _ => (500, Vec::new(), Vec::new())
+        }
+    });
+    let storage = HeapStorage::new();
+    let body = |h: ItemHandle<'_>| h.reader().unwrap().as_buffer().unwrap().as_slice().deref().to_vec();
+    std::env::set_var("ATLAS_TEST_HTTP_TOKEN", "sekrit");
+    let mut config = HttpConfig::parse(
+        "retries 2\nbackoff 1\ntimeout 10\nheader 127.0.0.1 Authorization: token ${ATLAS_TEST_HTTP_TOKEN}\n").unwrap();
+    let mut http = HttpProvider::new(&storage);
+    http.set_config(config.clone());
+    future::block_on(async {
+        let err = http.retrieve(&server.url.join("missing").unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(err.to_string().contains("404"));
+        // client errors aren't retried
+        assert_eq!(server.requests().len(), 1);
+
+        assert_eq!(body(http.retrieve(&server.url.join("moved").unwrap()).await.unwrap()), b"final");
+        assert_eq!(body(http.retrieve(&server.url.join("flaky").unwrap()).await.unwrap()), b"flaky");
+        assert_eq!(flaky.load(Ordering::SeqCst), 3);
+        assert_eq!(body(http.retrieve(&server.url.join("private").unwrap()).await.unwrap()), b"private");
+
+        // out of retries
+        let err = http.retrieve(&server.url.join("broken").unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("500"));
+        let broken = server.requests().iter().filter(|(l, _)| l.contains("/broken")).count();
+        assert_eq!(broken, 3);
+
+        config.follow_redirects = false;
+        config.headers.clear();
+        let mut http = HttpProvider::new(&storage);
+        http.set_config(config);
+        let err = http.retrieve(&server.url.join("moved").unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("302"));
+        let err = http.retrieve(&server.url.join("private").unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("401"));
+    });
+
+    // a server which never answers
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/slow", listener.local_addr().unwrap())).unwrap();
+    let mut http = HttpProvider::new(&storage);
+    http.set_config(HttpConfig::parse("retries 0\ntimeout 0.2").unwrap());
+    let start = Instant::now();
+    let err = future::block_on(http.retrieve(&url)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::IO);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    drop(listener);
+}
+
+// The headers for a host aren't sent on when it redirects elsewhere
+#[test]
+fn test_http_redirects() {
+    use super::http::{HttpProvider, HttpConfig};
+    let other = TestServer::new(|_, _| (200, Vec::new(), b"landed".to_vec()));
+    let landing = format!("http://localhost:{}/landing", other.url.port().unwrap());
+    let server = TestServer::new(move |line, _| {
+        match line.split(' ').nth(1).unwrap_or("") {
+            "/elsewhere" => (302, vec![("Location", landing.clone())], Vec::new()),
+            "/loop" => (302, vec![("Location", "/loop".to_string())], Vec::new()),
+            "/file" => (302, vec![("Location", "file:///etc/passwd".to_string())], Vec::new()),
+            _ => (404, Vec::new(), Vec::new())
+        }
+    });
+    let storage = HeapStorage::new();
+    let mut http = HttpProvider::new(&storage);
+    http.set_config(HttpConfig::parse(
+        "retries 0\ntimeout 10\nmax-redirects 2\nheader 127.0.0.1 Authorization: token sekrit\nheader localhost X-Other: yes\n").unwrap());
+    future::block_on(async {
+        let res = http.retrieve(&server.url.join("elsewhere").unwrap()).await.unwrap();
+        assert_eq!(res.reader().unwrap().as_buffer().unwrap().as_slice().to_vec(), b"landed");
+        assert_eq!(server.requests()[0].1.get("authorization").map(|a| a.as_str()), Some("token sekrit"));
+        let landed = other.requests();
+        assert_eq!(landed.len(), 1);
+        assert!(!landed[0].1.contains_key("authorization"));
+        assert_eq!(landed[0].1.get("x-other").map(|a| a.as_str()), Some("yes"));
+
+        let err = http.retrieve(&server.url.join("loop").unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("redirects"));
+        let loops = server.requests().iter().filter(|(l, _)| l.contains("/loop")).count();
+        assert_eq!(loops, 3);
+
+        let err = http.retrieve(&server.url.join("file").unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("Bad redirect"));
+    });
+}
+
+#[test]
+fn test_file_dir() {
+    use super::resource::FileProvider;
+    use crate::store::RecordReader;
+    use std::os::unix::fs::PermissionsExt;
+    use std::borrow::Borrow;
+    let dir = std::env::temp_dir().join(format!("atlas-vm-{}-tree", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("a.txt"), "a").unwrap();
+    std::fs::write(dir.join("run.sh"), "#!/bin/sh").unwrap();
+    std::fs::set_permissions(dir.join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(dir.join("sub/b.txt"), "b").unwrap();
+
+    let storage = HeapStorage::new();
+    let mut resources = Resources::new();
+    resources.add_provider(Rc::new(FileProvider::new(&storage)));
+    let machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(resources));
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let root = machine.fetch(&Url::from_file_path(&dir).unwrap()).await.unwrap();
+        let entries = root.reader().unwrap().as_record().unwrap().get("entries").unwrap().borrow().clone();
+        let entries = entries.reader().unwrap().as_record().unwrap();
+        let names : Vec<String> = entries.iter()
+            .map(|(k, _)| k.borrow().reader().unwrap().as_string().unwrap().as_slice().to_string()).collect();
+        assert_eq!(names, vec!["a.txt", "run.sh", "sub"]);
+
+        let run = entries.get("run.sh").unwrap().borrow().clone();
+        let run = run.reader().unwrap().as_record().unwrap();
+        let exe = run.get("executable").unwrap().borrow().clone();
+        assert_eq!(exe.reader().unwrap().as_bool().unwrap(), true);
+        let a = entries.get("a.txt").unwrap().borrow().clone();
+        let a = a.reader().unwrap().as_record().unwrap();
+        assert_eq!(a.get("executable").unwrap().borrow().reader().unwrap().as_bool().unwrap(), false);
+        let content = machine.force(a.get("content").unwrap().borrow()).await.unwrap();
+        assert_eq!(content.reader().unwrap().as_buffer().unwrap().as_slice().deref(), b"a");
+
+        let sub = machine.force(entries.get("sub").unwrap().borrow()).await.unwrap();
+        let sub_entries = sub.reader().unwrap().as_record().unwrap().get("entries").unwrap().borrow().clone();
+        let b = sub_entries.reader().unwrap().as_record().unwrap().get("b.txt").unwrap().borrow().clone();
+        let b = b.reader().unwrap().as_record().unwrap();
+        let content = machine.force(b.get("content").unwrap().borrow()).await.unwrap();
+        assert_eq!(content.reader().unwrap().as_buffer().unwrap().as_slice().deref(), b"b");
+
+        // an unchanged directory is unchanged by an update
+        let mut resources = Resources::new();
+        resources.add_provider(Rc::new(FileProvider::new(&storage)));
+        let snapshot = Snapshot::new(Rc::new(resources));
+        snapshot.retrieve(&Url::from_file_path(&dir).unwrap()).await.unwrap();
+        let (_, changed) = snapshot.update().await;
+        assert!(changed.is_empty());
+    }));
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+// Large files are mapped, and the store shares the mapping rather than
+// copying it. Files which may be modified in place are mapped from a
+// private copy, which nothing done to the file can change
+#[test]
+fn test_mmap_file() {
+    use super::resource::FileProvider;
+    use crate::util::mmap::{read_file, map_file, snapshot_file, MIN_MAP_SIZE};
+    use std::io::{Seek, SeekFrom, Write};
+    let path = std::env::temp_dir().join(format!("atlas-vm-{}-large", std::process::id()));
+    let snapshots = std::env::temp_dir().join(format!("atlas-vm-{}-snapshots", std::process::id()));
+    let data : Vec<u8> = (0..MIN_MAP_SIZE * 2).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, &data).unwrap();
+
+    let mapped = map_file(&path).unwrap();
+    assert_eq!(&mapped[..], &data[..]);
+    let storage = HeapStorage::new();
+    let h = storage.insert_from(&Value::Buffer(mapped.clone())).unwrap();
+    let reader = h.reader().unwrap();
+    let buffer = reader.as_buffer().unwrap();
+    assert_eq!(buffer.as_slice().as_ptr(), mapped.as_ptr());
+    assert_eq!(buffer.slice(1000, 4).deref(), &data[1000..1004]);
+    drop(mapped);
+
+    // neither a copy nor a snapshot is changed by writing to the file
+    let provider = FileProvider::with_snapshot_dir(&storage, &snapshots);
+    let url = Url::from_file_path(&path).unwrap();
+    let retrieved = future::block_on(provider.retrieve(&url)).unwrap();
+    let read = read_file(&path).unwrap();
+    let snapshot = snapshot_file(&path, &snapshots).unwrap();
+    let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(1000)).unwrap();
+    file.write_all(b"edit").unwrap();
+    assert_eq!(&read[..], &data[..]);
+    assert_eq!(&snapshot[..], &data[..]);
+    assert_eq!(retrieved.reader().unwrap().as_buffer().unwrap().slice(1000, 4).to_vec(), &data[1000..1004]);
+    // or truncating it (which would fault a mapping of the file itself)
+    file.set_len(0).unwrap();
+    drop(file);
+    assert_eq!(&snapshot[..], &data[..]);
+    assert_eq!(retrieved.reader().unwrap().as_buffer().unwrap().len(), data.len());
+    // and the copies are unlinked
+    assert_eq!(std::fs::read_dir(&snapshots).unwrap().count(), 0);
+    std::fs::remove_file(&path).ok();
+    std::fs::remove_dir_all(&snapshots).ok();
+}
+
+struct Broken;
+
+#[async_trait(?Send)]
+impl<'s> ResourceProvider<'s, HeapStorage> for Broken {
+    fn schemes(&self) -> Vec<&'static str> {
+        vec!["test"]
+    }
+    async fn retrieve(&self, _: &Url) -> Result<ItemHandle<'s>, crate::Error> {
+        Err(crate::Error::new_const(ErrorKind::IO, "Disk on fire"))
+    }
+}
+
+#[test]
+fn test_resource_errors() {
+    use super::resource::FileProvider;
+    let storage = HeapStorage::new();
+    let mut resources = Resources::new();
+    resources.add_provider(Rc::new(FileProvider::new(&storage)));
+    resources.add_provider(Rc::new(Broken));
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let err = resources.retrieve(&Url::parse("nope://a").unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnsupportedScheme);
+
+        let missing = std::env::temp_dir().join(format!("atlas-vm-{}-missing", std::process::id()));
+        let err = resources.retrieve(&Url::from_file_path(&missing).unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let io = err.chain().into_iter().find_map(|e| e.downcast_ref::<std::io::Error>()).unwrap();
+        assert_eq!(io.kind(), std::io::ErrorKind::NotFound);
+
+        let err = resources.retrieve(&Url::parse("test://a").unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IO);
+        assert!(err.to_string().contains("Disk on fire"));
+    }));
+}
+
+#[test]
+fn test_data_env() {
+    use super::resource::{DataProvider, EnvProvider};
+    let storage = HeapStorage::new();
+    let var = format!("ATLAS_TEST_{}", std::process::id());
+    std::env::set_var(&var, "one");
+    let mut resources = Resources::new();
+    resources.add_provider(Rc::new(DataProvider::new(&storage)));
+    resources.add_provider(Rc::new(EnvProvider::only(&storage, [var.clone()])));
+    let snapshot = Snapshot::new(Rc::new(resources));
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let buffer = |h: ItemHandle<'_>| h.reader().unwrap().as_buffer().unwrap().as_slice().to_vec();
+        let plain = snapshot.retrieve(&Url::parse("data:,hello%20world").unwrap()).await.unwrap();
+        assert_eq!(buffer(plain), b"hello world");
+        let b64 = snapshot.retrieve(&Url::parse("data:text/plain;base64,aGVsbG8=").unwrap()).await.unwrap();
+        assert_eq!(buffer(b64), b"hello");
+        // either alphabet, without padding, split over lines
+        let b64 = snapshot.retrieve(&Url::parse("data:;base64,-_-_%0AaGVsbG8").unwrap()).await.unwrap();
+        assert_eq!(buffer(b64), b"\xfb\xff\xbfhello");
+        let err = snapshot.retrieve(&Url::parse("data:;base64,a").unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BadFormat);
+
+        let url = Url::parse(&format!("env://{}", var)).unwrap();
+        let value = snapshot.retrieve(&url).await.unwrap();
+        assert_eq!(value.reader().unwrap().as_string().unwrap().as_slice().deref(), "one");
+        assert!(snapshot.lockfile().get(&url).is_some());
+        let err = snapshot.retrieve(&Url::parse("env://PATH").unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // the snapshot keeps what was read until it is updated
+        std::env::set_var(&var, "two");
+        let value = snapshot.retrieve(&url).await.unwrap();
+        assert_eq!(value.reader().unwrap().as_string().unwrap().as_slice().deref(), "one");
+        let (_, changed) = snapshot.update().await;
+        assert!(changed.contains(&url));
+    }));
+    std::env::remove_var(&var);
+}
+
+#[test]
+fn test_git() {
+    use super::git::GitProvider;
+    use crate::store::RecordReader;
+    use std::borrow::Borrow;
+    use std::process::Command;
+    let dir = std::env::temp_dir().join(format!("atlas-vm-{}-git", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let git = |args: &[&str]| {
+        let out = Command::new("git").arg("-C").arg(&dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args).output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        String::from_utf8(out.stdout).unwrap().trim().to_string()
+    };
+    git(&["init", "-q", "-b", "main"]);
+    std::fs::write(dir.join("a.txt"), "a").unwrap();
+    std::fs::write(dir.join("run.sh"), "#!/bin/sh").unwrap();
+    std::fs::set_permissions(dir.join("run.sh"), std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+    std::fs::write(dir.join("sub/b.txt"), "b").unwrap();
+    std::os::unix::fs::symlink("sub/b.txt", dir.join("link")).unwrap();
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "first"]);
+    let first = git(&["rev-parse", "HEAD"]);
+
+    let storage = HeapStorage::new();
+    let mut resources = Resources::new();
+    resources.add_provider(Rc::new(GitProvider::new(&storage)));
+    let machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(resources));
+    let url = |rev: &str| Url::parse(&format!("git+{}?rev={}", Url::from_file_path(&dir).unwrap(), rev)).unwrap();
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let root = machine.fetch(&url("main")).await.unwrap();
+        let root = root.reader().unwrap().as_record().unwrap();
+        let commit = root.get("commit").unwrap().borrow().clone();
+        assert_eq!(commit.reader().unwrap().as_string().unwrap().as_slice().to_string(), first);
+        let entries = root.get("entries").unwrap().borrow().clone();
+        let entries = entries.reader().unwrap().as_record().unwrap();
+        let names : Vec<String> = entries.iter()
+            .map(|(k, _)| k.borrow().reader().unwrap().as_string().unwrap().as_slice().to_string()).collect();
+        assert_eq!(names, vec!["a.txt", "link", "run.sh", "sub"]);
+
+        let run = entries.get("run.sh").unwrap().borrow().clone();
+        let exe = run.reader().unwrap().as_record().unwrap().get("executable").unwrap().borrow().clone();
+        assert_eq!(exe.reader().unwrap().as_bool().unwrap(), true);
+        let link = entries.get("link").unwrap().borrow().clone();
+        let target = link.reader().unwrap().as_record().unwrap().get("symlink").unwrap().borrow().clone();
+        assert_eq!(target.reader().unwrap().as_string().unwrap().as_slice().to_string(), "sub/b.txt");
+
+        // entries stay at the commit after the branch moves on
+        std::fs::write(dir.join("sub/b.txt"), "b2").unwrap();
+        git(&["commit", "-q", "-am", "second"]);
+        let sub = machine.force(entries.get("sub").unwrap().borrow()).await.unwrap();
+        let sub_entries = sub.reader().unwrap().as_record().unwrap().get("entries").unwrap().borrow().clone();
+        let b = sub_entries.reader().unwrap().as_record().unwrap().get("b.txt").unwrap().borrow().clone();
+        let content = b.reader().unwrap().as_record().unwrap().get("content").unwrap().borrow().clone();
+        let content = machine.force(&content).await.unwrap();
+        assert_eq!(content.reader().unwrap().as_buffer().unwrap().as_slice().deref(), b"b");
+
+        let err = machine.fetch(&url("nope")).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        // which git would otherwise take as an option
+        let err = machine.fetch(&url("--output=x")).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BadFormat);
+    }));
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+// Forces the lambda applied to the argument
+async fn apply<'s>(machine: &Machine<'s, HeapStorage>, storage: &'s HeapStorage, code: &str, arg: ItemHandle<'s>)
+        -> Result<ItemHandle<'s>, crate::Error> {
+    let lam = machine.force(&compile_expr(storage, code)).await?;
+    let partial = storage.insert_from(&Value::Partial(lam, vec![arg]))?;
+    machine.force(&storage.insert_from(&Value::Thunk(partial))?).await
+}
+
+#[test]
+fn test_archives() {
+    use crate::store::serialize::export;
+    use std::process::Command;
+    use std::os::unix::fs::PermissionsExt;
+    let dir = std::env::temp_dir().join(format!("atlas-vm-{}-archive", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let src = dir.join("src");
+    let long = "l".repeat(120);
+    std::fs::create_dir_all(src.join("sub").join(&long)).unwrap();
+    std::fs::write(src.join("a.txt"), "a").unwrap();
+    std::fs::write(src.join("run.sh"), "#!/bin/sh").unwrap();
+    std::fs::set_permissions(src.join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(src.join("sub").join(&long).join("b.txt"), "b").unwrap();
+    std::os::unix::fs::symlink("sub", src.join("link")).unwrap();
+    // which resolves through link, but stays inside
+    std::os::unix::fs::symlink("../link/..", src.join("sub").join("top")).unwrap();
+    let run = |cmd: &mut Command| assert!(cmd.status().unwrap().success());
+    run(Command::new("tar").arg("-C").arg(&src).arg("-czf").arg(dir.join("src.tar.gz")).arg("."));
+    run(Command::new("zip").current_dir(&src).arg("-qry").arg(dir.join("src.zip")).arg("."));
+    // symlinks which lead out of the archive
+    let (up, abs) = (dir.join("up"), dir.join("abs"));
+    std::fs::create_dir_all(up.join("sub")).unwrap();
+    std::os::unix::fs::symlink("../../x", up.join("sub").join("link")).unwrap();
+    std::fs::create_dir_all(&abs).unwrap();
+    std::os::unix::fs::symlink("/etc/passwd", abs.join("link")).unwrap();
+    // each of which stays inside on its own, but not together
+    let chain = dir.join("chain");
+    std::fs::create_dir_all(chain.join("d")).unwrap();
+    std::os::unix::fs::symlink("..", chain.join("d").join("l")).unwrap();
+    std::os::unix::fs::symlink("d/l/..", chain.join("x")).unwrap();
+    run(Command::new("tar").arg("-C").arg(&up).arg("-cf").arg(dir.join("up.tar")).arg("."));
+    run(Command::new("zip").current_dir(&up).arg("-qry").arg(dir.join("up.zip")).arg("."));
+    run(Command::new("tar").arg("-C").arg(&abs).arg("-cf").arg(dir.join("abs.tar")).arg("."));
+    run(Command::new("tar").arg("-C").arg(&chain).arg("-cf").arg(dir.join("chain.tar")).arg("."));
+    run(Command::new("zip").current_dir(&chain).arg("-qry").arg(dir.join("chain.zip")).arg("."));
+
+    let storage = HeapStorage::new();
+    let machine = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+    let exec = LocalExecutor::new();
+    future::block_on(exec.run(async {
+        let apply = |code, arg| apply(&machine, &storage, code, arg);
+        let buffer = |path: &std::path::Path| storage.insert_from(&Value::Buffer(std::fs::read(path).unwrap().into())).unwrap();
+
+        let from_tar = apply("|x| $untar($force(x))", buffer(&dir.join("src.tar.gz"))).await.unwrap();
+        let from_zip = apply("|x| $unzip($force(x))", buffer(&dir.join("src.zip"))).await.unwrap();
+        assert_eq!(export(&from_tar).unwrap(), export(&from_zip).unwrap());
+
+        // packing and unpacking again gives the same tree
+        let packed = apply("|x| $tar(x)", from_tar.clone()).await.unwrap();
+        let unpacked = apply("|x| $untar($force(x))", packed.clone()).await.unwrap();
+        assert_eq!(export(&from_tar).unwrap(), export(&unpacked).unwrap());
+
+        // which tar itself can read
+        let out = dir.join("out");
+        std::fs::create_dir_all(&out).unwrap();
+        std::fs::write(dir.join("packed.tar"), packed.reader().unwrap().as_buffer().unwrap().as_slice().deref()).unwrap();
+        run(Command::new("tar").arg("-C").arg(&out).arg("-xf").arg(dir.join("packed.tar")));
+        assert_eq!(std::fs::read_to_string(out.join("sub").join(&long).join("b.txt")).unwrap(), "b");
+        assert_eq!(std::fs::metadata(out.join("run.sh")).unwrap().permissions().mode() & 0o111, 0o111);
+        assert_eq!(std::fs::read_link(out.join("link")).unwrap(), std::path::PathBuf::from("sub"));
+
+        let (untar, unzip) = ("|x| $untar($force(x))", "|x| $unzip($force(x))");
+        for (unpack, archive) in [(untar, "up.tar"), (unzip, "up.zip"), (untar, "abs.tar"),
+                                 (untar, "chain.tar"), (unzip, "chain.zip")] {
+            let err = apply(unpack, buffer(&dir.join(archive))).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BadFormat);
+            assert!(err.to_string().contains("outside of the archive"));
+        }
+
+        let err = apply("|x| $untar($force(x))", storage.insert_from(&Value::Buffer(vec![1; 512].into())).unwrap()).await;
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::BadFormat);
+        // too short to hold the end of central directory it starts with
+        let err = apply("|x| $unzip($force(x))", storage.insert_from(&Value::Buffer(b"PK\x05\x06".to_vec().into())).unwrap()).await;
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::BadFormat);
+
+        // an entry whose (base-256) size is close to u64::MAX
+        let mut header = [0u8; 512];
+        header[0] = b'a';
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124] = 0x80;
+        header[125..136].fill(0xff);
+        header[156] = b'0';
+        header[148..156].fill(b' ');
+        let sum : u32 = header.iter().map(|b| *b as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        let mut huge = header.to_vec();
+        huge.resize(2048, 0);
+        let err = apply("|x| $untar($force(x))", storage.insert_from(&Value::Buffer(huge.into())).unwrap()).await;
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::BadFormat);
+    }));
+
+    // unpacking counts against the budget
+    std::fs::write(dir.join("zeros"), vec![0u8; 1 << 20]).unwrap();
+    run(Command::new("gzip").arg("-k").arg(dir.join("zeros")));
+    let bomb = storage.insert_from(&Value::Buffer(std::fs::read(dir.join("zeros.gz")).unwrap().into())).unwrap();
+    let tar = storage.insert_from(&Value::Buffer(std::fs::read(dir.join("src.tar.gz")).unwrap().into())).unwrap();
+    let limited = |budget: Budget| {
+        let mut m = Machine::new(&storage, Rc::new(storage.create_thunk_map()), Rc::new(Resources::new()));
+        m.set_budget(budget);
+        m
+    };
+    future::block_on(exec.run(async {
+        let m = limited(Budget { bytes: Some(1 << 16), ..Budget::default() });
+        let err = apply(&m, &storage, "|x| $gunzip($force(x))", bomb.clone()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BudgetExceeded);
+        let m = limited(Budget { bytes: Some(2 << 20), ..Budget::default() });
+        apply(&m, &storage, "|x| $gunzip($force(x))", bomb).await.unwrap();
+        let m = limited(Budget { allocations: Some(10), ..Budget::default() });
+        let err = apply(&m, &storage, "|x| $untar($force(x))", tar).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BudgetExceeded);
+    }));
+    std::fs::remove_dir_all(&dir).ok();
+}
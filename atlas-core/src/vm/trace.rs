@@ -1,158 +1,353 @@
-// use futures_lite::future::BoxedLocal;
-// use crate::store::Storage;
-// use std::collections::HashMap;
-// use super::resource::ResourceID;
-// use std::rc::{Rc, Weak};
-
-// use slab::Slab;
-// use std::cell::RefCell;
-
-// pub type LambdaHash = u64;
-// pub type ShallowHash = u64;
-// pub type ContextID = u64;
-
-// pub type ObjectID = usize;
-// pub type EventID = usize;
-
-// pub enum Action<'s, S: Storage + 's> {
-//     // Map a particular objectID to an input of the function
-//     SetInput(ObjectID, usize),
-//     Query(ObjectID),
-//     Result(ObjectID, ShallowHash),
-//     UseResource(ResourceID),
-//     Print(String),
-//     Ret(S::Handle<'s>)
-// }
-
-// pub struct TraceContext<'t, 's, S: Storage> {
-//     // The parent tracing context, for use
-//     // when one tracing function calls another
-//     // tracing function internally
-//     parent: Option<&'t mut TraceContext<'t, 's, S>>,
-//     // Trace object to output to
-//     target: Option<&'t mut Trace<'s, S>>,
-//     // Current trace set which we are in
-//     trace_set: Option<&'t TraceSet<'s, S>>,
-//     object_map: HashMap<ObjectID, S::Handle<'s>>
-// }
-
-// pub struct TraceEvent<'s, S: Storage + 's> {
-//     action: Action<'s, S>,
-//     deps: Vec<EventID>,
-//     children: Vec<EventID>
-// }
-
-// pub struct Trace<'s, S: Storage + 's> {
-//     events: Slab<TraceEvent<'s, S>>,
-// }
-
-// pub struct TraceSet<'s, S: Storage + 's> {
-//     root_trace: Trace<'s, S>,
-//     // sub-traces for any thunks returned by the root trace
-// }
-
-// pub struct CacheEntry<'s, S: Storage + 's> {
-//     root_traces: TraceSet<'s, S>,
-//     thunk_traces: HashMap<S::Handle<'s>, TraceSet<'s, S>>
-// }
-
-// pub struct Cache<'s, S: Storage + 's> {
-//     traces: HashMap<LambdaHash, TraceSet<'s, S>>,
-//     contexts: HashMap<ContextID, Context<'s, S>>
-// }
-
-// use crate::store::Storage;
-// use super::machine::Machine;
-// use crate::Error;
-// use std::marker::PhantomData;
-// use std::collections::HashMap;
-// use std::cell::RefCell;
-
-// pub trait TraceBuilder<'s, 'c, S: Storage> {
-//     // will consume the builder and finish the trace
-//     fn returned(self, value: S::Handle<'s>);
-// }
-
-// pub enum Lookup<'s, 'c, S, T>
-//         where
-//             S : Storage + 's,
-//             T : TraceBuilder<'s, 'c, S> {
-//     Hit(S::Handle<'s>), // yay we found a result in the cache
-//     // A miss. Atomically registers and returns a new trace
-//     // for the thunk that was requested
-//     Miss(T, PhantomData<&'c T>)
-// }
-
-// type CacheFuture<'a, T> = std::pin::Pin<Box<dyn futures_lite::Future<Output=T> + 'a>>;
-
-// pub trait Cache<'s, S : Storage + 's> : Sized {
-//     type TraceBuilder<'c> : TraceBuilder<'s, 'c, S> where Self : 'c, 's : 'c, S: 's, S: 'c;
-
-//     fn query<'c>(&'c self, mach: &'c Machine<'s, Self, S>, 
-//                     thunk_ref: &'c S::Handle<'s>)
-//             -> CacheFuture<'c, Result<Lookup<'s, 'c, S, Self::TraceBuilder<'c>>, Error>>;
-// }
-
-
-// enum ThunkStatus<'s, S: Storage + 's> {
-//     InProgres(async_broadcast::Receiver<S::Handle<'s>>),
-//     Finished(S::Handle<'s>)
-// }
-
-// // An execution cache which just keeps track of
-// // if a particular thunk is being forced
-// pub struct ThunkCache<'s, S: Storage + 's> {
-//     map: RefCell<HashMap<S::Handle<'s>, ThunkStatus<'s, S>>>
-// }
-
-// impl<'s, S: Storage> ThunkCache<'s, S> {
-//     pub fn new() -> Self {
-//         Self { map : RefCell::new(HashMap::new()) }
-//     }
-// }
-
-// pub struct DirectForceBuilder<'s, 'c, S: Storage> {
-//     ptr: S::Handle<'s>,
-//     cache: &'c ThunkCache<'s, S>,
-//     sender: async_broadcast::Sender<S::Handle<'s>>
-// }
-
-// impl<'s, 'c, S: Storage> TraceBuilder<'s, 'c, S> for DirectForceBuilder<'s, 'c, S> {
-//     fn returned(self, value: S::Handle<'s>) {
-//         let mut map = self.cache.map.borrow_mut();
-//         let old = map.insert(self.ptr, ThunkStatus::Finished(value.clone()));
-//         self.sender.try_broadcast(value).unwrap();
-//         std::mem::drop(old); // keep the old receiver around until after we have broadcasted to prevent closing the channel
-//     }
-// }
-
-// impl<'s, S: Storage> Cache<'s, S> for ThunkCache<'s, S> {
-//     type TraceBuilder<'c> = DirectForceBuilder<'s, 'c, S> where Self : 'c;
-
-//     fn query<'c>(&'c self, _mach: &'c Machine<'s, Self, S>, thunk_ref: &'c S::Handle<'s>)
-//             -> CacheFuture<'c, Result<Lookup<'s, 'c, S, Self::TraceBuilder<'c>>, Error>> {
-//         Box::pin(async move {
-//             let mut map = self.map.borrow_mut();
-//             let s = map.get(&thunk_ref);
-//             match s {
-//                 None => {
-//                     // insert an in-progress status
-//                     let (s, r) = async_broadcast::broadcast(1);
-//                     map.insert(thunk_ref.clone(), ThunkStatus::InProgres(r));
-//                     Ok(Lookup::Miss(DirectForceBuilder { ptr: thunk_ref.clone(), cache: self, sender: s }, PhantomData))
-//                 }
-//                 Some(status) => {
-//                     match status {
-//                         ThunkStatus::Finished(v) => Ok(Lookup::Hit(v.clone())),
-//                         ThunkStatus::InProgres(r) => {
-//                             let mut r = r.clone();
-//                             std::mem::drop(map); // we don't want to hold on the map refmut over the await
-//                             let v = r.recv().await.unwrap();
-//                             Ok(Lookup::Hit(v))
-//                         }
-//                     }
-//                 }
-//             }
-//         })
-//     }
-// }
\ No newline at end of file
+use crate::store::{Storage, Handle, WeakHandle, ThunkMap, ObjectReader, ReaderWhich, StringReader, BufferReader};
+use crate::Error;
+
+use std::collections::{HashMap, HashSet};
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::fmt;
+use url::Url;
+
+pub type ShallowHash = u64;
+
+type WeakOf<'s, S> = <<S as Storage>::Handle<'s> as Handle<'s>>::Weak;
+
+// Objects are numbered in the order a trace first sees them
+pub type ObjectID = usize;
+pub type EventID = usize;
+
+pub enum Action<'s, S: Storage + 's> {
+    // Map a particular objectID to an input of the function
+    SetInput(ObjectID, usize),
+    // The thunk forced an object
+    Query(ObjectID),
+    // The WHNF of a query, and the shallow hash of that WHNF
+    Result(ObjectID, ShallowHash),
+    UseResource(Url),
+    Print(String),
+    Ret(S::Handle<'s>)
+}
+
+pub struct TraceEvent<'s, S: Storage + 's> {
+    pub action: Action<'s, S>,
+    // The events which introduced the objects this event uses
+    pub deps: Vec<EventID>
+}
+
+// The record of evaluating a single thunk: which inputs
+// it queried, which resources it fetched and what it returned
+pub struct Trace<'s, S: Storage + 's> {
+    // Weak, so a cached trace does not keep its thunk alive
+    pub thunk: WeakOf<'s, S>,
+    // ObjectID --> the object
+    pub objects: Vec<S::Handle<'s>>,
+    pub events: Vec<TraceEvent<'s, S>>
+}
+
+impl<'s, S: Storage + 's> Trace<'s, S> {
+    pub fn resources(&self) -> Vec<&Url> {
+        self.events.iter().filter_map(|e| match &e.action {
+            Action::UseResource(u) => Some(u),
+            _ => None
+        }).collect()
+    }
+
+    // The objects this thunk forced
+    pub fn queries(&self) -> Vec<&S::Handle<'s>> {
+        self.events.iter().filter_map(|e| match &e.action {
+            Action::Query(o) => Some(&self.objects[*o]),
+            _ => None
+        }).collect()
+    }
+
+    pub fn ret(&self) -> Option<&S::Handle<'s>> {
+        match self.events.last().map(|e| &e.action) {
+            Some(Action::Ret(h)) => Some(h),
+            _ => None
+        }
+    }
+}
+
+// Builds the trace for a thunk while it is under evaluation
+pub struct TraceContext<'s, S: Storage + 's> {
+    trace: Trace<'s, S>,
+    objects: HashMap<S::Handle<'s>, (ObjectID, EventID)>,
+    // register --> the query event waiting on the register
+    pending: HashMap<crate::store::op::RegID, EventID>
+}
+
+impl<'s, S: Storage + 's> TraceContext<'s, S> {
+    pub fn new(thunk: S::Handle<'s>) -> Self {
+        Self {
+            trace: Trace { thunk: thunk.downgrade(), objects: Vec::new(), events: Vec::new() },
+            objects: HashMap::new(),
+            pending: HashMap::new()
+        }
+    }
+
+    fn push(&mut self, action: Action<'s, S>, deps: Vec<EventID>) -> EventID {
+        let id = self.trace.events.len();
+        self.trace.events.push(TraceEvent { action, deps });
+        id
+    }
+
+    // Registers an object introduced by the given event
+    fn object(&mut self, h: &S::Handle<'s>, event: EventID) -> ObjectID {
+        if let Some((id, _)) = self.objects.get(h) {
+            return *id
+        }
+        let id = self.trace.objects.len();
+        self.trace.objects.push(h.clone());
+        self.objects.insert(h.clone(), (id, event));
+        id
+    }
+
+    pub fn input(&mut self, h: &S::Handle<'s>, i: usize) {
+        let event = self.trace.events.len();
+        let obj = self.object(h, event);
+        self.push(Action::SetInput(obj, i), Vec::new());
+    }
+
+    // A force of h, whose WHNF will be delivered to the given register
+    pub fn query(&mut self, h: &S::Handle<'s>, dest: crate::store::op::RegID) {
+        let q = self.force(h);
+        self.pending.insert(dest, q);
+    }
+
+    // A force of h made by a builtin acting for the thunk,
+    // whose WHNF is passed to result() once it arrives
+    pub fn force(&mut self, h: &S::Handle<'s>) -> EventID {
+        let event = self.trace.events.len();
+        let deps = self.objects.get(h).map(|(_, e)| vec![*e]).unwrap_or_default();
+        let obj = self.object(h, event);
+        self.push(Action::Query(obj), deps)
+    }
+
+    pub fn result(&mut self, q: EventID, h: &S::Handle<'s>) {
+        let event = self.trace.events.len();
+        let obj = self.object(h, event);
+        self.push(Action::Result(obj, shallow_hash(h)), vec![q]);
+    }
+
+    // Called whenever a register is set, recording the result
+    // if the register was the destination of a query
+    pub fn complete(&mut self, reg: crate::store::op::RegID, h: &S::Handle<'s>) {
+        if let Some(q) = self.pending.remove(&reg) {
+            self.result(q, h)
+        }
+    }
+
+    pub fn use_resource(&mut self, url: &Url) {
+        self.push(Action::UseResource(url.clone()), Vec::new());
+    }
+
+    pub fn print<M: Into<String>>(&mut self, msg: M) {
+        self.push(Action::Print(msg.into()), Vec::new());
+    }
+
+    pub fn ret(mut self, h: &S::Handle<'s>) -> Trace<'s, S> {
+        self.push(Action::Ret(h.clone()), Vec::new());
+        self.trace
+    }
+
+    pub fn error(mut self, e: &Error) -> Trace<'s, S> {
+        self.push(Action::Print(format!("error: {}", e)), Vec::new());
+        self.trace
+    }
+}
+
+fn follow_indirects<'s, H: Handle<'s>>(h: &H) -> H {
+    let mut h = h.clone();
+    loop {
+        let next = match h.reader() {
+            Ok(r) => match r.which() {
+                ReaderWhich::Indirect(n) => Some(n.borrow().clone()),
+                _ => None
+            },
+            Err(_) => None
+        };
+        match next {
+            Some(n) => h = n,
+            None => return h
+        }
+    }
+}
+
+// Hashes the contents of primitive objects
+// and the handle of everything else
+pub fn shallow_hash<'s, H: Handle<'s>>(h: &H) -> ShallowHash {
+    let mut hasher = DefaultHasher::new();
+    let reader = match h.reader() {
+        Ok(r) => r,
+        Err(_) => { h.hash(&mut hasher); return hasher.finish() }
+    };
+    use ReaderWhich::*;
+    match reader.which() {
+        Unit => 0u8.hash(&mut hasher),
+        Int(i) => (1u8, i).hash(&mut hasher),
+        Float(f) => (2u8, f.to_bits()).hash(&mut hasher),
+        Bool(b) => (3u8, b).hash(&mut hasher),
+        Char(c) => (4u8, c).hash(&mut hasher),
+        String(s) => (5u8, s.as_slice().deref()).hash(&mut hasher),
+        Buffer(b) => (6u8, b.as_slice().deref()).hash(&mut hasher),
+        Nil => 7u8.hash(&mut hasher),
+        _ => (8u8, h).hash(&mut hasher)
+    }
+    hasher.finish()
+}
+
+// The traces recorded by all of the machines sharing this cache,
+// by thunk (in the order they were recorded). A trace is kept
+// while its thunk is, and keeps alive the objects it refers to
+pub struct Cache<'s, S: Storage + 's> {
+    traces: RefCell<Vec<Rc<Trace<'s, S>>>>,
+    index: RefCell<HashMap<WeakOf<'s, S>, usize>>
+}
+
+impl<'s, S: Storage + 's> Default for Cache<'s, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'s, S: Storage + 's> Cache<'s, S> {
+    pub fn new() -> Self {
+        Self { traces: RefCell::new(Vec::new()), index: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn get(&self, thunk: &S::Handle<'s>) -> Option<Rc<Trace<'s, S>>> {
+        let i = *self.index.borrow().get(&thunk.downgrade())?;
+        let trace = self.traces.borrow()[i].clone();
+        // not the trace of an earlier object which has been freed
+        trace.thunk.upgrade().map(|_| trace)
+    }
+
+    pub fn insert(&self, trace: Trace<'s, S>) {
+        let mut traces = self.traces.borrow_mut();
+        let mut index = self.index.borrow_mut();
+        match index.get(&trace.thunk) {
+            Some(i) => traces[*i] = Rc::new(trace),
+            None => {
+                index.insert(trace.thunk.clone(), traces.len());
+                traces.push(Rc::new(trace));
+            }
+        }
+    }
+
+    // Invalidates every thunk which (transitively) used one of the
+    // changed resources, removing it from the thunk map and the cache
+    // so that it is recomputed the next time it is forced.
+    // Returns the number of thunks invalidated
+    pub fn invalidate<M>(&self, changed: &HashSet<Url>, thunk_map: &M) -> usize
+            where M: ThunkMap<'s, Handle=S::Handle<'s>> {
+        let traces = self.traces.borrow().clone();
+        // trace --> the traces which depend on it
+        let mut dependents : Vec<Vec<usize>> = vec![Vec::new(); traces.len()];
+        let mut invalid = Vec::new();
+        {
+            let index = self.index.borrow();
+            for (i, t) in traces.iter().enumerate() {
+                // a thunk depends on the thunks it forced and the thunk it returned
+                for h in t.queries().into_iter().chain(t.ret()) {
+                    if let Some(d) = index.get(&follow_indirects(h).downgrade()) {
+                        dependents[*d].push(i)
+                    }
+                }
+                if t.resources().iter().any(|u| changed.contains(u)) {
+                    invalid.push(i)
+                }
+            }
+        }
+        let mut seen = HashSet::new();
+        while let Some(i) = invalid.pop() {
+            if seen.insert(i) {
+                invalid.extend(dependents[i].iter().cloned())
+            }
+        }
+        for i in seen.iter() {
+            if let Some(thunk) = traces[*i].thunk.upgrade() {
+                thunk_map.remove(&thunk)
+            }
+        }
+        self.retain(|i, _| !seen.contains(&i));
+        seen.len()
+    }
+
+    // Drops the traces of thunks which have been freed,
+    // returning the number dropped
+    pub fn evict(&self) -> usize {
+        let before = self.len();
+        self.retain(|_, t| t.thunk.upgrade().is_some());
+        before - self.len()
+    }
+
+    // Collects the storage. Dropping the traces of the thunks
+    // it freed can free more objects, so this repeats until
+    // no more traces are dropped. Returns the number of objects freed
+    pub fn gc(&self, storage: &'s S) -> usize {
+        let mut freed = storage.gc();
+        while self.evict() > 0 {
+            freed += storage.gc()
+        }
+        freed
+    }
+
+    fn retain<F: Fn(usize, &Trace<'s, S>) -> bool>(&self, keep: F) {
+        let kept : Vec<_> = self.traces.borrow().iter().enumerate()
+            .filter(|(i, t)| keep(*i, t)).map(|(_, t)| t.clone()).collect();
+        *self.index.borrow_mut() = kept.iter().enumerate()
+            .map(|(i, t)| (t.thunk.clone(), i)).collect();
+        *self.traces.borrow_mut() = kept;
+    }
+
+    pub fn traces(&self) -> Vec<Rc<Trace<'s, S>>> {
+        self.traces.borrow().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.traces.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dump<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        for t in self.traces.borrow().iter() {
+            write!(w, "{}", t)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'s, S: Storage + 's> fmt::Display for Action<'s, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Action::*;
+        match self {
+            SetInput(o, i) => write!(f, "@{} <- input {}", o, i),
+            Query(o) => write!(f, "query @{}", o),
+            Result(o, h) => write!(f, "@{} <- result {:016x}", o, h),
+            UseResource(u) => write!(f, "use {}", u),
+            Print(s) => write!(f, "print {}", s),
+            Ret(h) => write!(f, "ret {}", h)
+        }
+    }
+}
+
+impl<'s, S: Storage + 's> fmt::Display for Trace<'s, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "trace for {}:", self.thunk)?;
+        for (i, e) in self.events.iter().enumerate() {
+            write!(f, "  #{}: {}", i, e.action)?;
+            if !e.deps.is_empty() {
+                let deps : Vec<_> = e.deps.iter().map(|d| format!("#{}", d)).collect();
+                write!(f, " (after {})", deps.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@ use atlas_core::store::{Storage, Handle, ObjectReader, RecordReader, StringReade
 use atlas_core::store::value::Value;
 use atlas_core::vm::machine::Machine;
 use atlas_core::{Error, Result};
+use atlas_core::util::mmap::map_file;
 
 use futures_lite::future;
 
@@ -19,7 +20,6 @@ use nix::fcntl::OFlag;
 use nix::mount::MsFlags;
 
 use std::ffi::CStr;
-use bytes::Bytes;
 use std::borrow::Borrow;
 
 use std::os::unix::ffi::OsStrExt;
@@ -265,9 +265,11 @@ async fn merge_overlay_dirs<'s, S: Storage + 's>(old_dir: Option<S::Handle<'s>>,
                 let sub_merged = merge_overlay_dirs(old_dir, &sub_path, mach).await?;
                 entries.insert(name, sub_merged);
             } else if ft.is_file() {
-                // Read in the file
-                let data = std::fs::read(&sub_path).map_err(|_| Error::new("Unable to read overlay file"))?;
-                let val = Value::Buffer(Bytes::from(data));
+                // Map in the file, which nothing modifies now that the
+                // commands run in the sandbox have exited (and which the
+                // sandbox only unlinks when it is done)
+                let data = map_file(&sub_path).map_err(|_| Error::new("Unable to read overlay file"))?;
+                let val = Value::Buffer(data);
                 let val = store.insert_from(&val)?;
                 let content_str = store.insert_from(&Value::String(String::from("content")))?;
                 let entry = store.insert_from(&Value::Record(vec![(content_str, val)]))?;